@@ -0,0 +1,296 @@
+//! Keep every chat message the bridge sees in the database, so it can be
+//! searched later.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use azalea::{
+    ecs::{
+        app::{App, Plugin},
+        event::EventReader,
+        system::Query,
+    },
+    entity::Local,
+    GameProfileComponent,
+};
+use bevy_ecs::{
+    entity::Entity,
+    query::With,
+    system::{Res, ResMut, Resource},
+};
+use log::{error, info};
+use rusqlite::params;
+
+use crate::{
+    azalea_bridge::{is_whisper, BridgedToMinecraftEvent},
+    bevy_storage::Storage,
+};
+
+/// Archives chat from Minecraft and the messages every bridge sends to it. This
+/// needs [`StoragePlugin`](crate::bevy_storage::StoragePlugin).
+pub struct ArchivePlugin {
+    /// The address of the server, since every server shares the same archive.
+    pub server: String,
+    /// Messages older than this are deleted. None keeps them forever.
+    pub retention: Option<Duration>,
+}
+
+impl Plugin for ArchivePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ChatArchive {
+            server: self.server.clone(),
+            retention: self.retention,
+            last_pruned: None,
+        })
+        .add_system(archive_from_minecraft)
+        .add_system(archive_to_minecraft)
+        .add_system(prune_archive);
+    }
+}
+
+/// How often old messages are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Resource, Clone)]
+pub struct ChatArchive {
+    pub server: String,
+    pub retention: Option<Duration>,
+    last_pruned: Option<Instant>,
+}
+
+pub struct ArchivedMessage {
+    /// Unix time in milliseconds.
+    pub timestamp: u64,
+    /// Where the message came from, like `minecraft` or `discord`.
+    pub platform: String,
+    pub sender: Option<String>,
+    pub content: String,
+}
+
+impl ArchivedMessage {
+    /// The message as one line of text, without the time.
+    pub fn to_line(&self) -> String {
+        match (&self.sender, self.platform.as_str()) {
+            // minecraft messages already say who sent them
+            (_, "minecraft") | (None, _) => self.content.clone(),
+            (Some(sender), platform) => format!("({platform}) <{sender}> {}", self.content),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SearchQuery {
+    /// Only messages sent by this player or user.
+    pub player: Option<String>,
+    /// Only messages that contain this, ignoring case.
+    pub text: Option<String>,
+    /// Unix time in milliseconds.
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    /// At most this many of the newest messages are returned.
+    pub limit: usize,
+}
+
+impl ChatArchive {
+    /// Find archived messages from this server, oldest first. Whispers are
+    /// never returned.
+    pub fn search(
+        &self,
+        storage: &Storage,
+        query: &SearchQuery,
+    ) -> rusqlite::Result<Vec<ArchivedMessage>> {
+        // % and _ are wildcards in LIKE
+        let pattern = query.text.as_ref().map(|text| {
+            let escaped = text
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("%{escaped}%")
+        });
+        let mut messages = storage.with(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT timestamp, platform, sender, content FROM chat_archive
+                WHERE server = ?1 AND private = 0
+                    AND (?2 IS NULL OR sender = ?2 COLLATE NOCASE)
+                    AND (?3 IS NULL OR content LIKE ?3 ESCAPE '\\')
+                    AND timestamp >= ?4 AND timestamp <= ?5
+                ORDER BY timestamp DESC
+                LIMIT ?6",
+            )?;
+            let rows = statement.query_map(
+                params![
+                    self.server,
+                    query.player,
+                    pattern,
+                    query.since.unwrap_or(0),
+                    query.until.unwrap_or(i64::MAX as u64),
+                    query.limit,
+                ],
+                |row| {
+                    Ok(ArchivedMessage {
+                        timestamp: row.get(0)?,
+                        platform: row.get(1)?,
+                        sender: row.get(2)?,
+                        content: row.get(3)?,
+                    })
+                },
+            )?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+        })?;
+        messages.reverse();
+        Ok(messages)
+    }
+}
+
+/// The current unix time in milliseconds.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Format unix time in milliseconds like `2023-02-01 13:45:00`, in UTC.
+pub fn format_utc(millis: u64) -> String {
+    let secs = millis / 1000;
+    let (hours, minutes, seconds) = (secs / 3600 % 24, secs / 60 % 60, secs % 60);
+
+    // from http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let leap_days = day_of_era / 1460 - day_of_era / 36524 + day_of_era / 146096;
+    let year_of_era = (day_of_era - leap_days) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02} {hours:02}:{minutes:02}:{seconds:02}")
+}
+
+/// Parse a duration like `30m`, `2h` or `7d`.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let unit_start = text.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = text.split_at(unit_start);
+    let amount: u64 = amount.parse().ok()?;
+    let unit_secs = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(amount.checked_mul(unit_secs)?))
+}
+
+fn archive_from_minecraft(
+    archive: Res<ChatArchive>,
+    storage: Res<Storage>,
+    mut events: EventReader<azalea::chat::ChatReceivedEvent>,
+    query: Query<(Entity, &GameProfileComponent), With<Local>>,
+) {
+    // every bot sees the same chat, so only one of them is archived
+    let archiver = query.iter().min_by(|(_, a), (_, b)| a.name.cmp(&b.name));
+    let events = events
+        .iter()
+        .filter(|event| Some(event.entity) == archiver.map(|(entity, _)| entity))
+        .collect::<Vec<_>>();
+    let Some((_, game_profile)) = archiver.filter(|_| !events.is_empty()) else {
+        return;
+    };
+
+    let timestamp = now_millis();
+    let result = storage.transaction(|transaction| {
+        for event in events {
+            let message = event.packet.message();
+            transaction.execute(
+                "INSERT INTO chat_archive
+                    (timestamp, server, account, platform, sender, content, component, private)
+                VALUES (?1, ?2, ?3, 'minecraft', ?4, ?5, ?6, ?7)",
+                params![
+                    timestamp,
+                    archive.server,
+                    game_profile.name,
+                    event.packet.username(),
+                    message.to_string(),
+                    serde_json::to_string(&message).ok(),
+                    is_whisper(&event.packet),
+                ],
+            )?;
+        }
+        Ok(())
+    });
+    if let Err(err) = result {
+        error!("couldn't archive chat: {err}");
+    }
+}
+
+fn archive_to_minecraft(
+    archive: Res<ChatArchive>,
+    storage: Res<Storage>,
+    mut events: EventReader<BridgedToMinecraftEvent>,
+    query: Query<&GameProfileComponent, With<Local>>,
+) {
+    let events = events
+        .iter()
+        .filter_map(|event| Some((event, query.get(event.entity).ok()?)))
+        .collect::<Vec<_>>();
+    if events.is_empty() {
+        return;
+    }
+
+    let timestamp = now_millis();
+    let result = storage.transaction(|transaction| {
+        for (event, game_profile) in events {
+            transaction.execute(
+                "INSERT INTO chat_archive
+                    (timestamp, server, account, platform, sender, content, component, private)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, NULL, ?7)",
+                params![
+                    timestamp,
+                    archive.server,
+                    game_profile.name,
+                    event.platform,
+                    event.username,
+                    event.content,
+                    event.private,
+                ],
+            )?;
+        }
+        Ok(())
+    });
+    if let Err(err) = result {
+        error!("couldn't archive bridged messages: {err}");
+    }
+}
+
+/// Delete messages that are older than the retention, so the archive doesn't
+/// grow forever.
+fn prune_archive(mut archive: ResMut<ChatArchive>, storage: Res<Storage>) {
+    let Some(retention) = archive.retention else {
+        return;
+    };
+    if archive
+        .last_pruned
+        .map_or(false, |last_pruned| last_pruned.elapsed() < PRUNE_INTERVAL)
+    {
+        return;
+    }
+    archive.last_pruned = Some(Instant::now());
+
+    let cutoff = now_millis().saturating_sub(retention.as_millis() as u64);
+    let result = storage.with(|connection| {
+        connection.execute(
+            "DELETE FROM chat_archive WHERE server = ?1 AND timestamp < ?2",
+            params![archive.server, cutoff],
+        )
+    });
+    match result {
+        Ok(0) => {}
+        Ok(deleted) => info!("deleted {deleted} old messages from the archive"),
+        Err(err) => error!("couldn't prune the archive: {err}"),
+    }
+}
@@ -1,49 +1,660 @@
 //! An Azalea plugin that helps you avoid getting kicked for spamming or for
 //! sending illegal chat messages.
 
-use azalea::ecs::{component::Component, AppTickExt};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use azalea::{
+    chat::{translatable_component::StringOrComponent, ChatReceivedEvent, Component},
+    ecs::{component::Component, AppTickExt},
+    entity::Local,
+    packet_handling::PacketEvent,
+    GameProfileComponent,
+};
+use azalea_protocol::packets::game::ClientboundGamePacket;
 use bevy_app::{App, Plugin};
 use bevy_ecs::{
     entity::Entity,
     event::{EventReader, EventWriter},
-    system::{Commands, Query},
+    query::{With, Without},
+    system::{Commands, Query, Res, ResMut, Resource},
 };
+use log::{info, warn};
+use rusqlite::{params, OptionalExtension};
+use serde::Deserialize;
+
+use crate::{
+    azalea_archive::now_millis,
+    azalea_message_kind::{classify, MessageKind},
+    bevy_storage::Storage,
+};
+
+pub struct AvoidKickPlugin {
+    /// How fast bots without their own rate limit can send messages.
+    pub default_rate_limit: RateLimit,
+    /// The rate limits for specific bots, keyed by the account's username.
+    pub rate_limits: HashMap<String, RateLimit>,
+    /// Whether illegal characters in bridged messages are replaced instead of
+    /// the whole message being rejected.
+    pub sanitize: SanitizeMode,
+    /// The most messages a bot can have queued at once.
+    pub max_queue_length: usize,
+    /// Which message gets dropped when the queue is full.
+    pub drop_policy: DropPolicy,
+    /// If this is set, we slow down when the server warns or kicks us for
+    /// spamming, and remember it in the database.
+    pub learned_rate_limits: Option<LearnedRateLimits>,
+    /// If this is set, queued messages are kept in the database so they're
+    /// still sent after the bot reconnects or restarts.
+    pub saved_queues: Option<SavedChatQueues>,
+}
 
-pub struct AvoidKickPlugin;
+/// How to learn a server's slowdown, and where it's saved.
+#[derive(Clone)]
+pub struct LearnedRateLimits {
+    pub storage: Storage,
+    /// What the slowdown is saved as, usually the server's address.
+    pub server: String,
+    /// System messages and disconnect reasons that contain any of these,
+    /// ignoring case, mean we're sending messages too fast. Vanilla's kick for
+    /// spamming is always recognized.
+    pub spam_warnings: Vec<String>,
+}
+impl Default for AvoidKickPlugin {
+    fn default() -> Self {
+        Self {
+            default_rate_limit: RateLimit::from_spam_counter(20, 100),
+            rate_limits: HashMap::new(),
+            sanitize: SanitizeMode::Off,
+            max_queue_length: 20,
+            drop_policy: DropPolicy::Oldest,
+            learned_rate_limits: None,
+            saved_queues: None,
+        }
+    }
+}
 
 impl Plugin for AvoidKickPlugin {
     fn build(&self, app: &mut App) {
-        app.add_event::<SendChatEvent>()
-            .add_system(send_chat_listener)
-            .add_tick_system(drain_chat_message_queue);
+        app.insert_resource(ChatRateLimits {
+            default: self.default_rate_limit,
+            accounts: self.rate_limits.clone(),
+        })
+        .insert_resource(self.sanitize)
+        .insert_resource(ChatQueueLimit {
+            max_length: self.max_queue_length,
+            drop_policy: self.drop_policy,
+        })
+        .add_event::<SendChatEvent>()
+        .add_event::<ChatMessageSent>()
+        .add_event::<ChatMessageDropped>()
+        .add_system(send_chat_listener)
+        .add_tick_system(drain_chat_message_queue);
+
+        if let Some(saved_queues) = &self.saved_queues {
+            app.insert_resource(saved_queues.clone());
+        }
+
+        if let Some(learned) = &self.learned_rate_limits {
+            app.insert_resource(LearnedSlowdown::load(
+                learned.storage.clone(),
+                learned.server.clone(),
+            ))
+            .insert_resource(SpamWarnings {
+                phrases: learned
+                    .spam_warnings
+                    .iter()
+                    .map(|phrase| phrase.to_lowercase())
+                    .collect(),
+                recently_sent: VecDeque::new(),
+            })
+            .add_system(learn_from_spam_warnings);
+        } else {
+            app.init_resource::<LearnedSlowdown>();
+        }
+    }
+}
+
+/// How fast a bot can send chat messages. This is a token bucket: the bot can
+/// send `burst` messages at once, gets to send another one every
+/// `refill_ticks`, and always waits at least `min_spacing_ticks` between
+/// messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub burst: usize,
+    pub refill_ticks: usize,
+    pub min_spacing_ticks: usize,
+}
+impl RateLimit {
+    /// A rate limit that works like vanilla's spam counter, where every message
+    /// adds `message_cost`, it goes down by one every tick, and we don't send
+    /// messages that would make it go over `max_spam`.
+    pub fn from_spam_counter(message_cost: usize, max_spam: usize) -> Self {
+        Self {
+            burst: max_spam / message_cost.max(1),
+            refill_ticks: message_cost,
+            min_spacing_ticks: 0,
+        }
+    }
+
+    /// This rate limit, but `factor` times slower.
+    pub fn slowed_down(self, factor: f64) -> Self {
+        if factor <= 1. {
+            return self;
+        }
+        Self {
+            burst: ((self.burst as f64 / factor) as usize).max(1),
+            refill_ticks: (self.refill_ticks as f64 * factor).ceil() as usize,
+            min_spacing_ticks: (self.min_spacing_ticks as f64 * factor).ceil() as usize,
+        }
+    }
+}
+
+/// Named presets for [`RateLimit`], since servers with plugins or proxies in
+/// front of them don't always behave like vanilla.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "profile", rename_all = "snake_case", deny_unknown_fields)]
+pub enum RateLimitProfile {
+    /// As fast as vanilla allows. Vanilla kicks at 200 and every message adds
+    /// 20, so we can send 9 messages at once and one more every second.
+    Vanilla,
+    /// Slow and spaced out, for servers with stricter anti-spam plugins.
+    Conservative,
+    TokenBucket {
+        burst: usize,
+        refill_ticks: usize,
+        #[serde(default)]
+        min_spacing_ticks: usize,
+    },
+}
+impl RateLimitProfile {
+    pub fn rate_limit(&self) -> RateLimit {
+        match *self {
+            Self::Vanilla => RateLimit {
+                burst: 9,
+                refill_ticks: 20,
+                min_spacing_ticks: 0,
+            },
+            Self::Conservative => RateLimit {
+                burst: 3,
+                refill_ticks: 40,
+                min_spacing_ticks: 20,
+            },
+            Self::TokenBucket {
+                burst,
+                refill_ticks,
+                min_spacing_ticks,
+            } => RateLimit {
+                burst,
+                refill_ticks,
+                min_spacing_ticks,
+            },
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct ChatRateLimits {
+    pub default: RateLimit,
+    /// Keyed by the account's username.
+    pub accounts: HashMap<String, RateLimit>,
+}
+impl ChatRateLimits {
+    pub fn for_account(&self, username: &str) -> RateLimit {
+        self.accounts.get(username).copied().unwrap_or(self.default)
+    }
+}
+
+/// Which lane a queued message goes in. Messages in higher lanes are always
+/// sent first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ChatPriority {
+    /// Announcements and other things that can wait.
+    Low,
+    /// Chat bridged from other platforms.
+    #[default]
+    Normal,
+    /// System messages and responses to commands.
+    High,
+}
+impl ChatPriority {
+    /// What the priority is saved as in the database.
+    fn to_saved(self) -> u8 {
+        match self {
+            ChatPriority::Low => 0,
+            ChatPriority::Normal => 1,
+            ChatPriority::High => 2,
+        }
+    }
+
+    fn from_saved(saved: u8) -> Self {
+        match saved {
+            0 => ChatPriority::Low,
+            2 => ChatPriority::High,
+            _ => ChatPriority::Normal,
+        }
     }
 }
 
+/// Which message gets dropped when a bot's queue is full. Messages are only
+/// ever dropped to make room for a message with the same or a higher priority.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    /// Drop the message that's been waiting the longest.
+    #[default]
+    Oldest,
+    /// Drop the message that was just queued.
+    Newest,
+    /// Drop new messages that are the same as one that's already queued, and
+    /// otherwise drop the oldest message.
+    Coalesce,
+}
+
+#[derive(Resource)]
+pub struct ChatQueueLimit {
+    pub max_length: usize,
+    pub drop_policy: DropPolicy,
+}
+
+pub struct QueuedChatMessage {
+    pub id: u64,
+    pub content: String,
+    pub priority: ChatPriority,
+    /// The message's id in the database, if it's saved there.
+    pub saved_id: Option<i64>,
+}
+
+/// Where the queued messages are saved.
+#[derive(Resource, Clone)]
+pub struct SavedChatQueues {
+    pub storage: Storage,
+    /// What the queues are saved as, usually the server's address.
+    pub server: String,
+}
+
+/// Saved messages older than this aren't sent when the bot comes back, since
+/// they'd be out of context by then.
+const MAX_SAVED_MESSAGE_AGE: Duration = Duration::from_secs(10 * 60);
+
+impl SavedChatQueues {
+    /// Save a message that was just queued, returning its id in the database.
+    fn save(&self, account: &str, message: &QueuedChatMessage) -> Option<i64> {
+        let result = self.storage.with(|connection| {
+            connection.execute(
+                "INSERT INTO chat_queue (server, account, priority, content, queued_at)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    self.server,
+                    account,
+                    message.priority.to_saved(),
+                    message.content,
+                    now_millis()
+                ],
+            )?;
+            Ok(connection.last_insert_rowid())
+        });
+        match result {
+            Ok(saved_id) => Some(saved_id),
+            Err(err) => {
+                warn!("couldn't save a queued message for {account}: {err}");
+                None
+            }
+        }
+    }
+
+    /// Forget a message that was sent or dropped.
+    fn remove(&self, message: &QueuedChatMessage) {
+        let Some(saved_id) = message.saved_id else {
+            return;
+        };
+        let result = self.storage.with(|connection| {
+            connection.execute("DELETE FROM chat_queue WHERE id = ?1", [saved_id])
+        });
+        if let Err(err) = result {
+            warn!("couldn't remove a queued message: {err}");
+        }
+    }
+
+    /// The messages that were still queued for the account when it last
+    /// disconnected, oldest first. Messages that are too old are removed
+    /// instead.
+    fn load(&self, account: &str) -> Vec<QueuedChatMessage> {
+        let cutoff = now_millis().saturating_sub(MAX_SAVED_MESSAGE_AGE.as_millis() as u64);
+        let result = self.storage.transaction(|transaction| {
+            transaction.execute(
+                "DELETE FROM chat_queue WHERE server = ?1 AND account = ?2 AND queued_at < ?3",
+                params![self.server, account, cutoff],
+            )?;
+            let mut statement = transaction.prepare(
+                "SELECT id, priority, content FROM chat_queue
+                WHERE server = ?1 AND account = ?2
+                ORDER BY id",
+            )?;
+            let rows = statement.query_map(params![self.server, account], |row| {
+                Ok(QueuedChatMessage {
+                    id: NEXT_CHAT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed),
+                    content: row.get(2)?,
+                    priority: ChatPriority::from_saved(row.get(1)?),
+                    saved_id: Some(row.get(0)?),
+                })
+            })?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+        });
+        match result {
+            Ok(messages) => messages,
+            Err(err) => {
+                warn!("couldn't load the queued messages for {account}: {err}");
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// Added to bots once they have the messages that were saved for them, so
+/// they only get them once.
+#[derive(Component)]
+pub struct RestoredChatQueue;
+
+/// How much slower we send chat on this server than the rate limit says,
+/// because the server warned or kicked us for spamming.
+#[derive(Resource)]
+pub struct LearnedSlowdown {
+    /// 1 means we haven't had any trouble.
+    pub factor: f64,
+    last_changed: Instant,
+    /// The database and the server it's saved as.
+    saved_in: Option<(Storage, String)>,
+}
+impl Default for LearnedSlowdown {
+    fn default() -> Self {
+        Self {
+            factor: 1.,
+            last_changed: Instant::now(),
+            saved_in: None,
+        }
+    }
+}
+
+/// How much slower we get every time the server complains.
+const SLOWDOWN_STEP: f64 = 1.5;
+const MAX_SLOWDOWN: f64 = 8.;
+/// Ignore complaints this soon after the last one, since servers usually warn
+/// and kick for the same burst of messages.
+const SLOWDOWN_COOLDOWN: Duration = Duration::from_secs(10);
+/// We speed back up a bit after going this long without any trouble.
+const RELAX_AFTER: Duration = Duration::from_secs(10 * 60);
+const RELAX_STEP: f64 = 1.1;
+
+/// The translation keys of messages that mean we're sending messages too fast.
+/// Vanilla kicks us with `disconnect.spam`, for both chat and commands.
+const SPAM_TRANSLATION_KEYS: &[&str] = &["disconnect.spam"];
+/// How long after sending a message the server might still be showing it back
+/// to us.
+const ECHO_WINDOW: Duration = Duration::from_secs(10);
+
+impl LearnedSlowdown {
+    fn load(storage: Storage, server: String) -> Self {
+        let factor = storage.with(|connection| {
+            connection
+                .query_row(
+                    "SELECT factor FROM learned_rate_limits WHERE server = ?1",
+                    [&server],
+                    |row| row.get::<_, f64>(0),
+                )
+                .optional()
+        });
+        let factor = match factor {
+            Ok(factor) => factor.unwrap_or(1.).clamp(1., MAX_SLOWDOWN),
+            Err(err) => {
+                warn!("couldn't load the learned rate limit for {server}: {err}");
+                1.
+            }
+        };
+        if factor > 1. {
+            info!("{server}: chat is {factor:.2}x slower because of past spam warnings");
+        }
+        Self {
+            factor,
+            last_changed: Instant::now(),
+            saved_in: Some((storage, server)),
+        }
+    }
+
+    fn tighten(&mut self) {
+        if self.last_changed.elapsed() < SLOWDOWN_COOLDOWN && self.factor > 1. {
+            return;
+        }
+        self.factor = (self.factor * SLOWDOWN_STEP).min(MAX_SLOWDOWN);
+        self.last_changed = Instant::now();
+        self.save();
+    }
+
+    fn relax(&mut self) {
+        if self.factor <= 1. || self.last_changed.elapsed() < RELAX_AFTER {
+            return;
+        }
+        self.factor = (self.factor / RELAX_STEP).max(1.);
+        self.last_changed = Instant::now();
+        self.save();
+    }
+
+    fn save(&self) {
+        let Some((storage, server)) = &self.saved_in else {
+            return;
+        };
+        let result = storage.with(|connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO learned_rate_limits (server, factor) VALUES (?1, ?2)",
+                params![server, self.factor],
+            )
+        });
+        if let Err(err) = result {
+            warn!("couldn't save the learned rate limit for {server}: {err}");
+        }
+    }
+}
+
+/// What we look for to know that the server wants us to slow down.
+#[derive(Resource)]
+pub struct SpamWarnings {
+    /// Lowercase phrases that mean we're sending messages too fast.
+    pub phrases: Vec<String>,
+    /// What we've sent lately, so the server showing it back to us isn't
+    /// mistaken for a warning.
+    recently_sent: VecDeque<(String, Instant)>,
+}
+impl SpamWarnings {
+    fn contains_phrase(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.phrases
+            .iter()
+            .any(|phrase| text.contains(phrase.as_str()))
+    }
+
+    fn is_echo(&self, text: &str) -> bool {
+        self.recently_sent
+            .iter()
+            .any(|(sent, _)| !sent.is_empty() && text.contains(sent.as_str()))
+    }
+
+    fn remember_sent(&mut self, content: &str) {
+        let now = Instant::now();
+        self.recently_sent
+            .push_back((echoed_text(content).to_string(), now));
+        while let Some((_, sent_at)) = self.recently_sent.front() {
+            if now.duration_since(*sent_at) < ECHO_WINDOW {
+                break;
+            }
+            self.recently_sent.pop_front();
+        }
+    }
+}
+
+/// The part of a message we sent that the server would show back to us. For
+/// commands like `/me` and `/msg` that's only the text at the end.
+fn echoed_text(content: &str) -> &str {
+    let Some(command) = content.strip_prefix('/') else {
+        return content;
+    };
+    let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+    match name {
+        // these have the player they're for before the text
+        "msg" | "tell" | "w" => args.split_once(' ').map_or("", |(_, text)| text),
+        _ => args,
+    }
+}
+
+/// Whether the message is vanilla's kick for spamming, or has it inside it.
+fn has_spam_translation_key(component: &Component) -> bool {
+    let Component::Translatable(translatable) = component else {
+        return false;
+    };
+    SPAM_TRANSLATION_KEYS.contains(&translatable.key.as_str())
+        || translatable.args.iter().any(|arg| match arg {
+            StringOrComponent::Component(component) => has_spam_translation_key(component),
+            StringOrComponent::String(_) => false,
+        })
+}
+
 #[derive(Component)]
 pub struct AvoidChatKick {
-    pub queued_messages: Vec<String>,
+    /// The messages waiting to be sent, in a queue for each priority.
+    pub queued_messages: BTreeMap<ChatPriority, VecDeque<QueuedChatMessage>>,
+    /// Goes up by `refill_ticks` for every message and down by one every tick.
     pub chat_spam_tick_count: usize,
+    pub ticks_since_last_message: usize,
 }
+impl AvoidChatKick {
+    fn new() -> Self {
+        Self {
+            queued_messages: BTreeMap::new(),
+            chat_spam_tick_count: 0,
+            ticks_since_last_message: usize::MAX,
+        }
+    }
+
+    /// How many messages are queued in every lane.
+    pub fn len(&self) -> usize {
+        self.queued_messages.values().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued_messages.values().all(VecDeque::is_empty)
+    }
+
+    /// Take the next message that should be sent.
+    fn pop_next(&mut self) -> Option<QueuedChatMessage> {
+        self.queued_messages
+            .values_mut()
+            .rev()
+            .find_map(|lane| lane.pop_front())
+    }
+
+    /// Add a message to the queue, returning the message that had to be dropped
+    /// if the queue was full.
+    fn push(
+        &mut self,
+        message: QueuedChatMessage,
+        limit: &ChatQueueLimit,
+    ) -> Option<QueuedChatMessage> {
+        let mut dropped = None;
+        if self.len() >= limit.max_length {
+            // the lowest priority lane with anything in it is where we drop from
+            let Some((&lowest_priority, lowest_lane)) = self
+                .queued_messages
+                .iter_mut()
+                .find(|(_, lane)| !lane.is_empty())
+            else {
+                // max_length is 0
+                return Some(message);
+            };
+            if lowest_priority > message.priority {
+                return Some(message);
+            }
+            let is_repeat = lowest_lane.iter().any(|m| m.content == message.content);
+            match limit.drop_policy {
+                DropPolicy::Newest if lowest_priority == message.priority => {
+                    return Some(message);
+                }
+                DropPolicy::Newest => dropped = lowest_lane.pop_back(),
+                DropPolicy::Coalesce if is_repeat && lowest_priority == message.priority => {
+                    return Some(message);
+                }
+                DropPolicy::Oldest | DropPolicy::Coalesce => dropped = lowest_lane.pop_front(),
+            }
+        }
+        self.queued_messages
+            .entry(message.priority)
+            .or_default()
+            .push_back(message);
+        dropped
+    }
+}
+
+static NEXT_CHAT_MESSAGE_ID: AtomicU64 = AtomicU64::new(0);
 
 pub struct SendChatEvent {
     entity: Entity,
     content: String,
+    id: u64,
+    priority: ChatPriority,
 }
 
 impl SendChatEvent {
     pub fn new(entity: Entity, content: &str) -> Option<Self> {
         let content = content.to_string();
         if message_legal_to_minecraft(&content) {
-            Some(Self { entity, content })
+            Some(Self {
+                entity,
+                content,
+                id: NEXT_CHAT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed),
+                priority: ChatPriority::default(),
+            })
         } else {
             None
         }
     }
+
+    pub fn with_priority(self, priority: ChatPriority) -> Self {
+        Self { priority, ..self }
+    }
+
+    /// An id for the message that's unique for every [`SendChatEvent`], used
+    /// to find out what happened to it with [`ChatMessageSent`] and
+    /// [`ChatMessageDropped`].
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+}
+
+/// A message from a [`SendChatEvent`] was sent to the server.
+pub struct ChatMessageSent {
+    pub entity: Entity,
+    pub id: u64,
+    pub content: String,
+}
+
+/// A message from a [`SendChatEvent`] was dropped because the queue was full.
+pub struct ChatMessageDropped {
+    pub entity: Entity,
+    pub id: u64,
+    pub content: String,
 }
 
+/// The longest chat message that Minecraft allows, in bytes.
+pub const MAX_MESSAGE_LENGTH: usize = 256;
+
 /// Whether this message can be sent to Minecraft without the server kicking us.
 fn message_legal_to_minecraft(message: &str) -> bool {
-    if message.len() > 256 {
+    if message.len() > MAX_MESSAGE_LENGTH {
         return false;
     }
     for char in message.chars() {
@@ -55,49 +666,487 @@ fn message_legal_to_minecraft(message: &str) -> bool {
     true
 }
 
+/// What to do with characters that Minecraft doesn't allow in chat.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SanitizeMode {
+    /// Reject messages with illegal characters.
+    #[default]
+    Off,
+    /// Tabs become spaces, `§` becomes `&`, and other control characters are
+    /// removed.
+    Replace,
+    /// Like `Replace`, but `§` is removed too.
+    Remove,
+}
+
+/// Replace or remove the characters that Minecraft doesn't allow in chat.
+/// Newlines are kept since messages are split on them. If the mode is
+/// [`SanitizeMode::Off`] then the message isn't changed.
+pub fn sanitize_message(message: &str, mode: SanitizeMode) -> String {
+    if mode == SanitizeMode::Off {
+        return message.to_string();
+    }
+    message
+        .chars()
+        .filter_map(|char| match char {
+            '\n' => Some('\n'),
+            '\t' => Some(' '),
+            '§' if mode == SanitizeMode::Replace => Some('&'),
+            '\x00'..='\x1F' | '\x7F' | '§' => None,
+            _ => Some(char),
+        })
+        .collect()
+}
+
+/// Split a line into pieces that are at most `max_len` bytes, preferring to
+/// split between words. Words that are too long by themselves are split
+/// between characters. The spacing is kept as it is, except for the spaces
+/// where it's split. A blank line is one empty piece.
+pub fn split_message(line: &str, max_len: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    if max_len == 0 {
+        return chunks;
+    }
+    let mut chunk = String::new();
+    // whether we just split the line, since the spaces after that aren't needed
+    let mut at_split = false;
+    for piece in words_and_spaces(line) {
+        let is_whitespace = piece.starts_with(char::is_whitespace);
+        if is_whitespace && at_split {
+            continue;
+        }
+        if chunk.len() + piece.len() <= max_len {
+            chunk.push_str(piece);
+            at_split = false;
+            continue;
+        }
+        // it gets split here, so the spaces before this aren't needed either
+        chunk.truncate(chunk.trim_end().len());
+        if !chunk.is_empty() {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        at_split = is_whitespace;
+        if is_whitespace {
+            continue;
+        }
+        // the word doesn't fit in a chunk by itself, so it has to be split up
+        for char in piece.chars() {
+            if !chunk.is_empty() && chunk.len() + char.len_utf8() > max_len {
+                chunks.push(std::mem::take(&mut chunk));
+            }
+            chunk.push(char);
+        }
+    }
+    if !chunk.is_empty() || chunks.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Split the text into words and the whitespace between them, without losing
+/// any of it.
+fn words_and_spaces(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        let is_whitespace = rest.chars().next()?.is_whitespace();
+        let end = rest
+            .find(|char: char| char.is_whitespace() != is_whitespace)
+            .unwrap_or(rest.len());
+        let (piece, after) = rest.split_at(end);
+        rest = after;
+        Some(piece)
+    })
+}
+
 fn send_chat_listener(
     mut commands: Commands,
+    limit: Res<ChatQueueLimit>,
+    saved_queues: Option<Res<SavedChatQueues>>,
     mut events: EventReader<SendChatEvent>,
-    mut query: Query<Option<&mut AvoidChatKick>>,
+    mut query: Query<(Option<&mut AvoidChatKick>, Option<&GameProfileComponent>)>,
+    unrestored: Query<(Entity, &GameProfileComponent), (With<Local>, Without<RestoredChatQueue>)>,
+    mut dropped_events: EventWriter<ChatMessageDropped>,
 ) {
+    let saved_queues = saved_queues.as_deref();
+    // the component isn't added until the commands are applied, so messages
+    // for bots without it are collected here to keep them in order
+    let mut new_states: HashMap<Entity, AvoidChatKick> = HashMap::new();
+
+    // bots that just joined get what they didn't send before they disconnected
+    if let Some(saved_queues) = saved_queues {
+        for (entity, game_profile) in &unrestored {
+            commands.entity(entity).insert(RestoredChatQueue);
+            let messages = saved_queues.load(&game_profile.name);
+            if !messages.is_empty() {
+                info!(
+                    "{}: sending {} messages that were queued before disconnecting",
+                    game_profile.name,
+                    messages.len()
+                );
+            }
+            for message in messages {
+                let dropped = match query.get_mut(entity) {
+                    Ok((Some(mut state), _)) => state.push(message, &limit),
+                    _ => new_states
+                        .entry(entity)
+                        .or_insert_with(AvoidChatKick::new)
+                        .push(message, &limit),
+                };
+                if let Some(dropped) = dropped {
+                    saved_queues.remove(&dropped);
+                }
+            }
+        }
+    }
+
     for event in events.iter() {
-        let Ok(state) = query.get_mut(event.entity) else {
+        let Ok((state, game_profile)) = query.get_mut(event.entity) else {
             continue;
         };
 
-        if let Some(mut state) = state {
-            state.queued_messages.push(event.content.clone());
+        let mut message = QueuedChatMessage {
+            id: event.id,
+            content: event.content.clone(),
+            priority: event.priority,
+            saved_id: None,
+        };
+        if let (Some(saved_queues), Some(game_profile)) = (saved_queues, game_profile) {
+            message.saved_id = saved_queues.save(&game_profile.name, &message);
+        }
+        let dropped = if let Some(mut state) = state {
+            state.push(message, &limit)
         } else {
-            commands.entity(event.entity).insert(AvoidChatKick {
-                queued_messages: vec![event.content.clone()],
-                chat_spam_tick_count: 0,
+            new_states
+                .entry(event.entity)
+                .or_insert_with(AvoidChatKick::new)
+                .push(message, &limit)
+        };
+        if let Some(dropped) = dropped {
+            if let Some(saved_queues) = saved_queues {
+                saved_queues.remove(&dropped);
+            }
+            dropped_events.send(ChatMessageDropped {
+                entity: event.entity,
+                id: dropped.id,
+                content: dropped.content,
             });
         }
     }
+    for (entity, state) in new_states {
+        commands.entity(entity).insert(state);
+    }
 }
 
 fn drain_chat_message_queue(
-    mut query: Query<(Entity, &mut AvoidChatKick)>,
+    rate_limits: Res<ChatRateLimits>,
+    slowdown: Res<LearnedSlowdown>,
+    saved_queues: Option<Res<SavedChatQueues>>,
+    mut query: Query<(Entity, &mut AvoidChatKick, Option<&GameProfileComponent>)>,
     mut chat_message_events: EventWriter<azalea::chat::SendChatEvent>,
+    mut sent_events: EventWriter<ChatMessageSent>,
 ) {
-    for (entity, mut state) in query.iter_mut() {
+    for (entity, mut state, game_profile) in query.iter_mut() {
+        let rate_limit = game_profile
+            .map(|game_profile| rate_limits.for_account(&game_profile.name))
+            .unwrap_or(rate_limits.default)
+            .slowed_down(slowdown.factor);
+        let refill_ticks = rate_limit.refill_ticks.max(1);
+
         // decrease the chat_spam_tick_count every tick (unless it's 0)
         if state.chat_spam_tick_count > 0 {
             state.chat_spam_tick_count -= 1;
         }
+        state.ticks_since_last_message = state.ticks_since_last_message.saturating_add(1);
 
-        // the 100 is actually 200 in vanilla, but i chose 100 to make sure it doesn't go over
-        let max_drain = (100 - state.chat_spam_tick_count) / 20;
-        let len = state.queued_messages.len();
-        let len = max_drain.min(len);
-        state.chat_spam_tick_count += len * 20;
+        let mut max_drain = (rate_limit.burst * refill_ticks)
+            .saturating_sub(state.chat_spam_tick_count)
+            / refill_ticks;
+        if rate_limit.min_spacing_ticks > 0 {
+            // with spacing we can only send one message at a time
+            if state.ticks_since_last_message < rate_limit.min_spacing_ticks {
+                max_drain = 0;
+            }
+            max_drain = max_drain.min(1);
+        }
+        let len = max_drain.min(state.len());
+        state.chat_spam_tick_count += len * refill_ticks;
+        if len > 0 {
+            state.ticks_since_last_message = 0;
+        }
 
-        for message in state.queued_messages.drain(..len) {
-            println!("draining chat message: {message}");
+        for _ in 0..len {
+            let Some(message) = state.pop_next() else {
+                break;
+            };
+            if let Some(saved_queues) = &saved_queues {
+                saved_queues.remove(&message);
+            }
             chat_message_events.send(azalea::chat::SendChatEvent {
                 entity,
-                content: message.clone(),
+                content: message.content.clone(),
+            });
+            sent_events.send(ChatMessageSent {
+                entity,
+                id: message.id,
+                content: message.content,
             });
         }
     }
 }
+
+/// Slow down if the server kicks us or tells us to stop spamming, and speed
+/// back up slowly if it doesn't.
+fn learn_from_spam_warnings(
+    mut slowdown: ResMut<LearnedSlowdown>,
+    mut spam_warnings: ResMut<SpamWarnings>,
+    mut packet_events: EventReader<PacketEvent>,
+    mut chat_events: EventReader<ChatReceivedEvent>,
+    mut sent_events: EventReader<ChatMessageSent>,
+    rate_limits: Res<ChatRateLimits>,
+    mut query: Query<(&mut AvoidChatKick, Option<&GameProfileComponent>)>,
+) {
+    for event in sent_events.iter() {
+        spam_warnings.remember_sent(&event.content);
+    }
+
+    let mut warned = Vec::new();
+    for event in packet_events.iter() {
+        if let ClientboundGamePacket::Disconnect(packet) = &event.packet {
+            let reason = packet.reason.to_string();
+            if has_spam_translation_key(&packet.reason) || spam_warnings.contains_phrase(&reason) {
+                warn!("kicked for spamming: {reason}");
+                warned.push(event.entity);
+            }
+        }
+    }
+    for event in chat_events.iter() {
+        let message = event.packet.message();
+        if has_spam_translation_key(&message) {
+            warn!("warned for spamming: {message}");
+            warned.push(event.entity);
+            continue;
+        }
+        // only the server can warn us, so chat, joins, deaths and everything
+        // else that has a kind is from or about a player
+        if event.packet.username().is_some() || classify(&event.packet).kind != MessageKind::System
+        {
+            continue;
+        }
+        let message = message.to_string();
+        if spam_warnings.contains_phrase(&message) && !spam_warnings.is_echo(&message) {
+            warn!("warned for spamming: {message}");
+            warned.push(event.entity);
+        }
+    }
+
+    if warned.is_empty() {
+        slowdown.relax();
+        return;
+    }
+    slowdown.tighten();
+    info!("chat is now {:.2}x slower", slowdown.factor);
+    for entity in warned {
+        // wait for the server's spam counter to go back down before sending
+        // anything else
+        let Ok((mut state, game_profile)) = query.get_mut(entity) else {
+            continue;
+        };
+        let rate_limit = game_profile
+            .map(|game_profile| rate_limits.for_account(&game_profile.name))
+            .unwrap_or(rate_limits.default)
+            .slowed_down(slowdown.factor);
+        state.chat_spam_tick_count = rate_limit.burst * rate_limit.refill_ticks;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_message_keeps_spacing() {
+        assert_eq!(split_message("a  b   c", 100), ["a  b   c"]);
+        assert_eq!(split_message("  indented", 100), ["  indented"]);
+    }
+
+    #[test]
+    fn split_message_keeps_blank_lines() {
+        assert_eq!(split_message("", 100), [""]);
+    }
+
+    #[test]
+    fn split_message_splits_between_words() {
+        assert_eq!(split_message("aaa bbb ccc", 7), ["aaa bbb", "ccc"]);
+        // the spaces where it's split aren't kept
+        assert_eq!(split_message("aaa   bbb", 4), ["aaa", "bbb"]);
+    }
+
+    #[test]
+    fn split_message_stays_under_byte_limit() {
+        let line = "the quick brown fox jumps over the lazy dog ".repeat(20);
+        let chunks = split_message(&line, 50);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 50), "{chunks:?}");
+        assert_eq!(
+            chunks.join(" ").split_whitespace().collect::<Vec<_>>(),
+            line.split_whitespace().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn split_message_doesnt_split_characters() {
+        // é is two bytes
+        assert_eq!(split_message("ééé", 4), ["éé", "é"]);
+        assert_eq!(split_message("éé éé", 5), ["éé", "éé"]);
+    }
+
+    #[test]
+    fn split_message_splits_long_words() {
+        assert_eq!(split_message("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        assert_eq!(
+            split_message("hi abcdefghij", 4),
+            ["hi", "abcd", "efgh", "ij"]
+        );
+    }
+
+    #[test]
+    fn split_message_with_no_room() {
+        assert!(split_message("hello", 0).is_empty());
+    }
+
+    fn component(json: &str) -> Component {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn spam_warnings(phrases: &[&str]) -> SpamWarnings {
+        SpamWarnings {
+            phrases: phrases.iter().map(|phrase| phrase.to_string()).collect(),
+            recently_sent: VecDeque::new(),
+        }
+    }
+
+    #[test]
+    fn vanilla_spam_kick_is_a_warning() {
+        assert!(has_spam_translation_key(&component(
+            r#"{"translate":"disconnect.spam"}"#
+        )));
+        assert!(has_spam_translation_key(&component(
+            r#"{"translate":"multiplayer.disconnect.kicked","with":[{"translate":"disconnect.spam"}]}"#
+        )));
+        assert!(!has_spam_translation_key(&component(
+            r#"{"translate":"multiplayer.player.joined","with":["spammer"]}"#
+        )));
+        assert!(!has_spam_translation_key(&component(
+            r#"{"text":"disconnect.spam"}"#
+        )));
+    }
+
+    #[test]
+    fn spam_warning_phrases_ignore_case() {
+        let spam_warnings = spam_warnings(&["slow down"]);
+        assert!(spam_warnings.contains_phrase("Please SLOW DOWN!"));
+        assert!(!spam_warnings.contains_phrase("spammer joined the game"));
+    }
+
+    #[test]
+    fn our_own_messages_arent_warnings() {
+        let mut spam_warnings = spam_warnings(&["slow down"]);
+        spam_warnings.remember_sent("/me <bob> slow down please");
+        assert!(spam_warnings.is_echo("[Member] potatobot: <bob> slow down please"));
+        assert!(!spam_warnings.is_echo("Slow down! You're sending messages too fast."));
+    }
+
+    #[test]
+    fn echoed_text_of_commands() {
+        assert_eq!(echoed_text("hello"), "hello");
+        assert_eq!(echoed_text("/me waves"), "waves");
+        assert_eq!(echoed_text("/msg bob hi there"), "hi there");
+        assert_eq!(echoed_text("/list"), "");
+    }
+
+    #[test]
+    fn rate_limit_from_spam_counter() {
+        assert_eq!(
+            RateLimit::from_spam_counter(20, 200),
+            RateLimit {
+                burst: 10,
+                refill_ticks: 20,
+                min_spacing_ticks: 0,
+            }
+        );
+        // a free message can't make the burst infinite
+        assert_eq!(RateLimit::from_spam_counter(0, 200).burst, 200);
+    }
+
+    #[test]
+    fn slowing_down_a_rate_limit() {
+        let rate_limit = RateLimitProfile::Conservative.rate_limit();
+        assert_eq!(
+            rate_limit.slowed_down(2.),
+            RateLimit {
+                burst: 1,
+                refill_ticks: 80,
+                min_spacing_ticks: 40,
+            }
+        );
+        assert_eq!(
+            RateLimitProfile::Vanilla.rate_limit().slowed_down(1.5),
+            RateLimit {
+                burst: 6,
+                refill_ticks: 30,
+                min_spacing_ticks: 0,
+            }
+        );
+        // it never gets faster, and always lets at least one message through
+        assert_eq!(rate_limit.slowed_down(0.5), rate_limit);
+        assert_eq!(rate_limit.slowed_down(100.).burst, 1);
+    }
+
+    #[test]
+    fn token_bucket_profile() {
+        let profile: RateLimitProfile =
+            toml::from_str("profile = \"token_bucket\"\nburst = 4\nrefill_ticks = 30").unwrap();
+        assert_eq!(
+            profile.rate_limit(),
+            RateLimit {
+                burst: 4,
+                refill_ticks: 30,
+                min_spacing_ticks: 0,
+            }
+        );
+    }
+
+    fn queued(content: &str, priority: ChatPriority) -> QueuedChatMessage {
+        QueuedChatMessage {
+            id: 0,
+            content: content.to_string(),
+            priority,
+            saved_id: None,
+        }
+    }
+
+    #[test]
+    fn saved_queues_come_back_in_order() {
+        let saved_queues = SavedChatQueues {
+            storage: Storage::open(std::path::Path::new(":memory:")).unwrap(),
+            server: "localhost".to_string(),
+        };
+        let mut sent = queued("sent", ChatPriority::Normal);
+        sent.saved_id = saved_queues.save("bot", &sent);
+        for (content, priority) in [("first", ChatPriority::High), ("second", ChatPriority::Low)] {
+            saved_queues.save("bot", &queued(content, priority));
+        }
+        saved_queues.save("other bot", &queued("not ours", ChatPriority::Normal));
+        saved_queues.remove(&sent);
+
+        let loaded = saved_queues.load("bot");
+        let loaded = loaded
+            .iter()
+            .map(|message| (message.content.as_str(), message.priority))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            loaded,
+            [("first", ChatPriority::High), ("second", ChatPriority::Low)]
+        );
+    }
+}
@@ -1,9 +1,10 @@
 //! Common utilities for bridging Minecraft chat to arbitrary chat platforms.
 
 use std::{
-    collections::VecDeque,
+    collections::HashMap,
+    marker::PhantomData,
     ops::{Deref, DerefMut},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use azalea::{
@@ -16,57 +17,278 @@ use azalea::{
     entity::Local,
     GameProfileComponent,
 };
+use azalea_protocol::packets::game::clientbound_player_chat_packet::ChatType;
 use bevy_ecs::{
     entity::Entity,
     query::With,
     schedule::IntoSystemDescriptor,
-    system::{ResMut, Resource},
+    system::{Res, ResMut, Resource},
 };
 
-use crate::azalea_avoid_chat_kick;
+use crate::{
+    azalea_avoid_chat_kick::{
+        self, sanitize_message, ChatMessageDropped, ChatMessageSent, ChatPriority, SanitizeMode,
+    },
+    azalea_dedup::{DedupPolicies, DedupPolicy},
+    azalea_message_kind::{classify, ClassifiedMessage},
+    azalea_player_list::{OnlinePlayer, OnlinePlayers, PlayerJoined, PlayerLeft, PlayerListPlugin},
+};
 
-pub struct BridgePlugin<T: Clone + Sync + Send + 'static>(std::marker::PhantomData<T>);
+pub struct BridgePlugin<T: Clone + Sync + Send + 'static> {
+    /// The name of the platform messages come from, like `discord`. This is
+    /// what they're archived as.
+    pub platform: &'static str,
+    /// How messages from your bridge are sent to Minecraft. `{username}` and
+    /// `{content}` are replaced.
+    pub to_minecraft_format: String,
+    /// Long or multi-line messages are split into at most this many chat
+    /// messages. Messages that need more than this are rejected.
+    pub max_chunks: usize,
+    _marker: PhantomData<T>,
+}
+impl<T: Clone + Sync + Send + 'static> BridgePlugin<T> {
+    pub fn new(platform: &'static str, to_minecraft_format: String, max_chunks: usize) -> Self {
+        Self {
+            platform,
+            to_minecraft_format,
+            max_chunks,
+            _marker: PhantomData,
+        }
+    }
+}
 impl<T: Clone + Sync + Send + 'static> Default for BridgePlugin<T> {
     fn default() -> Self {
-        Self(std::marker::PhantomData)
+        Self::new("unknown", "/me <{username}> {content}".to_string(), 4)
     }
 }
 
 impl<T: Clone + Sync + Send + 'static> Plugin for BridgePlugin<T> {
     fn build(&self, app: &mut App) {
-        app.add_event::<FromMinecraftEvent>()
-            .add_event::<ToMinecraftEvent<T>>()
+        // the Minecraft side is shared between every bridge, so it only gets
+        // added by the first BridgePlugin
+        if !app.world.contains_resource::<RecentFromMinecraft>() {
+            if !app.world.contains_resource::<OnlinePlayers>() {
+                app.add_plugin(PlayerListPlugin);
+            }
+            app.add_event::<FromMinecraftEvent>()
+                .add_event::<FromMinecraftPlayerEvent>()
+                .add_event::<WhisperFromMinecraftEvent>()
+                .add_event::<BridgedToMinecraftEvent>()
+                .init_resource::<RecentFromMinecraft>()
+                .init_resource::<DedupPolicies>()
+                .add_system(from_minecraft)
+                .add_system(pop_no_longer_recent_messages.after(from_minecraft))
+                .add_system(player_activity_from_minecraft);
+        }
+
+        app.add_event::<ToMinecraftEvent<T>>()
             .add_event::<BridgeInfoEvent<T>>()
-            .init_resource::<RecentFromMinecraft>()
-            .add_system(from_minecraft)
+            .init_resource::<SanitizeMode>()
+            .insert_resource(ToMinecraftFormat::<T> {
+                platform: self.platform,
+                template: self.to_minecraft_format.clone(),
+                max_chunks: self.max_chunks,
+                _marker: PhantomData,
+            })
+            .init_resource::<PendingToMinecraft<T>>()
             .add_system(to_minecraft::<T>)
-            .add_system(pop_no_longer_recent_messages.after(from_minecraft));
+            .add_system(track_to_minecraft::<T>.after(to_minecraft::<T>));
+    }
+}
+
+/// How messages from the bridge with context `T` are sent to Minecraft.
+#[derive(Resource)]
+pub struct ToMinecraftFormat<T: Clone + Sync + Send + 'static> {
+    pub platform: &'static str,
+    pub template: String,
+    pub max_chunks: usize,
+    _marker: PhantomData<T>,
+}
+
+/// How long we wait for a message we sent to show up in chat.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The chat messages from the bridge with context `T` that haven't shown up in
+/// chat yet.
+#[derive(Resource)]
+pub struct PendingToMinecraft<T: Clone + Sync + Send + 'static> {
+    /// Keyed by their [`SendChatEvent`](azalea_avoid_chat_kick::SendChatEvent)
+    /// id.
+    chunks: HashMap<u64, PendingChunk>,
+    /// Every [`ToMinecraftEvent`] can be split into several chat messages, so
+    /// this is keyed by the id of the first one.
+    groups: HashMap<u64, PendingGroup<T>>,
+}
+impl<T: Clone + Sync + Send + 'static> Default for PendingToMinecraft<T> {
+    fn default() -> Self {
+        Self {
+            chunks: HashMap::new(),
+            groups: HashMap::new(),
+        }
+    }
+}
+struct PendingChunk {
+    entity: Entity,
+    group: u64,
+    /// The text we expect the bot to say in chat once it's delivered, or None
+    /// if it's a command that doesn't show anything.
+    expected_echo: Option<String>,
+    /// None if it's still queued.
+    sent_at: Option<Instant>,
+}
+struct PendingGroup<T> {
+    context: T,
+    remaining: usize,
+    /// Whether we already told the bridge that part of it didn't make it.
+    failed: bool,
+}
+
+/// What we expect the bot to say in chat after sending this. `/me`, `/say` and
+/// whispers show up as the bot saying the text with a different decoration.
+/// Other commands don't show anything we can recognize, so they're None.
+fn expected_echo(content: &str) -> Option<String> {
+    if let Some(whisper) = strip_whisper_command(content) {
+        return Some(whisper.split_once(' ')?.1.to_string());
+    }
+    if let Some(text) = content
+        .strip_prefix("/me ")
+        .or_else(|| content.strip_prefix("/say "))
+    {
+        return Some(text.to_string());
     }
+    if content.starts_with('/') {
+        return None;
+    }
+    Some(content.to_string())
+}
+
+/// Whether a chat message is the bot saying exactly `expected`. `sender` and
+/// `content` are from [`ChatPacket::split_sender_and_content`], and `message`
+/// is the whole message, which is how `/me` looks when the server sends it as
+/// plain text.
+fn is_echo(
+    sender: Option<&str>,
+    content: &str,
+    message: &str,
+    bot_name: &str,
+    expected: &str,
+) -> bool {
+    (sender == Some(bot_name) && content == expected)
+        || message == format!("* {bot_name} {expected}")
+}
+
+/// Remove the command from a chat message like `/msg player text`, or return
+/// None if it isn't a whisper.
+fn strip_whisper_command(content: &str) -> Option<&str> {
+    content
+        .strip_prefix("/msg ")
+        .or_else(|| content.strip_prefix("/tell "))
+        .or_else(|| content.strip_prefix("/w "))
 }
 
 /// We received a message from Minecraft. This is what you should show in your
 /// bridge. This may not be exactly the same message shown in Minecraft, since
 /// it attempts to de-duplicate messages.
 pub struct FromMinecraftEvent {
+    /// The bot that received the message.
+    pub entity: Entity,
     pub content: String,
     pub packet: ChatPacket,
+    /// How many times the message was sent. This is already shown in
+    /// `content`, but it's here in case you want to format the message
+    /// yourself.
+    pub sent_count: usize,
+    /// Whether it's chat, a death, an advancement and so on, and who it's
+    /// about.
+    pub classified: ClassifiedMessage,
+}
+impl FromMinecraftEvent {
+    fn new(entity: Entity, message: RecentMessage) -> Self {
+        Self {
+            entity,
+            content: format_for_repeats(&message.content, message.sent_count),
+            classified: classify(&message.packet),
+            packet: message.packet,
+            sent_count: message.sent_count,
+        }
+    }
+}
+
+/// A player whispered to the bot. Whispers are private, so they're sent as this
+/// instead of a [`FromMinecraftEvent`].
+pub struct WhisperFromMinecraftEvent {
+    /// The bot that was whispered to.
+    pub entity: Entity,
+    pub sender: String,
+    pub content: String,
+}
+
+/// A player joined or left the server. These are sent separately from chat
+/// messages so each bridge can show them in its own way.
+pub struct FromMinecraftPlayerEvent {
+    /// The bot that saw the player join or leave.
+    pub entity: Entity,
+    pub kind: PlayerActivity,
+    pub player: OnlinePlayer,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlayerActivity {
+    Joined,
+    Left,
 }
 
 /// We're sending a message to Minecraft from your bridge.
 pub struct ToMinecraftEvent<T: Clone + Sync + Send + 'static> {
+    /// The username of the Minecraft account that should send the message.
+    pub account: String,
     pub username: String,
     pub content: String,
+    /// Which lane the message is queued in. Bridged chat should be
+    /// [`ChatPriority::Normal`].
+    pub priority: ChatPriority,
     pub context: T,
 }
 
+/// A message from one of the bridges was queued to be sent to Minecraft. This is
+/// sent for every bridge, so it's useful for things like logging.
+pub struct BridgedToMinecraftEvent {
+    /// The bot that's sending the message.
+    pub entity: Entity,
+    /// The platform the message came from, like `discord`.
+    pub platform: &'static str,
+    pub username: String,
+    pub content: String,
+    /// Whether the message is being whispered to one player instead of sent
+    /// in public chat.
+    pub private: bool,
+}
+
 pub struct BridgeInfoEvent<T: Clone + Sync + Send + 'static> {
     pub kind: BridgeInfoKind,
     pub context: T,
 }
 pub enum BridgeInfoKind {
-    Ack,
+    /// The message is in the bot's chat queue.
+    Queued,
+    /// The message showed up in Minecraft chat.
+    Delivered,
+    /// The message was sent but didn't show up in chat, so the server might
+    /// have ignored it.
+    TimedOut,
+    /// The message was sent, but some characters had to be replaced or
+    /// removed first.
+    Sanitized,
     NotInServer,
     IllegalMessage,
+    /// The message would have to be split into more than `max_chunks` chat
+    /// messages.
+    TooLong {
+        chunks: usize,
+        max_chunks: usize,
+    },
+    /// The bot's chat queue was full, so the message (or part of it) was
+    /// dropped.
+    Dropped,
 }
 
 #[derive(Clone)]
@@ -77,10 +299,12 @@ pub struct RecentMessage {
     pub sent_count: usize,
     pub sent_at: Instant,
 }
+/// The dedup policy for each bot, which keeps track of the recent messages it
+/// received.
 #[derive(Resource, Default)]
-pub struct RecentFromMinecraft(VecDeque<RecentMessage>);
+pub struct RecentFromMinecraft(HashMap<Entity, Box<dyn DedupPolicy>>);
 impl Deref for RecentFromMinecraft {
-    type Target = VecDeque<RecentMessage>;
+    type Target = HashMap<Entity, Box<dyn DedupPolicy>>;
     fn deref(&self) -> &Self::Target {
         &self.0
     }
@@ -95,142 +319,515 @@ fn from_minecraft(
     mut recent_from_minecraft: ResMut<RecentFromMinecraft>,
     mut events: EventReader<azalea::chat::ChatReceivedEvent>,
     mut from_minecraft_events: EventWriter<FromMinecraftEvent>,
+    mut whisper_events: EventWriter<WhisperFromMinecraftEvent>,
+    dedup_policies: Res<DedupPolicies>,
     query: Query<&GameProfileComponent, With<Local>>,
 ) {
     for event in events.iter() {
-        println!(
-            "Got Minecraft chat packet: {}",
-            event.packet.message().to_ansi()
-        );
-
-        let game_profile = query.single();
+        let Ok(game_profile) = query.get(event.entity) else {
+            continue;
+        };
         if event.packet.username() == Some(game_profile.name.clone()) {
             // we sent this message lol
-            return;
+            continue;
         }
-
-        let message_string = event.packet.message().to_string();
-
-        // check if the message is the same as one of the recent messages
-        for (i, recent_message) in recent_from_minecraft.clone().iter().enumerate() {
-            if recent_message.content == message_string {
-                // remove it and add it back with the sent_count increased
-                recent_from_minecraft.remove(i);
-                let new_sent_count = recent_message.sent_count + 1;
-                recent_from_minecraft.push_back(RecentMessage {
-                    content: message_string.clone(),
-                    sent_count: new_sent_count,
-                    sent_at: Instant::now(),
-                    packet: event.packet.clone(),
+        match parse_whisper(&event.packet) {
+            Some(Whisper::Incoming { sender, content }) => {
+                whisper_events.send(WhisperFromMinecraftEvent {
+                    entity: event.entity,
+                    sender,
+                    content,
                 });
-
-                // if it's a power of 2, send it to discord with [x<number>] at the end
-                if new_sent_count.is_power_of_two() {
-                    from_minecraft_events.send(FromMinecraftEvent {
-                        content: format_for_repeats(&message_string, new_sent_count),
-                        packet: event.packet.clone(),
-                    });
-                }
-                return;
+                continue;
             }
+            // whispers the bot sent are just as private
+            Some(Whisper::Outgoing) => continue,
+            None => {}
         }
-        recent_from_minecraft.push_back(RecentMessage {
-            content: message_string.clone(),
+
+        let message_string = event.packet.message().to_string();
+        let policy = recent_from_minecraft
+            .entry(event.entity)
+            .or_insert_with(|| dedup_policies.build_for(&game_profile.name));
+
+        let messages = policy.receive(RecentMessage {
+            content: message_string,
             sent_count: 1,
             sent_at: Instant::now(),
             packet: event.packet.clone(),
         });
-        from_minecraft_events.send(FromMinecraftEvent {
-            content: message_string,
-            packet: event.packet.clone(),
+        for message in messages {
+            from_minecraft_events.send(FromMinecraftEvent::new(event.entity, message));
+        }
+    }
+}
+
+/// Whether a chat message is a whisper to or from the bot.
+pub fn is_whisper(packet: &ChatPacket) -> bool {
+    parse_whisper(packet).is_some()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Whisper {
+    Incoming { sender: String, content: String },
+    Outgoing,
+}
+
+/// Check whether a chat message is a whisper. Vanilla sends them as player chat
+/// with their own chat type, but servers with plugins usually send them as
+/// system messages like `[player -> me] text` instead.
+fn parse_whisper(packet: &ChatPacket) -> Option<Whisper> {
+    if let ChatPacket::Player(player_chat) = packet {
+        return match player_chat.chat_type.chat_type {
+            ChatType::MsgCommandIncoming => Some(Whisper::Incoming {
+                sender: player_chat.chat_type.name.to_string(),
+                content: packet.split_sender_and_content().1,
+            }),
+            ChatType::MsgCommandOutgoing => Some(Whisper::Outgoing),
+            _ => None,
+        };
+    }
+
+    parse_plain_whisper(&packet.message().to_string())
+}
+
+/// Check whether a system message looks like a whisper from a plugin.
+fn parse_plain_whisper(message: &str) -> Option<Whisper> {
+    if message.starts_with("You whisper to ") || message.starts_with("[me -> ") {
+        return Some(Whisper::Outgoing);
+    }
+    let (sender, content) =
+        if let Some((sender, content)) = message.split_once(" whispers to you: ") {
+            (sender, content)
+        } else {
+            let (sender, content) = message.strip_prefix('[')?.split_once(" -> me] ")?;
+            (sender, content)
+        };
+    // make sure it's actually a player and not just a message that looks like
+    // a whisper
+    if sender.is_empty()
+        || sender.len() > 16
+        || !sender
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return None;
+    }
+    Some(Whisper::Incoming {
+        sender: sender.to_string(),
+        content: content.to_string(),
+    })
+}
+
+fn player_activity_from_minecraft(
+    mut player_joined_events: EventReader<PlayerJoined>,
+    mut player_left_events: EventReader<PlayerLeft>,
+    mut from_minecraft_player_events: EventWriter<FromMinecraftPlayerEvent>,
+) {
+    for event in player_joined_events.iter() {
+        from_minecraft_player_events.send(FromMinecraftPlayerEvent {
+            entity: event.entity,
+            kind: PlayerActivity::Joined,
+            player: event.player.clone(),
+        });
+    }
+    for event in player_left_events.iter() {
+        from_minecraft_player_events.send(FromMinecraftPlayerEvent {
+            entity: event.entity,
+            kind: PlayerActivity::Left,
+            player: event.player.clone(),
         });
     }
 }
 
 fn to_minecraft<T: Clone + Sync + Send + 'static>(
-    query: Query<Entity, With<Local>>,
+    query: Query<(Entity, &GameProfileComponent), With<Local>>,
+    format: Res<ToMinecraftFormat<T>>,
+    sanitize: Res<SanitizeMode>,
     mut events: EventReader<ToMinecraftEvent<T>>,
     mut send_chat_events: EventWriter<azalea_avoid_chat_kick::SendChatEvent>,
     mut bridge_error_events: EventWriter<BridgeInfoEvent<T>>,
+    mut bridged_events: EventWriter<BridgedToMinecraftEvent>,
+    mut pending: ResMut<PendingToMinecraft<T>>,
 ) {
     for event in events.iter() {
-        let Ok(entity) =
-            query.get_single() else {
-                // the bot isn't on the server
-                bridge_error_events.send(BridgeInfoEvent {
-                    context: event.context.clone(),
-					kind: BridgeInfoKind::NotInServer,
-                });
-                return;
-            };
+        let Some((entity, _)) = query
+            .iter()
+            .find(|(_, game_profile)| game_profile.name == event.account)
+        else {
+            // the bot isn't on the server
+            bridge_error_events.send(BridgeInfoEvent {
+                context: event.context.clone(),
+                kind: BridgeInfoKind::NotInServer,
+            });
+            continue;
+        };
 
-        // check if a message is legal and add it to the queue!
-        let message_content = format!("/me <{}> {}", event.username, event.content);
+        let username = sanitize_message(&event.username, *sanitize);
+        let content = sanitize_message(&event.content, *sanitize);
+        let sanitized = username != event.username || content != event.content;
 
-        let chat_message_event =
-            azalea_avoid_chat_kick::SendChatEvent::new(entity, &message_content);
+        // every chunk gets the username, so that's how much space we have left
+        // for the content
+        let prefix_len = fill_template(
+            &format.template,
+            &[("username", &username), ("content", "")],
+        )
+        .len();
+        let max_chunk_len = azalea_avoid_chat_kick::MAX_MESSAGE_LENGTH.saturating_sub(prefix_len);
+        let chunks = message_chunks(&content, max_chunk_len);
 
-        if chat_message_event.is_none() {
+        if chunks.len() > format.max_chunks {
             bridge_error_events.send(BridgeInfoEvent {
                 context: event.context.clone(),
-                kind: BridgeInfoKind::IllegalMessage,
+                kind: BridgeInfoKind::TooLong {
+                    chunks: chunks.len(),
+                    max_chunks: format.max_chunks,
+                },
             });
-            println!("illegal message");
-            return;
+            continue;
         }
-        let chat_message_event = chat_message_event.unwrap();
 
+        // check if every chunk is legal before we add any of them to the queue!
+        let chat_message_events = chunks
+            .iter()
+            .map(|chunk| {
+                fill_template(
+                    &format.template,
+                    &[("username", &username), ("content", chunk)],
+                )
+            })
+            .map(|message_content| {
+                azalea_avoid_chat_kick::SendChatEvent::new(entity, &message_content)
+                    .map(|chat_event| chat_event.with_priority(event.priority))
+            })
+            .collect::<Option<Vec<_>>>();
+
+        let Some(chat_message_events) = chat_message_events.filter(|events| !events.is_empty())
+        else {
+            bridge_error_events.send(BridgeInfoEvent {
+                context: event.context.clone(),
+                kind: BridgeInfoKind::IllegalMessage,
+            });
+            continue;
+        };
+
+        if sanitized {
+            bridge_error_events.send(BridgeInfoEvent {
+                context: event.context.clone(),
+                kind: BridgeInfoKind::Sanitized,
+            });
+        }
         bridge_error_events.send(BridgeInfoEvent {
             context: event.context.clone(),
-            kind: BridgeInfoKind::Ack,
+            kind: BridgeInfoKind::Queued,
+        });
+        let group = chat_message_events[0].id();
+        pending.groups.insert(
+            group,
+            PendingGroup {
+                context: event.context.clone(),
+                remaining: chat_message_events.len(),
+                failed: false,
+            },
+        );
+        for chat_message_event in &chat_message_events {
+            pending.chunks.insert(
+                chat_message_event.id(),
+                PendingChunk {
+                    entity,
+                    group,
+                    expected_echo: expected_echo(chat_message_event.content()),
+                    sent_at: None,
+                },
+            );
+        }
+        send_chat_events.send_batch(chat_message_events);
+        bridged_events.send(BridgedToMinecraftEvent {
+            entity,
+            platform: format.platform,
+            username,
+            content,
+            private: strip_whisper_command(&format.template).is_some(),
         });
-        send_chat_events.send(chat_message_event);
-        println!("send chat event");
     }
 }
 
-fn pop_no_longer_recent_messages(
-    mut recent_from_minecraft: ResMut<RecentFromMinecraft>,
-    mut from_minecraft_events: EventWriter<FromMinecraftEvent>,
+/// Split a message into the pieces that are sent as separate chat messages,
+/// one or more for each line.
+fn message_chunks(content: &str, max_chunk_len: usize) -> Vec<String> {
+    content
+        .lines()
+        .flat_map(|line| azalea_avoid_chat_kick::split_message(line, max_chunk_len))
+        // minecraft doesn't show blank messages, and they'd still get the
+        // username so they'd be sent as almost empty messages
+        .filter(|chunk| !chunk.trim().is_empty())
+        .collect()
+}
+
+/// Keep track of the chat messages we queued until they show up in chat, and
+/// tell the bridge whether they were delivered.
+fn track_to_minecraft<T: Clone + Sync + Send + 'static>(
+    mut pending: ResMut<PendingToMinecraft<T>>,
+    mut sent_events: EventReader<ChatMessageSent>,
+    mut dropped_events: EventReader<ChatMessageDropped>,
+    mut chat_events: EventReader<azalea::chat::ChatReceivedEvent>,
+    mut bridge_info_events: EventWriter<BridgeInfoEvent<T>>,
+    query: Query<&GameProfileComponent, With<Local>>,
 ) {
-    loop {
-        let front_message = {
-            // if there's at most 5 messages and the oldest message is less than max_wait_time seconds old, we're good
-
-            let waited_enough = recent_from_minecraft
-                .front()
-                .map(|m| {
-                    // we're more lenient with the waiting if they sent a lot of messages
-                    let max_wait_time = if m.sent_count > 32 {
-                        16
-                    } else if m.sent_count > 16 {
-                        8
-                    } else {
-                        2
-                    };
-                    m.sent_at.elapsed().as_secs() > max_wait_time
-                })
-                .unwrap_or(false);
-            if recent_from_minecraft.len() <= 5 && !waited_enough {
-                break;
+    let pending = &mut *pending;
+    // (chunk id, whether it was delivered)
+    let mut finished = Vec::new();
+    for event in sent_events.iter() {
+        if let Some(chunk) = pending.chunks.get_mut(&event.id) {
+            chunk.sent_at = Some(Instant::now());
+            // there's no echo to wait for, so being sent is as far as we can
+            // tell
+            if chunk.expected_echo.is_none() {
+                finished.push((event.id, BridgeInfoKind::Delivered));
             }
-            recent_from_minecraft.pop_front().expect(
-                "we just checked to make sure there's stuff in recent_items so it shouldn't be empty",
-            )
+        }
+    }
+
+    for event in dropped_events.iter() {
+        if pending.chunks.contains_key(&event.id) {
+            finished.push((event.id, BridgeInfoKind::Dropped));
+        }
+    }
+    for event in chat_events.iter() {
+        let Ok(game_profile) = query.get(event.entity) else {
+            continue;
+        };
+        let (sender, content) = event.packet.split_sender_and_content();
+        let message = event.packet.message().to_string();
+        // the oldest chunk that this is the echo of
+        let echoed = pending
+            .chunks
+            .iter()
+            .filter(|(_, chunk)| chunk.entity == event.entity)
+            .filter_map(|(&id, chunk)| Some((id, chunk.sent_at?, chunk.expected_echo.as_ref()?)))
+            .filter(|(_, _, expected)| {
+                is_echo(
+                    sender.as_deref(),
+                    &content,
+                    &message,
+                    &game_profile.name,
+                    expected,
+                )
+            })
+            .min_by_key(|(_, sent_at, _)| *sent_at)
+            .map(|(id, _, _)| id);
+        if let Some(id) = echoed {
+            finished.push((id, BridgeInfoKind::Delivered));
+        }
+    }
+    for (&id, chunk) in &pending.chunks {
+        let timed_out = chunk
+            .sent_at
+            .map(|sent_at| sent_at.elapsed() > DELIVERY_TIMEOUT)
+            .unwrap_or(false);
+        // if the bot disconnected then the message is never going to show up
+        if timed_out || !query.contains(chunk.entity) {
+            finished.push((id, BridgeInfoKind::TimedOut));
+        }
+    }
+
+    for (id, kind) in finished {
+        let Some(chunk) = pending.chunks.remove(&id) else {
+            continue;
         };
-        // if it's a power of 2 that means we already sent it
-        if front_message.sent_count > 2 && !front_message.sent_count.is_power_of_two() {
-            from_minecraft_events.send(FromMinecraftEvent {
-                content: format_for_repeats(&front_message.content, front_message.sent_count),
-                packet: front_message.packet,
+        let Some(group) = pending.groups.get_mut(&chunk.group) else {
+            continue;
+        };
+        group.remaining -= 1;
+        if !matches!(kind, BridgeInfoKind::Delivered) && !group.failed {
+            // only report the first chunk that didn't make it
+            group.failed = true;
+            bridge_info_events.send(BridgeInfoEvent {
+                kind,
+                context: group.context.clone(),
             });
         }
+        if group.remaining == 0 {
+            let group = pending.groups.remove(&chunk.group).unwrap();
+            if !group.failed {
+                bridge_info_events.send(BridgeInfoEvent {
+                    kind: BridgeInfoKind::Delivered,
+                    context: group.context,
+                });
+            }
+        }
+    }
+}
+
+fn pop_no_longer_recent_messages(
+    mut recent_from_minecraft: ResMut<RecentFromMinecraft>,
+    mut from_minecraft_events: EventWriter<FromMinecraftEvent>,
+    query: Query<Entity, With<Local>>,
+) {
+    // don't keep policies around for bots that disconnected
+    recent_from_minecraft.retain(|&entity, _| query.contains(entity));
+
+    for (&entity, policy) in recent_from_minecraft.iter_mut() {
+        for message in policy.flush() {
+            from_minecraft_events.send(FromMinecraftEvent::new(entity, message));
+        }
     }
 }
 
-fn format_for_repeats(message: &str, sent_count: usize) -> String {
+pub fn format_for_repeats(message: &str, sent_count: usize) -> String {
     if sent_count == 1 {
         return message.to_string();
     }
     format!("{message} [x{sent_count}]")
 }
+
+/// Replace every `{key}` in the template with its value. This is done in one
+/// pass so placeholders inside of the values aren't replaced.
+pub fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let after_brace = &rest[start + 1..];
+        let replacement = after_brace.find('}').and_then(|end| {
+            let key = &after_brace[..end];
+            values
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, value)| (*value, end))
+        });
+        match replacement {
+            Some((value, end)) => {
+                filled.push_str(value);
+                rest = &after_brace[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = after_brace;
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expected_echo_of_chat_and_commands() {
+        assert_eq!(expected_echo("hello").as_deref(), Some("hello"));
+        assert_eq!(expected_echo("/me <bob> hi").as_deref(), Some("<bob> hi"));
+        assert_eq!(expected_echo("/say hi").as_deref(), Some("hi"));
+        assert_eq!(
+            expected_echo("/msg bob hi there").as_deref(),
+            Some("hi there")
+        );
+        assert_eq!(expected_echo("/tp bob"), None);
+    }
+
+    #[test]
+    fn echoes_are_from_the_bot() {
+        assert!(is_echo(
+            Some("potatobot"),
+            "hi",
+            "<potatobot> hi",
+            "potatobot",
+            "hi"
+        ));
+        // someone else saying the same thing doesn't count
+        assert!(!is_echo(Some("bob"), "hi", "<bob> hi", "potatobot", "hi"));
+        assert!(!is_echo(None, "hi", "hi", "potatobot", "hi"));
+    }
+
+    #[test]
+    fn echoes_have_the_exact_content() {
+        assert!(!is_echo(
+            Some("potatobot"),
+            "hi there",
+            "<potatobot> hi there",
+            "potatobot",
+            "hi"
+        ));
+        assert!(!is_echo(
+            Some("bob"),
+            "<potatobot> hi",
+            "<bob> <potatobot> hi",
+            "potatobot",
+            "hi"
+        ));
+    }
+
+    #[test]
+    fn plain_text_emotes_are_echoes() {
+        assert!(is_echo(
+            None,
+            "* potatobot <bob> hi",
+            "* potatobot <bob> hi",
+            "potatobot",
+            "<bob> hi"
+        ));
+        assert!(!is_echo(
+            None,
+            "* bob <bob> hi",
+            "* bob <bob> hi",
+            "potatobot",
+            "<bob> hi"
+        ));
+    }
+
+    fn incoming(sender: &str, content: &str) -> Option<Whisper> {
+        Some(Whisper::Incoming {
+            sender: sender.to_string(),
+            content: content.to_string(),
+        })
+    }
+
+    #[test]
+    fn plain_incoming_whispers() {
+        assert_eq!(
+            parse_plain_whisper("bob whispers to you: hi there"),
+            incoming("bob", "hi there")
+        );
+        assert_eq!(
+            parse_plain_whisper("[bob_2 -> me] hi there"),
+            incoming("bob_2", "hi there")
+        );
+    }
+
+    #[test]
+    fn plain_outgoing_whispers() {
+        assert_eq!(
+            parse_plain_whisper("You whisper to bob: hi"),
+            Some(Whisper::Outgoing)
+        );
+        assert_eq!(
+            parse_plain_whisper("[me -> bob] hi"),
+            Some(Whisper::Outgoing)
+        );
+    }
+
+    #[test]
+    fn messages_that_look_like_whispers() {
+        for message in [
+            "<bob> alice whispers to you: hi",
+            " whispers to you: hi",
+            "[a_name_thats_too_long -> me] hi",
+            "[bob -> everyone] hi",
+            "bob: hi",
+        ] {
+            assert_eq!(parse_plain_whisper(message), None, "{message}");
+        }
+    }
+
+    #[test]
+    fn blank_lines_arent_chunks() {
+        assert_eq!(
+            message_chunks("hello\n\n  \nthere", 100),
+            ["hello", "there"]
+        );
+        assert!(message_chunks("\n\n", 100).is_empty());
+    }
+}
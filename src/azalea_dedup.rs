@@ -0,0 +1,405 @@
+//! Policies for collapsing repeated Minecraft messages before they're bridged.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use azalea::ecs::app::{App, Plugin};
+use bevy_ecs::system::Resource;
+use serde::Deserialize;
+
+use crate::azalea_bridge::RecentMessage;
+
+/// Decides which messages from Minecraft are repeats, and when to report how
+/// many times they were sent.
+///
+/// Messages returned from [`Self::receive`] and [`Self::flush`] are bridged,
+/// with `[x<sent_count>]` added if they were sent more than once.
+pub trait DedupPolicy: Send + Sync + 'static {
+    /// We received a message from Minecraft. `message.sent_count` is always 1.
+    /// Returns the messages that should be bridged right away.
+    fn receive(&mut self, message: RecentMessage) -> Vec<RecentMessage>;
+    /// Called every update. Returns the messages that should be bridged
+    /// because we're done waiting for them to be repeated.
+    fn flush(&mut self) -> Vec<RecentMessage>;
+}
+
+/// Remember the last few messages, and if one of them is sent again then count
+/// it instead of bridging it again. Counts are reported when they reach a power
+/// of two, and once more when the message is forgotten.
+pub struct CollapseRepeats {
+    /// How many different messages we remember at once.
+    pub max_recent: usize,
+    /// How long to wait for a message to be repeated before forgetting it. This
+    /// is longer for messages that have already been repeated a lot.
+    pub wait: Duration,
+    recent: VecDeque<RecentMessage>,
+}
+impl CollapseRepeats {
+    pub fn new(max_recent: usize, wait: Duration) -> Self {
+        Self {
+            max_recent,
+            wait,
+            recent: VecDeque::new(),
+        }
+    }
+
+    fn wait_for(&self, sent_count: usize) -> Duration {
+        // we're more lenient with the waiting if they sent a lot of messages
+        if sent_count > 32 {
+            self.wait * 8
+        } else if sent_count > 16 {
+            self.wait * 4
+        } else {
+            self.wait
+        }
+    }
+}
+impl DedupPolicy for CollapseRepeats {
+    fn receive(&mut self, message: RecentMessage) -> Vec<RecentMessage> {
+        // check if the message is the same as one of the recent messages
+        if let Some(i) = self
+            .recent
+            .iter()
+            .position(|m| m.content == message.content)
+        {
+            // remove it and add it back with the sent_count increased
+            let recent_message = self.recent.remove(i).unwrap();
+            let repeated_message = RecentMessage {
+                sent_count: recent_message.sent_count + 1,
+                ..message
+            };
+            self.recent.push_back(repeated_message.clone());
+
+            // if it's a power of 2, send it with [x<number>] at the end
+            if repeated_message.sent_count.is_power_of_two() {
+                return vec![repeated_message];
+            }
+            return vec![];
+        }
+        self.recent.push_back(message.clone());
+        vec![message]
+    }
+
+    fn flush(&mut self) -> Vec<RecentMessage> {
+        let mut flushed = Vec::new();
+        loop {
+            // if there's at most max_recent messages and the oldest message is
+            // less than max_wait_time seconds old, we're good
+            let waited_enough = self
+                .recent
+                .front()
+                .map(|m| m.sent_at.elapsed().as_secs() > self.wait_for(m.sent_count).as_secs())
+                .unwrap_or(false);
+            if self.recent.len() <= self.max_recent && !waited_enough {
+                break;
+            }
+            let front_message = self.recent.pop_front().expect(
+                "we just checked to make sure there's stuff in recent so it shouldn't be empty",
+            );
+            // if it's a power of 2 that means we already sent it
+            if front_message.sent_count > 2 && !front_message.sent_count.is_power_of_two() {
+                flushed.push(front_message);
+            }
+        }
+        flushed
+    }
+}
+
+/// Bridge every message, even if it's a repeat.
+pub struct NoDedup;
+impl DedupPolicy for NoDedup {
+    fn receive(&mut self, message: RecentMessage) -> Vec<RecentMessage> {
+        vec![message]
+    }
+
+    fn flush(&mut self) -> Vec<RecentMessage> {
+        vec![]
+    }
+}
+
+/// Bridge the first time a message is sent, and count any repeats until the
+/// window after it ends. The count is reported once when the window ends.
+pub struct TimeWindow {
+    pub window: Duration,
+    recent: Vec<RecentMessage>,
+}
+impl TimeWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            recent: Vec::new(),
+        }
+    }
+}
+impl DedupPolicy for TimeWindow {
+    fn receive(&mut self, message: RecentMessage) -> Vec<RecentMessage> {
+        if let Some(recent_message) = self
+            .recent
+            .iter_mut()
+            .find(|m| m.content == message.content)
+        {
+            // sent_at is left alone so the window starts at the first message
+            recent_message.sent_count += 1;
+            recent_message.packet = message.packet;
+            return vec![];
+        }
+        self.recent.push(message.clone());
+        vec![message]
+    }
+
+    fn flush(&mut self) -> Vec<RecentMessage> {
+        let mut flushed = Vec::new();
+        let window = self.window;
+        self.recent.retain(|m| {
+            if m.sent_at.elapsed() < window {
+                return true;
+            }
+            if m.sent_count > 1 {
+                flushed.push(m.clone());
+            }
+            false
+        });
+        flushed
+    }
+}
+
+/// Like [`CollapseRepeats`], but every sender gets their own recent messages,
+/// so one person spamming doesn't push everyone else's messages out.
+pub struct PerSender {
+    pub max_recent: usize,
+    pub wait: Duration,
+    /// The policies for each sender. System messages have no sender.
+    senders: HashMap<Option<String>, CollapseRepeats>,
+}
+impl PerSender {
+    pub fn new(max_recent: usize, wait: Duration) -> Self {
+        Self {
+            max_recent,
+            wait,
+            senders: HashMap::new(),
+        }
+    }
+}
+impl DedupPolicy for PerSender {
+    fn receive(&mut self, message: RecentMessage) -> Vec<RecentMessage> {
+        let (max_recent, wait) = (self.max_recent, self.wait);
+        self.senders
+            .entry(message.packet.username())
+            .or_insert_with(|| CollapseRepeats::new(max_recent, wait))
+            .receive(message)
+    }
+
+    fn flush(&mut self) -> Vec<RecentMessage> {
+        let flushed = self
+            .senders
+            .values_mut()
+            .flat_map(|policy| policy.flush())
+            .collect();
+        // forget senders once they don't have any recent messages
+        self.senders.retain(|_, policy| !policy.recent.is_empty());
+        flushed
+    }
+}
+
+/// Which [`DedupPolicy`] to use and its parameters. Dedup happens before
+/// messages are split up between an account's bridges, so each account has
+/// one of these for all of them.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "policy", rename_all = "snake_case", deny_unknown_fields)]
+pub enum DedupSettings {
+    CollapseRepeats {
+        #[serde(default = "default_max_recent")]
+        max_recent: usize,
+        #[serde(default = "default_wait_secs")]
+        wait_secs: u64,
+    },
+    Off,
+    TimeWindow {
+        window_secs: u64,
+    },
+    PerSender {
+        #[serde(default = "default_max_recent")]
+        max_recent: usize,
+        #[serde(default = "default_wait_secs")]
+        wait_secs: u64,
+    },
+}
+fn default_max_recent() -> usize {
+    5
+}
+fn default_wait_secs() -> u64 {
+    2
+}
+impl Default for DedupSettings {
+    fn default() -> Self {
+        Self::CollapseRepeats {
+            max_recent: default_max_recent(),
+            wait_secs: default_wait_secs(),
+        }
+    }
+}
+impl DedupSettings {
+    pub fn build(&self) -> Box<dyn DedupPolicy> {
+        match *self {
+            Self::CollapseRepeats {
+                max_recent,
+                wait_secs,
+            } => Box::new(CollapseRepeats::new(
+                max_recent,
+                Duration::from_secs(wait_secs),
+            )),
+            Self::Off => Box::new(NoDedup),
+            Self::TimeWindow { window_secs } => {
+                Box::new(TimeWindow::new(Duration::from_secs(window_secs)))
+            }
+            Self::PerSender {
+                max_recent,
+                wait_secs,
+            } => Box::new(PerSender::new(max_recent, Duration::from_secs(wait_secs))),
+        }
+    }
+}
+
+/// The dedup settings for each bot, keyed by the account's username. Bots that
+/// aren't in here use [`DedupSettings::default`].
+#[derive(Resource, Default)]
+pub struct DedupPolicies(pub HashMap<String, DedupSettings>);
+impl DedupPolicies {
+    pub fn build_for(&self, account: &str) -> Box<dyn DedupPolicy> {
+        self.0.get(account).cloned().unwrap_or_default().build()
+    }
+}
+
+/// Set which dedup policy each bot uses. Without this, every bot uses
+/// [`CollapseRepeats`].
+pub struct DedupPlugin {
+    /// The dedup settings for each bot, keyed by the account's username.
+    pub policies: HashMap<String, DedupSettings>,
+}
+impl Plugin for DedupPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(DedupPolicies(self.policies.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Instant};
+
+    use azalea::chat::ChatPacket;
+    use azalea_protocol::packets::game::clientbound_system_chat_packet::ClientboundSystemChatPacket;
+
+    use super::*;
+
+    fn message(content: &str) -> RecentMessage {
+        RecentMessage {
+            content: content.to_string(),
+            packet: ChatPacket::System(Arc::new(ClientboundSystemChatPacket {
+                content: serde_json::from_value(serde_json::json!({ "text": content })).unwrap(),
+                overlay: false,
+            })),
+            sent_count: 1,
+            sent_at: Instant::now(),
+        }
+    }
+
+    fn counts(messages: Vec<RecentMessage>) -> Vec<(String, usize)> {
+        messages
+            .into_iter()
+            .map(|m| (m.content, m.sent_count))
+            .collect()
+    }
+
+    #[test]
+    fn collapse_repeats_reports_powers_of_two() {
+        let mut policy = CollapseRepeats::new(5, Duration::from_secs(60));
+        let received = (0..5)
+            .map(|_| counts(policy.receive(message("hi"))))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            received,
+            [
+                vec![("hi".to_string(), 1)],
+                vec![("hi".to_string(), 2)],
+                vec![],
+                vec![("hi".to_string(), 4)],
+                vec![],
+            ]
+        );
+        assert!(policy.flush().is_empty());
+    }
+
+    #[test]
+    fn collapse_repeats_reports_leftover_count_when_forgotten() {
+        let mut policy = CollapseRepeats::new(1, Duration::from_secs(60));
+        for _ in 0..3 {
+            policy.receive(message("hi"));
+        }
+        policy.receive(message("bye"));
+        // "hi" was pushed out, and its count of 3 wasn't reported yet
+        assert_eq!(counts(policy.flush()), [("hi".to_string(), 3)]);
+    }
+
+    #[test]
+    fn no_dedup_bridges_everything() {
+        let mut policy = NoDedup;
+        for _ in 0..3 {
+            assert_eq!(
+                counts(policy.receive(message("hi"))),
+                [("hi".to_string(), 1)]
+            );
+        }
+        assert!(policy.flush().is_empty());
+    }
+
+    #[test]
+    fn time_window_counts_repeats_until_it_ends() {
+        let mut policy = TimeWindow::new(Duration::from_secs(60));
+        assert_eq!(
+            counts(policy.receive(message("hi"))),
+            [("hi".to_string(), 1)]
+        );
+        assert!(policy.receive(message("hi")).is_empty());
+        assert!(policy.receive(message("hi")).is_empty());
+        assert!(policy.flush().is_empty());
+
+        // pretend the window is over
+        policy.window = Duration::ZERO;
+        assert_eq!(counts(policy.flush()), [("hi".to_string(), 3)]);
+        assert!(policy.flush().is_empty());
+    }
+
+    #[test]
+    fn time_window_doesnt_report_messages_sent_once() {
+        let mut policy = TimeWindow::new(Duration::ZERO);
+        policy.receive(message("hi"));
+        assert!(policy.flush().is_empty());
+    }
+
+    #[test]
+    fn per_sender_keeps_senders_apart() {
+        let mut policy = PerSender::new(1, Duration::from_secs(60));
+        policy.receive(message("<alice> hi"));
+        policy.receive(message("<alice> hi"));
+        policy.receive(message("<alice> hi"));
+        // bob's message doesn't push alice's out
+        policy.receive(message("<bob> hello"));
+        assert!(policy.flush().is_empty());
+
+        policy.receive(message("<alice> something else"));
+        assert_eq!(counts(policy.flush()), [("<alice> hi".to_string(), 3)]);
+    }
+
+    #[test]
+    fn settings_default_to_collapse_repeats() {
+        assert_eq!(
+            DedupSettings::default(),
+            DedupSettings::CollapseRepeats {
+                max_recent: 5,
+                wait_secs: 2
+            }
+        );
+    }
+}
@@ -1,37 +1,137 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
 
 use azalea::{
     ecs::{
         app::{App, Plugin},
         event::{EventReader, EventWriter},
+        system::Query,
         AppTickExt,
     },
+    entity::Local,
     prelude::*,
+    GameProfileComponent,
+};
+use bevy_ecs::{
+    query::With,
+    system::{Res, ResMut},
+};
+use log::{info, warn};
+use rusqlite::params;
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_model::{
+    channel::{
+        message::{sticker::MessageSticker, Mention},
+        Attachment, Message,
+    },
+    id::{
+        marker::{ChannelMarker, GuildMarker, RoleMarker, UserMarker},
+        Id,
+    },
+    user::User,
 };
-use bevy_ecs::system::{Res, ResMut};
+use uuid::Uuid;
 
 use crate::{
+    azalea_archive::now_millis,
+    azalea_avoid_chat_kick::ChatPriority,
     azalea_bridge::{
-        BridgeInfoEvent, BridgeInfoKind, BridgePlugin, FromMinecraftEvent, ToMinecraftEvent,
+        fill_template, format_for_repeats, BridgeInfoEvent, BridgeInfoKind, BridgePlugin,
+        FromMinecraftEvent, FromMinecraftPlayerEvent, PlayerActivity, ToMinecraftEvent,
     },
+    azalea_discord_links::AccountLinks,
+    azalea_discord_markdown::{chat_content_to_markdown, component_to_markdown, escape_markdown},
+    azalea_message_kind::{ClassifiedMessage, MessageKind},
     bevy_discord,
+    bevy_storage::Storage,
 };
 
 pub struct DiscordBridgePlugin {
-    pub channel_id: u64,
+    /// The Discord channels that each Minecraft account is bridged to, keyed
+    /// by the account's username.
+    pub channels: HashMap<String, Vec<u64>>,
+    /// The bridged channels where this swarm answers commands. When a channel
+    /// is bridged to more than one server, only one of them should answer.
+    pub command_channels: HashSet<u64>,
+    /// Every Discord channel that's bridged to any server, including the ones
+    /// for other swarms.
+    pub all_channels: HashSet<u64>,
+    /// Whether this swarm tells people when they use a command in a channel
+    /// that isn't bridged to any server. Only one swarm should.
+    pub answers_unbridged: bool,
+    /// How Minecraft messages are shown in Discord. `{content}` is replaced.
+    pub format: String,
+    /// How Discord messages are sent to Minecraft. `{username}` and
+    /// `{content}` are replaced.
+    pub to_minecraft_format: String,
+    /// Long or multi-line messages are split into at most this many
+    /// Minecraft chat messages.
+    pub max_chunks: usize,
+    /// How much sending a message adds to the ratelimit.
+    pub message_cost: usize,
+    /// We can't send messages while the ratelimit is at least this.
+    pub max_ratelimit: usize,
+    /// Channels that should get messages through a webhook, so each message
+    /// shows up with the Minecraft player's name and avatar.
+    pub webhooks: HashMap<u64, DiscordWebhook>,
+    /// The URL of a player's avatar when sending through a webhook. `{uuid}`
+    /// and `{name}` are replaced, and `{uuid}` falls back to the name if we
+    /// don't know the UUID.
+    pub avatar_url_template: Option<String>,
+    /// Edits to messages that were sent to Minecraft less than this long ago
+    /// are sent to Minecraft too. Zero means edits are ignored.
+    pub edit_window: Duration,
+    /// Whether to say in Minecraft when a message that was sent there is
+    /// deleted in Discord. This uses the same time window as edits.
+    pub relay_deletions: bool,
+    /// If this is set, messages waiting to be sent to Discord are kept in the
+    /// database so they're still sent after the swarm reconnects or the bot
+    /// restarts.
+    pub saved_queues: Option<SavedDiscordQueues>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DiscordWebhook {
+    pub id: u64,
+    pub token: String,
 }
 
 impl Plugin for DiscordBridgePlugin {
     fn build(&self, app: &mut App) {
+        let discord_queues = match &self.saved_queues {
+            Some(saved_queues) => saved_queues.load(&self.channels),
+            None => HashMap::new(),
+        };
         app.insert_resource(DiscordBridge {
-            channel_id: self.channel_id,
-            discord_queue: VecDeque::new(),
-            discord_ratelimit: 0,
+            channels: self.channels.clone(),
+            command_channels: self.command_channels.clone(),
+            all_channels: self.all_channels.clone(),
+            answers_unbridged: self.answers_unbridged,
+            discord_queues,
+            saved_queues: self.saved_queues.clone(),
+            format: self.format.clone(),
+            message_cost: self.message_cost,
+            max_ratelimit: self.max_ratelimit,
+            webhooks: self.webhooks.clone(),
+            avatar_url_template: self.avatar_url_template.clone(),
+            recently_relayed: VecDeque::new(),
+            sent_to_discord: VecDeque::new(),
+            minecraft_senders: VecDeque::new(),
+            edit_window: self.edit_window,
+            relay_deletions: self.relay_deletions,
         })
-        .add_plugin(BridgePlugin::<DiscordContext>::default())
+        .add_plugin(BridgePlugin::<DiscordContext>::new(
+            "discord",
+            self.to_minecraft_format.clone(),
+            self.max_chunks,
+        ))
         .add_system(minecraft_to_discord_queue)
+        .add_system(player_activity_to_discord_queue)
         .add_system(discord_to_minecraft)
-        .add_system(handle_bridge_info_events)
+        .add_system(discord_edits_to_minecraft)
+        .add_system(handle_bridge_info_events::<DiscordContext>)
         .add_tick_system(flush_to_discord_queue);
     }
 }
@@ -44,107 +144,1014 @@ pub struct DiscordContext {
 
 #[derive(Resource)]
 pub struct DiscordBridge {
+    /// The Discord channels that each Minecraft account is bridged to, keyed
+    /// by the account's username.
+    pub channels: HashMap<String, Vec<u64>>,
+    /// The bridged channels where this swarm answers commands.
+    pub command_channels: HashSet<u64>,
+    /// Every Discord channel that's bridged to any server.
+    pub all_channels: HashSet<u64>,
+    pub answers_unbridged: bool,
+    /// The messages waiting to be sent to each channel, keyed by channel id.
+    pub discord_queues: HashMap<u64, DiscordQueue>,
+    pub saved_queues: Option<SavedDiscordQueues>,
+    pub format: String,
+    pub message_cost: usize,
+    pub max_ratelimit: usize,
+    pub webhooks: HashMap<u64, DiscordWebhook>,
+    pub avatar_url_template: Option<String>,
+    /// The Discord messages we sent to Minecraft in the last `edit_window`,
+    /// oldest first.
+    pub recently_relayed: VecDeque<RelayedMessage>,
+    /// Messages we sent to Discord without a webhook that we haven't seen come
+    /// back yet, oldest first. We need them to know which Minecraft player a
+    /// Discord reply is replying to.
+    pub sent_to_discord: VecDeque<SentToDiscord>,
+    /// The Minecraft player that sent each of our recent Discord messages, as
+    /// `(message id, player name)`, oldest first.
+    pub minecraft_senders: VecDeque<(u64, String)>,
+    pub edit_window: Duration,
+    pub relay_deletions: bool,
+}
+
+/// A message that we sent to a Discord channel without a webhook.
+pub struct SentToDiscord {
     pub channel_id: u64,
-    pub discord_queue: VecDeque<String>,
-    /// The number of ticks we have to wait until the ratelimit is fully reset. Sending a message adds 20, if it's >= 100 we can't send messages.
-    pub discord_ratelimit: usize,
+    pub content: String,
+    /// The Minecraft player that sent everything in the message, if it was
+    /// just one player.
+    pub sender: Option<String>,
+    pub sent_at: Instant,
+}
+
+/// How long we wait to see our own message in Discord before we forget about
+/// it.
+const SENT_TO_DISCORD_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many of our Discord messages we remember the Minecraft player of.
+const MAX_MINECRAFT_SENDERS: usize = 500;
+/// How much of the message being replied to is quoted in Minecraft.
+const MAX_REPLY_QUOTE_LENGTH: usize = 32;
+
+/// A Discord message that was sent to Minecraft.
+pub struct RelayedMessage {
+    pub message_id: u64,
+    pub channel_id: u64,
+    pub username: String,
+    /// What we sent, without the reply context, so we can tell whether an
+    /// update actually changed it.
+    pub content: String,
+    pub relayed_at: Instant,
+}
+
+impl DiscordBridge {
+    /// The usernames of the Minecraft accounts that are bridged to this
+    /// Discord channel.
+    pub fn accounts_for_channel(&self, channel_id: u64) -> impl Iterator<Item = &String> {
+        self.channels
+            .iter()
+            .filter(move |(_, channel_ids)| channel_ids.contains(&channel_id))
+            .map(|(account, _)| account)
+    }
+
+    /// Whether this swarm should answer a command used in the channel. Every
+    /// swarm gets every command, so this makes sure only one of them answers.
+    pub fn answers_commands_in(&self, channel_id: u64) -> bool {
+        self.command_channels.contains(&channel_id)
+    }
+
+    /// Add a message to the channel's queue, and save it if the queues are
+    /// saved.
+    fn queue(&mut self, channel_id: u64, mut message: QueuedDiscordMessage) {
+        if let Some(saved_queues) = &self.saved_queues {
+            message.saved_id = saved_queues.save(channel_id, &message);
+        }
+        self.discord_queues
+            .entry(channel_id)
+            .or_default()
+            .messages
+            .push_back(message);
+    }
+}
+
+#[derive(Default)]
+pub struct DiscordQueue {
+    pub messages: VecDeque<QueuedDiscordMessage>,
+    /// The number of ticks we have to wait until the ratelimit is fully reset. Sending a message adds `message_cost`, if it's >= `max_ratelimit` we can't send messages.
+    pub ratelimit: usize,
+}
+
+pub struct QueuedDiscordMessage {
+    /// The player that sent the message. This is only set for channels with a
+    /// webhook, and it's used as the webhook's name and avatar.
+    pub author: Option<MinecraftAuthor>,
+    /// The name of the player that sent the message, if it was sent by a
+    /// player. Unlike `author`, this is set for every channel.
+    pub sender: Option<String>,
+    pub content: String,
+    /// The message's id in the database, if it's saved there.
+    pub saved_id: Option<i64>,
+}
+
+/// Where the Discord queues are saved.
+#[derive(Clone)]
+pub struct SavedDiscordQueues {
+    pub storage: Storage,
+    /// What the queues are saved as, usually the server's address, since
+    /// a channel can be bridged to more than one server.
+    pub server: String,
+}
+
+/// Saved messages older than this aren't sent when the bot comes back, since
+/// they'd be out of context by then.
+const MAX_SAVED_MESSAGE_AGE: Duration = Duration::from_secs(10 * 60);
+
+impl SavedDiscordQueues {
+    /// Save a message that was just queued, returning its id in the database.
+    fn save(&self, channel_id: u64, message: &QueuedDiscordMessage) -> Option<i64> {
+        let result = self.storage.with(|connection| {
+            connection.execute(
+                "INSERT INTO discord_queue
+                    (server, channel_id, author_name, author_uuid, sender, content, queued_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    self.server,
+                    channel_id,
+                    message.author.as_ref().map(|author| &author.name),
+                    message
+                        .author
+                        .as_ref()
+                        .and_then(|author| author.uuid)
+                        .map(|uuid| uuid.to_string()),
+                    message.sender,
+                    message.content,
+                    now_millis(),
+                ],
+            )?;
+            Ok(connection.last_insert_rowid())
+        });
+        match result {
+            Ok(saved_id) => Some(saved_id),
+            Err(err) => {
+                warn!("couldn't save a message for Discord: {err}");
+                None
+            }
+        }
+    }
+
+    /// Forget messages that were sent.
+    fn remove(&self, saved_ids: &[i64]) {
+        if saved_ids.is_empty() {
+            return;
+        }
+        let result = self.storage.transaction(|transaction| {
+            for saved_id in saved_ids {
+                transaction.execute("DELETE FROM discord_queue WHERE id = ?1", [saved_id])?;
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            warn!("couldn't remove sent messages for Discord: {err}");
+        }
+    }
+
+    /// The messages that were still queued for the bridged channels when the
+    /// swarm last stopped, keyed by channel id. Messages that are too old are
+    /// removed instead.
+    fn load(&self, channels: &HashMap<String, Vec<u64>>) -> HashMap<u64, DiscordQueue> {
+        let bridged = channels.values().flatten().copied().collect::<HashSet<_>>();
+        let cutoff = now_millis().saturating_sub(MAX_SAVED_MESSAGE_AGE.as_millis() as u64);
+        let result = self.storage.transaction(|transaction| {
+            transaction.execute(
+                "DELETE FROM discord_queue WHERE server = ?1 AND queued_at < ?2",
+                params![self.server, cutoff],
+            )?;
+            let mut statement = transaction.prepare(
+                "SELECT id, channel_id, author_name, author_uuid, sender, content
+                FROM discord_queue
+                WHERE server = ?1
+                ORDER BY id",
+            )?;
+            let rows = statement.query_map([&self.server], |row| {
+                let author_name: Option<String> = row.get(2)?;
+                let author_uuid: Option<String> = row.get(3)?;
+                Ok((
+                    row.get::<_, u64>(1)?,
+                    QueuedDiscordMessage {
+                        author: author_name.map(|name| MinecraftAuthor {
+                            name,
+                            uuid: author_uuid.and_then(|uuid| Uuid::parse_str(&uuid).ok()),
+                        }),
+                        sender: row.get(4)?,
+                        content: row.get(5)?,
+                        saved_id: Some(row.get(0)?),
+                    },
+                ))
+            })?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+        });
+        let messages = match result {
+            Ok(messages) => messages,
+            Err(err) => {
+                warn!("couldn't load the queued messages for Discord: {err}");
+                return HashMap::new();
+            }
+        };
+
+        let mut queues = HashMap::<u64, DiscordQueue>::new();
+        for (channel_id, message) in messages {
+            // they're left to expire if the channel isn't bridged anymore
+            if bridged.contains(&channel_id) {
+                queues
+                    .entry(channel_id)
+                    .or_default()
+                    .messages
+                    .push_back(message);
+            }
+        }
+        let count = queues
+            .values()
+            .map(|queue| queue.messages.len())
+            .sum::<usize>();
+        if count > 0 {
+            info!(
+                "{}: sending {count} messages to Discord that were queued before disconnecting",
+                self.server
+            );
+        }
+        queues
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct MinecraftAuthor {
+    pub name: String,
+    pub uuid: Option<Uuid>,
 }
 
 fn minecraft_to_discord_queue(
     mut discord_bridge: ResMut<DiscordBridge>,
     mut events: EventReader<FromMinecraftEvent>,
+    query: Query<&GameProfileComponent, With<Local>>,
+) {
+    let discord_bridge = &mut *discord_bridge;
+    for event in events.iter() {
+        let Ok(game_profile) = query.get(event.entity) else {
+            continue;
+        };
+        let Some(channel_ids) = discord_bridge.channels.get(&game_profile.name).cloned() else {
+            continue;
+        };
+        // joins and leaves are sent by player_activity_to_discord_queue, so
+        // the vanilla messages for them would be duplicates
+        if matches!(
+            event.classified.kind,
+            MessageKind::Join | MessageKind::Leave
+        ) {
+            continue;
+        }
+
+        // only chat is sent as the player, everything else is from the server
+        let webhook_author = match &event.classified {
+            ClassifiedMessage {
+                kind: MessageKind::Chat,
+                sender: Some(name),
+                ..
+            } => Some(MinecraftAuthor {
+                name: name.clone(),
+                uuid: event.packet.uuid(),
+            }),
+            _ => None,
+        };
+
+        for &channel_id in &channel_ids {
+            let (author, content) = match &webhook_author {
+                // the webhook shows who sent it, so we only need the content
+                Some(author) if discord_bridge.webhooks.contains_key(&channel_id) => {
+                    let content = chat_content_to_markdown(&event.packet.message(), &author.name)
+                        .unwrap_or_else(|| {
+                            escape_markdown(&event.packet.split_sender_and_content().1)
+                        });
+                    (
+                        Some(author.clone()),
+                        format_for_repeats(&content, event.sent_count),
+                    )
+                }
+                _ => (
+                    None,
+                    format_for_repeats(
+                        &component_to_markdown(&event.packet.message()),
+                        event.sent_count,
+                    ),
+                ),
+            };
+            let content = fill_template(&discord_bridge.format, &[("content", &content)]);
+            discord_bridge.queue(
+                channel_id,
+                QueuedDiscordMessage {
+                    author,
+                    sender: webhook_author.as_ref().map(|author| author.name.clone()),
+                    content,
+                    saved_id: None,
+                },
+            );
+        }
+    }
+}
+
+fn player_activity_to_discord_queue(
+    mut discord_bridge: ResMut<DiscordBridge>,
+    mut events: EventReader<FromMinecraftPlayerEvent>,
+    query: Query<&GameProfileComponent, With<Local>>,
 ) {
+    let discord_bridge = &mut *discord_bridge;
     for event in events.iter() {
-        let content = event
-            .content
-            .to_string()
-            .replace('*', "\\*")
-            .replace('_', "\\_");
+        let Ok(game_profile) = query.get(event.entity) else {
+            continue;
+        };
+        let Some(channel_ids) = discord_bridge.channels.get(&game_profile.name).cloned() else {
+            continue;
+        };
+
+        let name = escape_markdown(&event.player.name);
+        let content = match event.kind {
+            PlayerActivity::Joined => format!("**{name}** joined the game"),
+            PlayerActivity::Left => format!("**{name}** left the game"),
+        };
 
-        discord_bridge.discord_queue.push_back(content);
+        for &channel_id in &channel_ids {
+            discord_bridge.queue(
+                channel_id,
+                QueuedDiscordMessage {
+                    author: None,
+                    sender: None,
+                    content: content.clone(),
+                    saved_id: None,
+                },
+            );
+        }
     }
 }
 
 fn flush_to_discord_queue(
     mut discord_bridge: ResMut<DiscordBridge>,
     mut creating_message_events: EventWriter<bevy_discord::send::CreateMessage>,
+    mut execute_webhook_events: EventWriter<bevy_discord::send::ExecuteWebhook>,
 ) {
-    if discord_bridge.discord_ratelimit > 0 {
-        discord_bridge.discord_ratelimit -= 1;
-    }
-    if discord_bridge.discord_ratelimit >= 100 {
-        // ratelimited!
-        return;
-    }
-    let mut sending_messages = Vec::new();
-    while let Some(content) = discord_bridge.discord_queue.pop_front() {
-        discord_bridge.discord_ratelimit += 20;
-        // 1000 instead of 2000 just to maybe avoid possible exploits
-        if sending_messages.join("\n").len() + 1 + content.len() > 1000 {
-            break;
+    let discord_bridge = &mut *discord_bridge;
+    let message_cost = discord_bridge.message_cost;
+    let max_ratelimit = discord_bridge.max_ratelimit;
+    for (&channel_id, queue) in discord_bridge.discord_queues.iter_mut() {
+        if queue.ratelimit > 0 {
+            queue.ratelimit -= 1;
+        }
+        if queue.ratelimit >= max_ratelimit {
+            // ratelimited!
+            continue;
+        }
+        let Some(author) = queue.messages.front().map(|m| m.author.clone()) else {
+            continue;
+        };
+        // messages are sent together as long as they have the same author
+        let mut sending_messages: Vec<String> = Vec::new();
+        let mut senders: Vec<Option<String>> = Vec::new();
+        let mut saved_ids: Vec<i64> = Vec::new();
+        while let Some(message) = queue.messages.front() {
+            // 1000 instead of 2000 just to maybe avoid possible exploits
+            if message.author != author
+                || (!sending_messages.is_empty()
+                    && sending_messages.join("\n").len() + 1 + message.content.len() > 1000)
+            {
+                break;
+            }
+            let message = queue.messages.pop_front().unwrap();
+            saved_ids.extend(message.saved_id);
+            sending_messages.push(message.content);
+            if !senders.contains(&message.sender) {
+                senders.push(message.sender);
+            }
+        }
+        queue.ratelimit += message_cost;
+        if let Some(saved_queues) = &discord_bridge.saved_queues {
+            saved_queues.remove(&saved_ids);
         }
-        sending_messages.push(content);
-    }
-    if !sending_messages.is_empty() {
         let content = sending_messages.join("\n");
-        creating_message_events.send(bevy_discord::send::CreateMessage {
-            channel_id: discord_bridge.channel_id,
-            content,
-        });
+
+        if let Some(webhook) = discord_bridge.webhooks.get(&channel_id) {
+            execute_webhook_events.send(webhook_message(
+                webhook,
+                author,
+                discord_bridge.avatar_url_template.as_deref(),
+                content,
+            ));
+        } else {
+            // a batch from more than one player can't be replied to as any of
+            // them
+            let sender = match senders.as_slice() {
+                [Some(sender)] => Some(sender.clone()),
+                _ => None,
+            };
+            if sender.is_some() {
+                discord_bridge.sent_to_discord.push_back(SentToDiscord {
+                    channel_id,
+                    content: content.clone(),
+                    sender,
+                    sent_at: Instant::now(),
+                });
+            }
+            creating_message_events.send(bevy_discord::send::CreateMessage {
+                channel_id,
+                content,
+            });
+        }
+    }
+}
+
+/// Send a message through the webhook as the Minecraft player, or as the
+/// webhook itself if there's no author.
+fn webhook_message(
+    webhook: &DiscordWebhook,
+    author: Option<MinecraftAuthor>,
+    avatar_url_template: Option<&str>,
+    content: String,
+) -> bevy_discord::send::ExecuteWebhook {
+    let avatar_url = match (&author, avatar_url_template) {
+        (Some(author), Some(template)) => {
+            let uuid = author
+                .uuid
+                .map(|uuid| uuid.to_string())
+                .unwrap_or_else(|| author.name.clone());
+            Some(fill_template(
+                template,
+                &[("uuid", &uuid), ("name", &author.name)],
+            ))
+        }
+        _ => None,
+    };
+    bevy_discord::send::ExecuteWebhook {
+        webhook_id: webhook.id,
+        token: webhook.token.clone(),
+        username: author.map(|author| author.name),
+        avatar_url,
+        content,
     }
 }
 
 fn discord_to_minecraft(
-    discord_bridge: Res<DiscordBridge>,
+    mut discord_bridge: ResMut<DiscordBridge>,
     mut events: EventReader<bevy_discord::recv::MessageCreate>,
     mut to_minecraft_events: EventWriter<ToMinecraftEvent<DiscordContext>>,
+    discord: Res<bevy_discord::Discord>,
+    links: Option<Res<AccountLinks>>,
 ) {
+    let links = links.as_deref();
+    let discord_bridge = &mut *discord_bridge;
+    discord_bridge
+        .sent_to_discord
+        .retain(|sent| sent.sent_at.elapsed() < SENT_TO_DISCORD_TIMEOUT);
+
     for event in events.iter() {
-        if event.author.bot {
+        // this also ignores messages from our webhooks
+        if event.author.bot || event.webhook_id.is_some() {
+            remember_minecraft_sender(discord_bridge, &event.0);
+            continue;
+        }
+
+        let content = discord_message_to_minecraft(&(&event.0).into(), &discord.cache, links);
+        if content.is_empty() {
+            // probably just an embed
+            continue;
+        }
+        let relayed_content = match &event.referenced_message {
+            Some(referenced) => format!(
+                "{} {content}",
+                reply_context(discord_bridge, referenced, &discord.cache, links)
+            ),
+            None => content.clone(),
+        };
+
+        let username = minecraft_username(&event.author, links);
+        let mut relayed = false;
+        for account in discord_bridge.accounts_for_channel(event.channel_id.get()) {
+            relayed = true;
+            to_minecraft_events.send(ToMinecraftEvent {
+                account: account.clone(),
+                content: relayed_content.clone(),
+                username: username.clone(),
+                priority: ChatPriority::Normal,
+                context: DiscordContext {
+                    channel_id: event.channel_id.get(),
+                    message_id: event.id.get(),
+                },
+            });
+        }
+        if relayed && !discord_bridge.edit_window.is_zero() {
+            discord_bridge.recently_relayed.push_back(RelayedMessage {
+                message_id: event.id.get(),
+                channel_id: event.channel_id.get(),
+                username,
+                content,
+                relayed_at: Instant::now(),
+            });
+        }
+    }
+}
+
+/// The name a Discord user is shown as in Minecraft. Users that linked their
+/// Minecraft account are shown with their Minecraft name.
+pub fn minecraft_username(author: &User, links: Option<&AccountLinks>) -> String {
+    match links.and_then(|links| links.by_discord_id(author.id.get())) {
+        Some(link) => link.name,
+        None => format!("{}#{:0>4}", author.name, author.discriminator),
+    }
+}
+
+/// If this is one of our own messages, remember which Minecraft player sent it
+/// so replies to it can be shown as replies to them.
+fn remember_minecraft_sender(discord_bridge: &mut DiscordBridge, message: &Message) {
+    let channel_id = message.channel_id.get();
+    let sender = if let Some(webhook_id) = message.webhook_id {
+        // webhook messages already have the player's name on them
+        if !discord_bridge
+            .webhooks
+            .values()
+            .any(|webhook| webhook.id == webhook_id.get())
+        {
             return;
         }
-        if event.channel_id.get() != discord_bridge.channel_id {
+        message.author.name.clone()
+    } else {
+        let Some(index) = discord_bridge.sent_to_discord.iter().position(|sent| {
+            sent.channel_id == channel_id && sent.content.trim() == message.content.trim()
+        }) else {
             return;
+        };
+        let Some(sender) = discord_bridge.sent_to_discord.remove(index).unwrap().sender else {
+            return;
+        };
+        sender
+    };
+
+    discord_bridge
+        .minecraft_senders
+        .push_back((message.id.get(), sender));
+    if discord_bridge.minecraft_senders.len() > MAX_MINECRAFT_SENDERS {
+        discord_bridge.minecraft_senders.pop_front();
+    }
+}
+
+/// Describe the message that a Discord message is replying to, like
+/// `(→ player: "the original message")`. Replies to messages that came from
+/// Minecraft are shown as replies to the player instead of the bot.
+fn reply_context(
+    discord_bridge: &DiscordBridge,
+    referenced: &Message,
+    cache: &InMemoryCache,
+    links: Option<&AccountLinks>,
+) -> String {
+    let name = discord_bridge
+        .minecraft_senders
+        .iter()
+        .find(|(message_id, _)| *message_id == referenced.id.get())
+        .map(|(_, name)| name.clone())
+        .or_else(|| {
+            // we might not have seen the message if it's from before we
+            // started, but webhook messages still have the player's name
+            let webhook_id = referenced.webhook_id?.get();
+            discord_bridge
+                .webhooks
+                .values()
+                .any(|webhook| webhook.id == webhook_id)
+                .then(|| referenced.author.name.clone())
+        })
+        .unwrap_or_else(|| {
+            user_display_name(referenced.author.id, &referenced.into(), cache, links)
+        });
+
+    let quote = unescape_markdown(&discord_message_to_minecraft(
+        &referenced.into(),
+        cache,
+        links,
+    ))
+    .replace('\n', " ");
+    // messages we sent without a webhook already say who sent them
+    let quote = quote
+        .strip_prefix(&format!("<{name}> "))
+        .unwrap_or(&quote)
+        .trim();
+
+    if quote.is_empty() {
+        return format!("(→ {name})");
+    }
+    match quote.char_indices().nth(MAX_REPLY_QUOTE_LENGTH) {
+        Some((end, _)) => format!("(→ {name}: \"{}…\")", quote[..end].trim_end()),
+        None => format!("(→ {name}: \"{quote}\")"),
+    }
+}
+
+/// Remove the backslashes that [`escape_markdown`] adds, since they'd just be
+/// noise in Minecraft.
+fn unescape_markdown(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(escaped) = chars.next() {
+                unescaped.push(escaped);
+            }
+        } else {
+            unescaped.push(c);
         }
+    }
+    unescaped
+}
 
-        to_minecraft_events.send(ToMinecraftEvent {
-            content: event.content.clone(),
-            username: format!("{}#{:0>4}", event.author.name, event.author.discriminator),
-            context: DiscordContext {
-                channel_id: event.channel_id.get(),
-                message_id: event.id.get(),
+/// Send edits and deletions of messages that were recently sent to Minecraft.
+fn discord_edits_to_minecraft(
+    mut discord_bridge: ResMut<DiscordBridge>,
+    mut update_events: EventReader<bevy_discord::recv::MessageUpdate>,
+    mut delete_events: EventReader<bevy_discord::recv::MessageDelete>,
+    mut to_minecraft_events: EventWriter<ToMinecraftEvent<DiscordContext>>,
+    discord: Res<bevy_discord::Discord>,
+    links: Option<Res<AccountLinks>>,
+) {
+    let links = links.as_deref();
+    let discord_bridge = &mut *discord_bridge;
+    let edit_window = discord_bridge.edit_window;
+    while let Some(relayed) = discord_bridge.recently_relayed.front() {
+        if relayed.relayed_at.elapsed() < edit_window {
+            break;
+        }
+        discord_bridge.recently_relayed.pop_front();
+    }
+
+    let mut edited = Vec::new();
+    for event in update_events.iter() {
+        // discord also sends updates when a link unfurls or an embed is added,
+        // which aren't edits, and those that only change the embeds don't have
+        // content
+        let (Some(content), Some(_)) = (&event.content, event.edited_timestamp) else {
+            continue;
+        };
+        let content = discord_message_to_minecraft(
+            &DiscordContent {
+                content,
+                guild_id: event.guild_id,
+                mentions: event.mentions.as_deref().unwrap_or_default(),
+                attachments: event.attachments.as_deref().unwrap_or_default(),
+                sticker_items: &[],
             },
-        });
+            &discord.cache,
+            links,
+        );
+        let Some(relayed) = discord_bridge
+            .recently_relayed
+            .iter_mut()
+            .find(|relayed| relayed.message_id == event.id.get())
+        else {
+            continue;
+        };
+        if content.is_empty() || content == relayed.content {
+            continue;
+        }
+        relayed.content = content.clone();
+        edited.push((event.id.get(), format!("(edited) {content}")));
+    }
+    if discord_bridge.relay_deletions {
+        for event in delete_events.iter() {
+            edited.push((event.id.get(), "(deleted a message)".to_string()));
+        }
+    }
+
+    for (message_id, content) in edited {
+        let Some(relayed) = discord_bridge
+            .recently_relayed
+            .iter()
+            .find(|relayed| relayed.message_id == message_id)
+        else {
+            continue;
+        };
+        for account in discord_bridge.accounts_for_channel(relayed.channel_id) {
+            to_minecraft_events.send(ToMinecraftEvent {
+                account: account.clone(),
+                content: content.clone(),
+                username: relayed.username.clone(),
+                priority: ChatPriority::Normal,
+                context: DiscordContext {
+                    channel_id: relayed.channel_id,
+                    message_id,
+                },
+            });
+        }
+    }
+}
+
+/// The parts of a Discord message that we show in Minecraft. Edits only come
+/// with some of the message, so this works for both.
+struct DiscordContent<'a> {
+    content: &'a str,
+    guild_id: Option<Id<GuildMarker>>,
+    mentions: &'a [Mention],
+    attachments: &'a [Attachment],
+    sticker_items: &'a [MessageSticker],
+}
+impl<'a> From<&'a Message> for DiscordContent<'a> {
+    fn from(message: &'a Message) -> Self {
+        Self {
+            content: &message.content,
+            guild_id: message.guild_id,
+            mentions: &message.mentions,
+            attachments: &message.attachments,
+            sticker_items: &message.sticker_items,
+        }
+    }
+}
+
+/// Turn a Discord message into text that makes sense in Minecraft. Mentions and
+/// custom emoji are replaced with their names, and attachments and stickers
+/// are summarized like `[image: name.png]`.
+fn discord_message_to_minecraft(
+    message: &DiscordContent,
+    cache: &InMemoryCache,
+    links: Option<&AccountLinks>,
+) -> String {
+    let mut parts = Vec::new();
+    let content = resolve_mentions(message, cache, links);
+    if !content.trim().is_empty() {
+        parts.push(content);
+    }
+    for attachment in message.attachments {
+        let kind = match attachment.content_type.as_deref() {
+            Some(content_type) if content_type.starts_with("image/") => "image",
+            Some(content_type) if content_type.starts_with("video/") => "video",
+            Some(content_type) if content_type.starts_with("audio/") => "audio",
+            _ => "file",
+        };
+        parts.push(format!("[{kind}: {}]", attachment.filename));
+    }
+    for sticker in message.sticker_items {
+        parts.push(format!("[sticker: {}]", sticker.name));
+    }
+    parts.join(" ")
+}
+
+fn resolve_mentions(
+    message: &DiscordContent,
+    cache: &InMemoryCache,
+    links: Option<&AccountLinks>,
+) -> String {
+    let mut resolved = String::with_capacity(message.content.len());
+    let mut rest = message.content;
+    while let Some(start) = rest.find('<') {
+        resolved.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let resolved_tag = rest
+            .find('>')
+            .and_then(|end| Some((resolve_tag(&rest[1..end], message, cache, links)?, end)));
+        match resolved_tag {
+            Some((name, end)) => {
+                resolved.push_str(&name);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                // not something we know about, so leave the < alone
+                resolved.push('<');
+                rest = &rest[1..];
+            }
+        }
+    }
+    resolved.push_str(rest);
+    resolved
+}
+
+/// Resolve the inside of a `<...>` tag from a Discord message, like `@123` or
+/// `:pog:123`.
+fn resolve_tag(
+    tag: &str,
+    message: &DiscordContent,
+    cache: &InMemoryCache,
+    links: Option<&AccountLinks>,
+) -> Option<String> {
+    if let Some(id) = tag.strip_prefix("@&") {
+        let id = Id::<RoleMarker>::new_checked(id.parse().ok()?)?;
+        let name = cache
+            .role(id)
+            .map(|role| role.resource().name.clone())
+            .unwrap_or_else(|| "unknown-role".to_string());
+        return Some(format!("@{name}"));
+    }
+    if let Some(id) = tag.strip_prefix('@') {
+        // <@!id> is an older way of mentioning someone by their nickname
+        let id = id.strip_prefix('!').unwrap_or(id);
+        let id = Id::<UserMarker>::new_checked(id.parse().ok()?)?;
+        return Some(format!("@{}", user_display_name(id, message, cache, links)));
+    }
+    if let Some(id) = tag.strip_prefix('#') {
+        let id = Id::<ChannelMarker>::new_checked(id.parse().ok()?)?;
+        let name = cache
+            .channel(id)
+            .and_then(|channel| channel.name.clone())
+            .unwrap_or_else(|| "unknown-channel".to_string());
+        return Some(format!("#{name}"));
+    }
+    // custom emoji look like <:name:id>, or <a:name:id> if they're animated
+    let emoji = tag.strip_prefix('a').unwrap_or(tag);
+    let (name, id) = emoji.strip_prefix(':')?.split_once(':')?;
+    if name.is_empty() || id.parse::<u64>().is_err() {
+        return None;
+    }
+    Some(format!(":{name}:"))
+}
+
+/// The name that a user shows up as in the message's server, preferring their
+/// Minecraft name if they're linked and then their nickname.
+fn user_display_name(
+    id: Id<UserMarker>,
+    message: &DiscordContent,
+    cache: &InMemoryCache,
+    links: Option<&AccountLinks>,
+) -> String {
+    if let Some(link) = links.and_then(|links| links.by_discord_id(id.get())) {
+        return link.name;
     }
+    let mention = message.mentions.iter().find(|mention| mention.id == id);
+    mention
+        .and_then(|mention| mention.member.as_ref()?.nick.clone())
+        .or_else(|| {
+            let guild_id = message.guild_id?;
+            cache.member(guild_id, id)?.nick().map(str::to_owned)
+        })
+        .or_else(|| mention.map(|mention| mention.name.clone()))
+        .or_else(|| cache.user(id).map(|user| user.name.clone()))
+        .unwrap_or_else(|| "unknown-user".to_string())
 }
 
-fn handle_bridge_info_events(
-    mut events: EventReader<BridgeInfoEvent<DiscordContext>>,
+/// React to the Discord message to show what happened to it. This works for any
+/// bridge that's sending Discord messages to Minecraft.
+pub fn handle_bridge_info_events<T: Clone + Sync + Send + Into<DiscordContext> + 'static>(
+    mut events: EventReader<BridgeInfoEvent<T>>,
     mut react_events: EventWriter<bevy_discord::send::CreateReaction>,
+    mut delete_reaction_events: EventWriter<bevy_discord::send::DeleteReaction>,
+    mut create_message_events: EventWriter<bevy_discord::send::CreateMessage>,
 ) {
     for event in events.iter() {
-        match event.kind {
-            BridgeInfoKind::Ack => {
-                react_events.send(bevy_discord::send::CreateReaction {
-                    channel_id: event.context.channel_id,
-                    message_id: event.context.message_id,
-                    emoji: '👍',
-                });
-            }
-            BridgeInfoKind::NotInServer => {
-                react_events.send(bevy_discord::send::CreateReaction {
-                    channel_id: event.context.channel_id,
-                    message_id: event.context.message_id,
-                    emoji: '👎',
+        let context: DiscordContext = event.context.clone().into();
+        let emoji = match event.kind {
+            BridgeInfoKind::Queued => '⏳',
+            BridgeInfoKind::Delivered => '👍',
+            BridgeInfoKind::TimedOut => '⌛',
+            BridgeInfoKind::Sanitized => '🧹',
+            BridgeInfoKind::Dropped => '💨',
+            BridgeInfoKind::NotInServer => '👎',
+            BridgeInfoKind::IllegalMessage => '🚫',
+            BridgeInfoKind::TooLong { chunks, max_chunks } => {
+                create_message_events.send(bevy_discord::send::CreateMessage {
+                    channel_id: context.channel_id,
+                    content: format!(
+                        "That message is too long for Minecraft, it would take {chunks} messages \
+                         but the limit is {max_chunks}."
+                    ),
                 });
+                '📏'
             }
-            BridgeInfoKind::IllegalMessage => {
-                react_events.send(bevy_discord::send::CreateReaction {
-                    channel_id: event.context.channel_id,
-                    message_id: event.context.message_id,
-                    emoji: '🚫',
-                });
+        };
+        // the hourglass is only there until we know what happened to the
+        // message
+        if matches!(
+            event.kind,
+            BridgeInfoKind::Delivered | BridgeInfoKind::TimedOut | BridgeInfoKind::Dropped
+        ) {
+            delete_reaction_events.send(bevy_discord::send::DeleteReaction {
+                channel_id: context.channel_id,
+                message_id: context.message_id,
+                emoji: '⏳',
+            });
+        }
+        react_events.send(bevy_discord::send::CreateReaction {
+            channel_id: context.channel_id,
+            message_id: context.message_id,
+            emoji,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use twilight_http::Client as HttpClient;
+
+    use super::*;
+
+    /// Accept one request, answer it with 204 No Content, and return its head
+    /// and body.
+    async fn receive_one_request(listener: TcpListener) -> (String, String) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let read = stream.read(&mut buf).await.unwrap();
+            assert!(
+                read > 0,
+                "the connection closed before the whole request was sent"
+            );
+            request.extend_from_slice(&buf[..read]);
+            let text = String::from_utf8_lossy(&request);
+            let Some(head_end) = text.find("\r\n\r\n") else {
+                continue;
+            };
+            let head = &text[..head_end];
+            let content_length = head
+                .lines()
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("content-length")
+                        .then(|| value.trim().parse::<usize>().unwrap())
+                })
+                .unwrap_or(0);
+            let body = &text[head_end + 4..];
+            if body.len() >= content_length {
+                stream
+                    .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                    .await
+                    .unwrap();
+                return (head.to_string(), body.to_string());
             }
         }
     }
+
+    fn webhook() -> DiscordWebhook {
+        DiscordWebhook {
+            id: 123,
+            token: "webhook-token".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn webhook_messages_are_sent_as_the_player() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http = HttpClient::builder()
+            .token("token".to_string())
+            .proxy(listener.local_addr().unwrap().to_string(), true)
+            .build();
+        let message = webhook_message(
+            &webhook(),
+            Some(MinecraftAuthor {
+                name: "Notch".to_string(),
+                uuid: Some(Uuid::parse_str("069a79f4-44e9-4726-a5be-fca90e38aaf5").unwrap()),
+            }),
+            Some("https://mc-heads.net/avatar/{uuid}?name={name}"),
+            "hello".to_string(),
+        );
+
+        let ((head, body), response) = tokio::join!(
+            receive_one_request(listener),
+            bevy_discord::execute_webhook(Arc::new(http), message)
+        );
+        assert!(matches!(response, Ok(Ok(_))), "{response:?}");
+        assert!(
+            head.starts_with("POST /api/v10/webhooks/123/webhook-token"),
+            "{head}"
+        );
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["username"], "Notch");
+        assert_eq!(
+            body["avatar_url"],
+            "https://mc-heads.net/avatar/069a79f4-44e9-4726-a5be-fca90e38aaf5?name=Notch"
+        );
+        assert_eq!(body["content"], "hello");
+    }
+
+    #[test]
+    fn avatar_url_falls_back_to_the_name() {
+        let message = webhook_message(
+            &webhook(),
+            Some(MinecraftAuthor {
+                name: "Notch".to_string(),
+                uuid: None,
+            }),
+            Some("https://mc-heads.net/avatar/{uuid}"),
+            "hello".to_string(),
+        );
+        assert_eq!(message.username.as_deref(), Some("Notch"));
+        assert_eq!(
+            message.avatar_url.as_deref(),
+            Some("https://mc-heads.net/avatar/Notch")
+        );
+    }
+
+    #[test]
+    fn messages_without_an_author_use_the_webhook_itself() {
+        let message = webhook_message(
+            &webhook(),
+            None,
+            Some("https://mc-heads.net/avatar/{uuid}"),
+            "Notch joined the game".to_string(),
+        );
+        assert_eq!(message.username, None);
+        assert_eq!(message.avatar_url, None);
+    }
 }
@@ -0,0 +1,545 @@
+//! Discord slash commands for interacting with the bots.
+
+use std::collections::HashMap;
+
+use azalea::{
+    ecs::{
+        app::{App, Plugin},
+        event::{EventReader, EventWriter},
+        system::Query,
+    },
+    entity::Local,
+    GameProfileComponent,
+};
+use bevy_ecs::{
+    component::Component,
+    entity::Entity,
+    query::With,
+    system::{Commands, Res, ResMut, Resource},
+};
+use bevy_tasks::{IoTaskPool, Task};
+use futures_lite::future;
+use log::error;
+use twilight_model::application::{
+    command::CommandType,
+    interaction::{
+        application_command::{CommandDataOption, CommandOptionValue},
+        InteractionData,
+    },
+};
+use twilight_util::builder::command::{CommandBuilder, StringBuilder};
+
+use crate::{
+    azalea_archive::{format_utc, now_millis, parse_duration, ChatArchive, SearchQuery},
+    azalea_avoid_chat_kick::ChatPriority,
+    azalea_bridge::{BridgeInfoEvent, BridgeInfoKind, BridgePlugin, ToMinecraftEvent},
+    azalea_discord_bridge::{minecraft_username, DiscordBridge},
+    azalea_discord_links::AccountLinks,
+    azalea_discord_markdown::escape_markdown,
+    azalea_player_list::OnlinePlayers,
+    bevy_discord::{
+        self,
+        send::{FileAttachment, InteractionResponseKind, UpdateInteractionResponse},
+        ApplicationCommands,
+    },
+    bevy_storage::Storage,
+};
+
+/// Adds the `/status`, `/list`, `/say` and `/search` commands. This should be added after
+/// [`DiscordBridgePlugin`](crate::azalea_discord_bridge::DiscordBridgePlugin),
+/// since commands only work in bridged channels.
+pub struct DiscordCommandsPlugin {
+    /// How messages from `/say` are sent to Minecraft. `{username}` and
+    /// `{content}` are replaced.
+    pub to_minecraft_format: String,
+    /// Long or multi-line messages are split into at most this many
+    /// Minecraft chat messages.
+    pub max_chunks: usize,
+}
+
+impl Plugin for DiscordCommandsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ApplicationCommands>();
+        app.world.resource_mut::<ApplicationCommands>().0.extend([
+            CommandBuilder::new(
+                "status",
+                "Show whether the bots are on the server",
+                CommandType::ChatInput,
+            )
+            .build(),
+            CommandBuilder::new(
+                "list",
+                "List the players on the server",
+                CommandType::ChatInput,
+            )
+            .build(),
+            CommandBuilder::new("say", "Send a message to Minecraft", CommandType::ChatInput)
+                .option(StringBuilder::new("message", "What to say").required(true))
+                .build(),
+            CommandBuilder::new(
+                "search",
+                "Search the chat history of the server",
+                CommandType::ChatInput,
+            )
+            .option(StringBuilder::new(
+                "player",
+                "Only messages from this player or user",
+            ))
+            .option(StringBuilder::new(
+                "text",
+                "Only messages that contain this",
+            ))
+            .option(StringBuilder::new(
+                "since",
+                "Only messages from less than this long ago, like 2h or 7d",
+            ))
+            .option(StringBuilder::new(
+                "until",
+                "Only messages from more than this long ago, like 30m or 1d",
+            ))
+            .build(),
+        ]);
+
+        app.add_plugin(BridgePlugin::<DiscordCommandContext>::new(
+            "discord",
+            self.to_minecraft_format.clone(),
+            self.max_chunks,
+        ))
+        .init_resource::<PendingSays>()
+        .add_system(handle_interactions)
+        .add_system(handle_unbridged_interactions)
+        .add_system(handle_search_tasks)
+        .add_system(handle_bridge_info_events);
+    }
+}
+
+/// The interaction that a message sent with `/say` came from.
+#[derive(Clone)]
+pub struct DiscordCommandContext {
+    pub application_id: u64,
+    pub token: String,
+    /// The bot that's sending the message, since `/say` sends it with every
+    /// bot in the channel.
+    pub account: String,
+}
+
+/// The `/say` commands we haven't responded to yet, keyed by the interaction
+/// token. We only respond once we know what happened with every bot, since
+/// they'd overwrite each other's responses otherwise.
+#[derive(Resource, Default)]
+struct PendingSays(HashMap<String, PendingSay>);
+
+struct PendingSay {
+    /// How many bots we're still waiting to hear from.
+    remaining: usize,
+    /// What happened with each bot that we've heard from.
+    results: Vec<(String, String)>,
+    sanitized: bool,
+}
+
+/// A `/search` that's running on another thread, so a slow search doesn't hold
+/// up the bots.
+#[derive(Component)]
+struct SearchTask {
+    application_id: u64,
+    token: String,
+    task: Task<(String, Option<FileAttachment>)>,
+}
+
+fn handle_interactions(
+    mut commands: Commands,
+    discord_bridge: Res<DiscordBridge>,
+    mut events: EventReader<bevy_discord::recv::InteractionCreate>,
+    mut respond_events: EventWriter<bevy_discord::send::CreateInteractionResponse>,
+    mut to_minecraft_events: EventWriter<ToMinecraftEvent<DiscordCommandContext>>,
+    mut pending_says: ResMut<PendingSays>,
+    online_players: Res<OnlinePlayers>,
+    links: Option<Res<AccountLinks>>,
+    archive: Option<Res<ChatArchive>>,
+    storage: Option<Res<Storage>>,
+    query: Query<(Entity, &GameProfileComponent), With<Local>>,
+) {
+    for interaction in events.iter() {
+        let Some(InteractionData::ApplicationCommand(data)) = &interaction.data else {
+            continue;
+        };
+        let Some(channel_id) = interaction.channel_id else {
+            continue;
+        };
+        if !discord_bridge.answers_commands_in(channel_id.get()) {
+            // this channel isn't bridged to any of our bots, or another swarm
+            // is answering it
+            continue;
+        }
+        let accounts = discord_bridge
+            .accounts_for_channel(channel_id.get())
+            .collect::<Vec<_>>();
+
+        let mut respond = |kind| {
+            respond_events.send(bevy_discord::send::CreateInteractionResponse {
+                application_id: interaction.application_id.get(),
+                interaction_id: interaction.id.get(),
+                token: interaction.token.clone(),
+                kind,
+            })
+        };
+
+        match data.name.as_str() {
+            "status" => {
+                let lines = accounts
+                    .iter()
+                    .map(|account| {
+                        let online = query.iter().any(|(_, profile)| profile.name == **account);
+                        if online {
+                            format!("{account} is online")
+                        } else {
+                            format!("{account} is offline")
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                respond(InteractionResponseKind::Message(lines.join("\n")));
+            }
+            "list" => {
+                let lines = accounts
+                    .iter()
+                    .map(|account| {
+                        let player_list = query
+                            .iter()
+                            .find(|(_, profile)| profile.name == **account)
+                            .and_then(|(entity, _)| online_players.get(&entity));
+                        let Some(player_list) = player_list else {
+                            return format!("{account} is offline");
+                        };
+                        let names = player_list
+                            .sorted()
+                            .iter()
+                            .map(|player| player.name.as_str())
+                            .collect::<Vec<_>>();
+                        format!("{} players online: {}", names.len(), names.join(", "))
+                    })
+                    .collect::<Vec<_>>();
+                respond(InteractionResponseKind::Message(escape_markdown(
+                    &lines.join("\n"),
+                )));
+            }
+            "say" => {
+                let Some(CommandOptionValue::String(message)) = data
+                    .options
+                    .iter()
+                    .find(|option| option.name == "message")
+                    .map(|option| &option.value)
+                else {
+                    continue;
+                };
+                let Some(author) = interaction.author() else {
+                    continue;
+                };
+
+                // we respond once we know whether it was sent
+                respond(InteractionResponseKind::Defer);
+                pending_says.0.insert(
+                    interaction.token.clone(),
+                    PendingSay {
+                        remaining: accounts.len(),
+                        results: Vec::new(),
+                        sanitized: false,
+                    },
+                );
+                for account in accounts {
+                    to_minecraft_events.send(ToMinecraftEvent {
+                        account: account.clone(),
+                        content: message.clone(),
+                        username: minecraft_username(author, links.as_deref()),
+                        priority: ChatPriority::High,
+                        context: DiscordCommandContext {
+                            application_id: interaction.application_id.get(),
+                            token: interaction.token.clone(),
+                            account: account.clone(),
+                        },
+                    });
+                }
+            }
+            "search" => {
+                let (Some(archive), Some(storage)) = (&archive, &storage) else {
+                    respond(InteractionResponseKind::Message(
+                        "The chat archive is turned off.".to_string(),
+                    ));
+                    continue;
+                };
+                // we respond once the search is done
+                respond(InteractionResponseKind::Defer);
+                let archive = (**archive).clone();
+                let storage = (**storage).clone();
+                let options = data.options.clone();
+                let task = IoTaskPool::get()
+                    .spawn(async move { search_archive(&archive, &storage, &options) });
+                commands.spawn(SearchTask {
+                    application_id: interaction.application_id.get(),
+                    token: interaction.token.clone(),
+                    task,
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Every command only works in bridged channels, so say so instead of leaving
+/// the interaction to fail.
+fn handle_unbridged_interactions(
+    discord_bridge: Res<DiscordBridge>,
+    mut events: EventReader<bevy_discord::recv::InteractionCreate>,
+    mut respond_events: EventWriter<bevy_discord::send::CreateInteractionResponse>,
+) {
+    if !discord_bridge.answers_unbridged {
+        return;
+    }
+    for interaction in events.iter() {
+        let Some(InteractionData::ApplicationCommand(_)) = &interaction.data else {
+            continue;
+        };
+        // DMs aren't bridged either
+        if let Some(channel_id) = interaction.channel_id {
+            if discord_bridge.all_channels.contains(&channel_id.get()) {
+                continue;
+            }
+        }
+        respond_events.send(bevy_discord::send::CreateInteractionResponse {
+            application_id: interaction.application_id.get(),
+            interaction_id: interaction.id.get(),
+            token: interaction.token.clone(),
+            kind: InteractionResponseKind::EphemeralMessage(
+                "This channel isn't bridged to a server.".to_string(),
+            ),
+        });
+    }
+}
+
+/// The most messages `/search` returns.
+const MAX_SEARCH_RESULTS: usize = 1000;
+/// Results that are longer than this are sent as a file instead.
+const MAX_INLINE_SEARCH_LENGTH: usize = 1900;
+
+fn search_archive(
+    archive: &ChatArchive,
+    storage: &Storage,
+    options: &[CommandDataOption],
+) -> (String, Option<FileAttachment>) {
+    let option = |name: &str| {
+        options
+            .iter()
+            .find(|option| option.name == name)
+            .and_then(|option| match &option.value {
+                CommandOptionValue::String(value) => Some(value.as_str()),
+                _ => None,
+            })
+    };
+    let now = now_millis();
+    let mut query = SearchQuery {
+        player: option("player").map(str::to_owned),
+        text: option("text").map(str::to_owned),
+        // one more than we show, so we know whether there were more
+        limit: MAX_SEARCH_RESULTS + 1,
+        ..Default::default()
+    };
+    for (name, time) in [("since", &mut query.since), ("until", &mut query.until)] {
+        let Some(value) = option(name) else {
+            continue;
+        };
+        let Some(ago) = parse_duration(value) else {
+            return (format!("`{name}` should look like 30m, 2h or 7d."), None);
+        };
+        *time = Some(now.saturating_sub(ago.as_millis() as u64));
+    }
+
+    let mut messages = match archive.search(storage, &query) {
+        Ok(messages) => messages,
+        Err(err) => {
+            error!("couldn't search the archive: {err}");
+            return ("Couldn't search the archive.".to_string(), None);
+        }
+    };
+    if messages.is_empty() {
+        return ("No messages found.".to_string(), None);
+    }
+    // they're oldest first, so the extra one is the oldest
+    let found_more = messages.len() > MAX_SEARCH_RESULTS;
+    if found_more {
+        messages.drain(..messages.len() - MAX_SEARCH_RESULTS);
+    }
+
+    // discord shows these timestamps in everyone's own timezone
+    let inline = messages
+        .iter()
+        .map(|message| {
+            format!(
+                "<t:{}:f> {}",
+                message.timestamp / 1000,
+                escape_markdown(&message.to_line())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    if inline.len() <= MAX_INLINE_SEARCH_LENGTH {
+        return (inline, None);
+    }
+
+    let file = messages
+        .iter()
+        .map(|message| {
+            format!(
+                "[{} UTC] {}",
+                format_utc(message.timestamp),
+                message.to_line()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let content = if found_more {
+        format!("Found more than {MAX_SEARCH_RESULTS} messages, here are the newest ones.")
+    } else {
+        format!("Found {} messages.", messages.len())
+    };
+    (
+        content,
+        Some(FileAttachment {
+            filename: "search.txt".to_string(),
+            data: file.into_bytes(),
+        }),
+    )
+}
+
+fn handle_search_tasks(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut SearchTask)>,
+    mut update_response_events: EventWriter<UpdateInteractionResponse>,
+) {
+    for (entity, mut search) in &mut query {
+        let Some((content, attachment)) = future::block_on(future::poll_once(&mut search.task))
+        else {
+            continue;
+        };
+        commands.entity(entity).despawn();
+        update_response_events.send(UpdateInteractionResponse {
+            application_id: search.application_id,
+            token: search.token.clone(),
+            content,
+            attachment,
+        });
+    }
+}
+
+fn handle_bridge_info_events(
+    mut events: EventReader<BridgeInfoEvent<DiscordCommandContext>>,
+    mut pending_says: ResMut<PendingSays>,
+    mut update_response_events: EventWriter<UpdateInteractionResponse>,
+) {
+    for event in events.iter() {
+        let Some(pending) = pending_says.0.get_mut(&event.context.token) else {
+            continue;
+        };
+        let result = match event.kind {
+            BridgeInfoKind::Queued => continue,
+            BridgeInfoKind::Sanitized => {
+                pending.sanitized = true;
+                continue;
+            }
+            BridgeInfoKind::Delivered => "Sent.".to_string(),
+            BridgeInfoKind::TimedOut => {
+                "Sent, but it didn't show up in chat, so the server might have ignored it."
+                    .to_string()
+            }
+            BridgeInfoKind::NotInServer => "The bot isn't on the server.".to_string(),
+            BridgeInfoKind::Dropped => {
+                "The bot had too many messages to send, so that one was dropped.".to_string()
+            }
+            BridgeInfoKind::IllegalMessage => {
+                "That message can't be sent in Minecraft.".to_string()
+            }
+            BridgeInfoKind::TooLong { chunks, max_chunks } => format!(
+                "That message is too long for Minecraft, it would take {chunks} messages but the \
+                 limit is {max_chunks}."
+            ),
+        };
+        pending
+            .results
+            .push((event.context.account.clone(), result));
+        pending.remaining -= 1;
+        if pending.remaining > 0 {
+            continue;
+        }
+
+        let pending = pending_says.0.remove(&event.context.token).unwrap();
+        update_response_events.send(UpdateInteractionResponse {
+            application_id: event.context.application_id,
+            token: event.context.token.clone(),
+            content: say_response(&pending),
+            attachment: None,
+        });
+    }
+}
+
+/// What we tell someone after they used `/say`. It only says which bot is
+/// which if they didn't all end up the same.
+fn say_response(pending: &PendingSay) -> String {
+    let (_, first) = &pending.results[0];
+    let mut lines = if pending.results.iter().all(|(_, result)| result == first) {
+        vec![first.clone()]
+    } else {
+        pending
+            .results
+            .iter()
+            .map(|(account, result)| format!("{}: {result}", escape_markdown(account)))
+            .collect()
+    };
+    if pending.sanitized {
+        lines.push("Some characters had to be replaced or removed first.".to_string());
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(results: &[(&str, &str)], sanitized: bool) -> PendingSay {
+        PendingSay {
+            remaining: 0,
+            results: results
+                .iter()
+                .map(|(account, result)| (account.to_string(), result.to_string()))
+                .collect(),
+            sanitized,
+        }
+    }
+
+    #[test]
+    fn say_response_when_every_bot_agrees() {
+        let pending = pending(&[("potatobot", "Sent."), ("tomato_bot", "Sent.")], false);
+        assert_eq!(say_response(&pending), "Sent.");
+    }
+
+    #[test]
+    fn say_response_when_bots_disagree() {
+        let pending = pending(
+            &[
+                ("potatobot", "Sent."),
+                ("tomato_bot", "The bot isn't on the server."),
+            ],
+            false,
+        );
+        assert_eq!(
+            say_response(&pending),
+            "potatobot: Sent.\ntomato\\_bot: The bot isn't on the server."
+        );
+    }
+
+    #[test]
+    fn say_response_keeps_the_sanitized_notice() {
+        let pending = pending(&[("potatobot", "Sent.")], true);
+        assert_eq!(
+            say_response(&pending),
+            "Sent.\nSome characters had to be replaced or removed first."
+        );
+    }
+}
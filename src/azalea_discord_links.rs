@@ -0,0 +1,365 @@
+//! Link Discord users to Minecraft players. Someone runs `/link` in Discord to
+//! get a code, and then whispers it to one of the bots in game.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use azalea::{
+    ecs::{
+        app::{App, Plugin},
+        event::{EventReader, EventWriter},
+        system::Query,
+    },
+    entity::Local,
+    GameProfileComponent,
+};
+use bevy_ecs::{
+    query::With,
+    system::{Res, Resource},
+};
+use log::{error, info};
+use parking_lot::Mutex;
+use rusqlite::{params, OptionalExtension, ToSql};
+use twilight_model::application::{command::CommandType, interaction::InteractionData};
+use twilight_util::builder::command::CommandBuilder;
+use uuid::Uuid;
+
+use crate::{
+    azalea_avoid_chat_kick::{ChatPriority, SendChatEvent},
+    azalea_bridge::WhisperFromMinecraftEvent,
+    azalea_discord_bridge::DiscordBridge,
+    azalea_discord_markdown::escape_markdown,
+    azalea_player_list::OnlinePlayers,
+    bevy_discord::{self, send::InteractionResponseKind, ApplicationCommands},
+    bevy_storage::Storage,
+};
+
+/// Adds the `/link` and `/unlink` commands, and links accounts when players
+/// whisper their code to a bot. This should be added after
+/// [`DiscordBridgePlugin`](crate::azalea_discord_bridge::DiscordBridgePlugin),
+/// since the commands only work in bridged channels.
+pub struct DiscordLinksPlugin {
+    /// The links, which are shared between every server.
+    pub links: AccountLinks,
+}
+
+impl Plugin for DiscordLinksPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ApplicationCommands>();
+        app.world.resource_mut::<ApplicationCommands>().0.extend([
+            CommandBuilder::new(
+                "link",
+                "Link your Discord account to your Minecraft account",
+                CommandType::ChatInput,
+            )
+            .build(),
+            CommandBuilder::new(
+                "unlink",
+                "Unlink your Discord account from your Minecraft account",
+                CommandType::ChatInput,
+            )
+            .build(),
+        ]);
+
+        app.insert_resource(self.links.clone())
+            .add_system(handle_link_interactions)
+            .add_system(redeem_link_codes);
+    }
+}
+
+/// How long a code from `/link` can be used for.
+const CODE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+const CODE_LENGTH: usize = 8;
+/// The characters codes are made of. Ones that are easy to mix up, like 0 and
+/// O, are left out.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// The Discord users that are linked to Minecraft players. The links are kept
+/// in the database, and the codes that haven't been used yet are shared between
+/// every clone.
+#[derive(Resource, Clone)]
+pub struct AccountLinks {
+    storage: Storage,
+    /// The codes from `/link` that haven't been whispered yet.
+    pending: Arc<Mutex<HashMap<String, PendingLink>>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LinkedPlayer {
+    pub uuid: Uuid,
+    /// The player's name when they linked. This can be out of date if they
+    /// changed it since then.
+    pub name: String,
+    pub discord_id: u64,
+}
+
+struct PendingLink {
+    discord_id: u64,
+    created_at: Instant,
+}
+
+impl AccountLinks {
+    pub fn new(storage: Storage) -> Self {
+        Self {
+            storage,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The Minecraft player that's linked to this Discord user.
+    pub fn by_discord_id(&self, discord_id: u64) -> Option<LinkedPlayer> {
+        self.query_link("discord_id = ?1", discord_id)
+    }
+
+    /// The Discord user that's linked to this Minecraft player.
+    pub fn by_uuid(&self, uuid: &Uuid) -> Option<LinkedPlayer> {
+        self.query_link("uuid = ?1", uuid.to_string())
+    }
+
+    fn query_link(&self, condition: &str, value: impl ToSql) -> Option<LinkedPlayer> {
+        let result = self.storage.with(|connection| {
+            connection
+                .query_row(
+                    &format!("SELECT uuid, name, discord_id FROM account_links WHERE {condition}"),
+                    [value],
+                    |row| {
+                        Ok((
+                            row.get::<_, String>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, u64>(2)?,
+                        ))
+                    },
+                )
+                .optional()
+        });
+        let (uuid, name, discord_id) = match result {
+            Ok(link) => link?,
+            Err(err) => {
+                error!("couldn't look up account link: {err}");
+                return None;
+            }
+        };
+        Some(LinkedPlayer {
+            uuid: Uuid::parse_str(&uuid).ok()?,
+            name,
+            discord_id,
+        })
+    }
+
+    /// Make a code that links the Discord user to whoever whispers it. This
+    /// replaces the user's previous code if they had one.
+    fn create_code(&self, discord_id: u64) -> String {
+        let mut pending = self.pending.lock();
+        pending.retain(|_, pending| {
+            pending.discord_id != discord_id && pending.created_at.elapsed() < CODE_LIFETIME
+        });
+        let code = loop {
+            let code = random_code();
+            if !pending.contains_key(&code) {
+                break code;
+            }
+        };
+        pending.insert(
+            code.clone(),
+            PendingLink {
+                discord_id,
+                created_at: Instant::now(),
+            },
+        );
+        code
+    }
+
+    /// Link the player to the Discord user that made the code, and return the
+    /// Discord user's id. This returns None if the code is wrong or expired.
+    fn redeem_code(&self, code: &str, uuid: Uuid, name: &str) -> Option<u64> {
+        let pending = self.pending.lock().remove(&normalize_code(code)?)?;
+        if pending.created_at.elapsed() >= CODE_LIFETIME {
+            return None;
+        }
+        // each Discord user and player can only be linked once
+        let result = self.storage.transaction(|transaction| {
+            transaction.execute(
+                "DELETE FROM account_links WHERE uuid = ?1 OR discord_id = ?2",
+                params![uuid.to_string(), pending.discord_id],
+            )?;
+            transaction.execute(
+                "INSERT INTO account_links (uuid, name, discord_id) VALUES (?1, ?2, ?3)",
+                params![uuid.to_string(), name, pending.discord_id],
+            )?;
+            Ok(())
+        });
+        if let Err(err) = result {
+            error!("couldn't save account link: {err}");
+            return None;
+        }
+        Some(pending.discord_id)
+    }
+
+    /// Remove the Discord user's link, and return the player they were linked
+    /// to.
+    fn unlink(&self, discord_id: u64) -> Option<LinkedPlayer> {
+        let link = self.by_discord_id(discord_id)?;
+        let result = self.storage.with(|connection| {
+            connection.execute(
+                "DELETE FROM account_links WHERE discord_id = ?1",
+                [discord_id],
+            )
+        });
+        if let Err(err) = result {
+            error!("couldn't remove account link: {err}");
+            return None;
+        }
+        Some(link)
+    }
+}
+
+fn random_code() -> String {
+    // v4 uuids come from the OS's secure random number generator. the top half
+    // of byte 6 is always the version though, so we skip it, and there are 32
+    // letters so each byte gives an evenly spread one
+    Uuid::new_v4()
+        .as_bytes()
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 6)
+        .take(CODE_LENGTH)
+        .map(|(_, &byte)| CODE_ALPHABET[byte as usize % CODE_ALPHABET.len()] as char)
+        .collect()
+}
+
+/// The code in a whisper, uppercased so it doesn't matter how it was typed.
+fn normalize_code(message: &str) -> Option<String> {
+    let code = message.trim().to_ascii_uppercase();
+    let valid = code.len() == CODE_LENGTH && code.bytes().all(|c| CODE_ALPHABET.contains(&c));
+    valid.then_some(code)
+}
+
+/// Whether a whisper is probably a link code, so it shouldn't be treated like
+/// a normal whisper.
+pub fn looks_like_link_code(message: &str) -> bool {
+    normalize_code(message).is_some()
+}
+
+fn handle_link_interactions(
+    links: Res<AccountLinks>,
+    discord_bridge: Res<DiscordBridge>,
+    mut events: EventReader<bevy_discord::recv::InteractionCreate>,
+    mut respond_events: EventWriter<bevy_discord::send::CreateInteractionResponse>,
+    query: Query<&GameProfileComponent, With<Local>>,
+) {
+    for interaction in events.iter() {
+        let Some(InteractionData::ApplicationCommand(data)) = &interaction.data else {
+            continue;
+        };
+        let Some(channel_id) = interaction.channel_id else {
+            continue;
+        };
+        // every server gets the interaction, so only the one that answers
+        // commands in the channel responds
+        if !discord_bridge.answers_commands_in(channel_id.get()) {
+            continue;
+        }
+        let Some(author) = interaction.author() else {
+            continue;
+        };
+
+        let content = match data.name.as_str() {
+            "link" => {
+                let code = links.create_code(author.id.get());
+                let bot = query
+                    .iter()
+                    .next()
+                    .map(|game_profile| game_profile.name.clone())
+                    .unwrap_or_else(|| "<bot>".to_string());
+                format!(
+                    "Whisper `{code}` to one of the bots in Minecraft, like `/msg {} {code}`. \
+                     The code expires in {} minutes.",
+                    escape_markdown(&bot),
+                    CODE_LIFETIME.as_secs() / 60
+                )
+            }
+            "unlink" => match links.unlink(author.id.get()) {
+                Some(link) => format!(
+                    "Unlinked from Minecraft player **{}**.",
+                    escape_markdown(&link.name)
+                ),
+                None => "You aren't linked to a Minecraft player.".to_string(),
+            },
+            _ => continue,
+        };
+        respond_events.send(bevy_discord::send::CreateInteractionResponse {
+            application_id: interaction.application_id.get(),
+            interaction_id: interaction.id.get(),
+            token: interaction.token.clone(),
+            // the code shouldn't be seen by anyone else, or they could link
+            // themselves to this user
+            kind: InteractionResponseKind::EphemeralMessage(content),
+        });
+    }
+}
+
+/// Link players that whisper a code from `/link` to one of the bots.
+fn redeem_link_codes(
+    links: Res<AccountLinks>,
+    online_players: Res<OnlinePlayers>,
+    mut events: EventReader<WhisperFromMinecraftEvent>,
+    mut send_chat_events: EventWriter<SendChatEvent>,
+    mut direct_message_events: EventWriter<bevy_discord::send::CreateDirectMessage>,
+) {
+    for event in events.iter() {
+        if !looks_like_link_code(&event.content) {
+            continue;
+        }
+        // the uuid is what we link, since names can change
+        let player = online_players.get(&event.entity).and_then(|player_list| {
+            player_list
+                .players
+                .values()
+                .find(|player| player.name == event.sender)
+        });
+        let reply = match player {
+            Some(player) => match links.redeem_code(&event.content, player.uuid, &player.name) {
+                Some(discord_id) => {
+                    info!("linked {} to Discord user {discord_id}", player.name);
+                    direct_message_events.send(bevy_discord::send::CreateDirectMessage {
+                        user_id: discord_id,
+                        content: format!(
+                            "Linked to Minecraft player **{}**.",
+                            escape_markdown(&player.name)
+                        ),
+                    });
+                    "Linked to your Discord account!"
+                }
+                None => "That code is wrong or expired, run /link in Discord to get a new one.",
+            },
+            None => "Couldn't find you in the tab list, try again in a bit.",
+        };
+        let Some(chat_event) =
+            SendChatEvent::new(event.entity, &format!("/msg {} {reply}", event.sender))
+        else {
+            continue;
+        };
+        send_chat_events.send(chat_event.with_priority(ChatPriority::High));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_codes_can_be_whispered() {
+        for _ in 0..100 {
+            let code = random_code();
+            assert_eq!(normalize_code(&code.to_lowercase()), Some(code));
+        }
+    }
+
+    #[test]
+    fn random_codes_are_different() {
+        assert_ne!(random_code(), random_code());
+    }
+}
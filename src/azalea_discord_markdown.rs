@@ -0,0 +1,311 @@
+//! Convert Minecraft chat components to Discord markdown.
+
+use azalea::chat::{style::Style, translatable_component::StringOrComponent, Component};
+
+/// The styles that we can show in Discord.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+struct MarkdownStyle {
+    bold: bool,
+    italic: bool,
+    underlined: bool,
+    strikethrough: bool,
+    obfuscated: bool,
+}
+impl MarkdownStyle {
+    fn inherit(self, style: &Style) -> Self {
+        if style.reset {
+            return Self::default().inherit(&Style {
+                reset: false,
+                ..style.clone()
+            });
+        }
+        Self {
+            bold: style.bold.unwrap_or(self.bold),
+            italic: style.italic.unwrap_or(self.italic),
+            underlined: style.underlined.unwrap_or(self.underlined),
+            strikethrough: style.strikethrough.unwrap_or(self.strikethrough),
+            obfuscated: style.obfuscated.unwrap_or(self.obfuscated),
+        }
+    }
+
+    /// The markdown that goes around text with this style. The closing markers
+    /// are the same but reversed.
+    fn markers(self) -> String {
+        let mut markers = String::new();
+        // obfuscated text is hidden in game, so spoilers are the closest thing
+        if self.obfuscated {
+            markers.push_str("||");
+        }
+        if self.strikethrough {
+            markers.push_str("~~");
+        }
+        if self.underlined {
+            markers.push_str("__");
+        }
+        if self.bold {
+            markers.push_str("**");
+        }
+        if self.italic {
+            // underscores would be ambiguous with underlines
+            markers.push('*');
+        }
+        markers
+    }
+}
+
+/// Convert a Minecraft chat component to Discord markdown, keeping the styles
+/// that Discord supports and escaping everything else.
+///
+/// Azalea doesn't keep click events when it parses components, so links are
+/// made from URLs in the text instead.
+pub fn component_to_markdown(component: &Component) -> String {
+    let mut runs = Vec::new();
+    collect_runs(component, MarkdownStyle::default(), &mut runs);
+    runs_to_markdown(runs)
+}
+
+/// Like [`component_to_markdown`], but only what the player said, without
+/// their name. This is for webhooks, since they already show who sent it.
+/// Returns None if we can't tell which part is the content.
+pub fn chat_content_to_markdown(message: &Component, sender: &str) -> Option<String> {
+    let Component::Translatable(translatable) = message else {
+        // plugins send chat as plain text like `<player> text`
+        let prefix = escape_markdown(&format!("<{sender}> "));
+        return component_to_markdown(message)
+            .strip_prefix(&prefix)
+            .map(str::to_owned);
+    };
+    if !matches!(
+        translatable.key.as_str(),
+        "chat.type.text" | "chat.type.emote"
+    ) {
+        return None;
+    }
+    // the first argument is the sender and the second is what they said
+    let style = MarkdownStyle::default().inherit(&translatable.base.style);
+    let mut runs = Vec::new();
+    match translatable.args.get(1)? {
+        StringOrComponent::String(text) => runs.push((style, text.clone())),
+        StringOrComponent::Component(component) => collect_runs(component, style, &mut runs),
+    }
+    Some(runs_to_markdown(runs))
+}
+
+fn runs_to_markdown(runs: Vec<(MarkdownStyle, String)>) -> String {
+    // merge runs with the same style so we don't end up with ****
+    let mut merged: Vec<(MarkdownStyle, String)> = Vec::new();
+    for (style, text) in runs {
+        if text.is_empty() {
+            continue;
+        }
+        match merged.last_mut() {
+            Some((last_style, last_text)) if *last_style == style => last_text.push_str(&text),
+            _ => merged.push((style, text)),
+        }
+    }
+
+    let mut markdown = String::new();
+    for (style, text) in merged {
+        // discord doesn't format text if the markers are next to whitespace, so
+        // the whitespace has to go outside
+        let trimmed_start = text.trim_start();
+        let leading = &text[..text.len() - trimmed_start.len()];
+        let trimmed = trimmed_start.trim_end();
+        let trailing = &trimmed_start[trimmed.len()..];
+
+        markdown.push_str(leading);
+        if !trimmed.is_empty() {
+            let markers = style.markers();
+            markdown.push_str(&markers);
+            markdown.push_str(&escape_markdown(trimmed));
+            markdown.extend(markers.chars().rev());
+        }
+        markdown.push_str(trailing);
+    }
+    markdown
+}
+
+fn collect_runs(
+    component: &Component,
+    parent: MarkdownStyle,
+    runs: &mut Vec<(MarkdownStyle, String)>,
+) {
+    let style = parent.inherit(&component.get_base().style);
+    match component {
+        Component::Text(text_component) => {
+            runs.push((style, text_component.text.clone()));
+            for sibling in &text_component.base.siblings {
+                collect_runs(sibling, style, runs);
+            }
+        }
+        Component::Translatable(translatable_component) => {
+            match translatable_component.read() {
+                Ok(text_component) => {
+                    collect_runs(&Component::Text(text_component), style, runs);
+                }
+                Err(_) => runs.push((style, translatable_component.to_string())),
+            }
+            for sibling in &translatable_component.base.siblings {
+                collect_runs(sibling, style, runs);
+            }
+        }
+    }
+}
+
+/// Escape everything in the text that Discord would treat as markdown. URLs
+/// are left alone so Discord still turns them into links.
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            escaped.push('\n');
+        }
+        // these are only special at the start of a line
+        if line.starts_with(['>', '#', '-']) {
+            escaped.push('\\');
+        }
+        let mut words = line.split(' ').peekable();
+        while let Some(word) = words.next() {
+            if is_url(word) {
+                escaped.push_str(word);
+            } else {
+                for c in word.chars() {
+                    if matches!(
+                        c,
+                        '\\' | '*' | '_' | '~' | '|' | '`' | '[' | ']' | '(' | ')' | '<' | ':'
+                    ) {
+                        escaped.push('\\');
+                    }
+                    escaped.push(c);
+                }
+            }
+            if words.peek().is_some() {
+                escaped.push(' ');
+            }
+        }
+    }
+    escaped
+}
+
+fn is_url(word: &str) -> bool {
+    (word.starts_with("https://") || word.starts_with("http://"))
+        && word.len() > "https://".len()
+        && !word.contains(['<', '>'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn markdown(json: &str) -> String {
+        component_to_markdown(&serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn escapes_markdown_characters() {
+        assert_eq!(escape_markdown("*hi* _there_"), r"\*hi\* \_there\_");
+        assert_eq!(
+            escape_markdown("`code` ||spoiler||"),
+            r"\`code\` \|\|spoiler\|\|"
+        );
+        assert_eq!(
+            escape_markdown("[link](x) <@123> :x:"),
+            r"\[link\]\(x\) \<@123> \:x\:"
+        );
+        assert_eq!(escape_markdown(r"back\slash"), r"back\\slash");
+    }
+
+    #[test]
+    fn escapes_line_starts() {
+        assert_eq!(escape_markdown("> quote"), r"\> quote");
+        assert_eq!(
+            escape_markdown("# heading\n- item"),
+            "\\# heading\n\\- item"
+        );
+        // only at the start of a line
+        assert_eq!(escape_markdown("a > b # c - d"), "a > b # c - d");
+    }
+
+    #[test]
+    fn leaves_urls_alone() {
+        assert_eq!(
+            escape_markdown("see https://example.com/a_b_(c) *now*"),
+            r"see https://example.com/a_b_(c) \*now\*"
+        );
+        assert_eq!(escape_markdown("https://"), r"https\://");
+    }
+
+    #[test]
+    fn plain_text() {
+        assert_eq!(markdown(r#"{"text":"hello *world*"}"#), r"hello \*world\*");
+    }
+
+    #[test]
+    fn styles() {
+        assert_eq!(markdown(r#"{"text":"hi","bold":true}"#), "**hi**");
+        assert_eq!(markdown(r#"{"text":"hi","italic":true}"#), "*hi*");
+        assert_eq!(markdown(r#"{"text":"hi","underlined":true}"#), "__hi__");
+        assert_eq!(markdown(r#"{"text":"hi","strikethrough":true}"#), "~~hi~~");
+        assert_eq!(markdown(r#"{"text":"hi","obfuscated":true}"#), "||hi||");
+        assert_eq!(
+            markdown(r#"{"text":"hi","bold":true,"italic":true}"#),
+            "***hi***"
+        );
+    }
+
+    #[test]
+    fn siblings_inherit_styles() {
+        assert_eq!(
+            markdown(
+                r#"{"text":"a","bold":true,"extra":[{"text":"b"},{"text":"c","bold":false}]}"#
+            ),
+            "**ab**c"
+        );
+    }
+
+    #[test]
+    fn whitespace_goes_outside_markers() {
+        assert_eq!(
+            markdown(
+                r#"{"text":"","extra":[{"text":"say "},{"text":" hi ","bold":true},{"text":"now"}]}"#
+            ),
+            "say  **hi** now"
+        );
+        assert_eq!(markdown(r#"{"text":"   ","bold":true}"#), "   ");
+    }
+
+    #[test]
+    fn translations_are_filled_in() {
+        assert_eq!(
+            markdown(r#"{"translate":"chat.type.text","with":["Notch","*hi*"]}"#),
+            r"\<Notch> \*hi\*"
+        );
+    }
+
+    fn chat_content(json: &str, sender: &str) -> Option<String> {
+        chat_content_to_markdown(&serde_json::from_str(json).unwrap(), sender)
+    }
+
+    #[test]
+    fn chat_content_without_the_sender() {
+        assert_eq!(
+            chat_content(
+                r#"{"translate":"chat.type.text","with":["Notch",{"text":"hi","bold":true}]}"#,
+                "Notch"
+            ),
+            Some("**hi**".to_string())
+        );
+        assert_eq!(
+            chat_content(r#"{"text":"<Notch> *hi*"}"#, "Notch"),
+            Some(r"\*hi\*".to_string())
+        );
+        assert_eq!(
+            chat_content(
+                r#"{"text":"","extra":[{"text":"<my_name> "},{"text":"hi","italic":true}]}"#,
+                "my_name"
+            ),
+            Some("*hi*".to_string())
+        );
+        assert_eq!(chat_content(r#"{"text":"Notch: hi"}"#, "Notch"), None);
+    }
+}
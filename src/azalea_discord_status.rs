@@ -0,0 +1,210 @@
+//! Show whether the server is up and who's on it in Discord, as the bot's
+//! activity and in channel topics.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use azalea::{
+    ecs::{
+        app::{App, Plugin},
+        event::EventWriter,
+        system::Query,
+    },
+    entity::Local,
+    GameProfileComponent,
+};
+use bevy_ecs::{
+    query::With,
+    system::{Res, ResMut, Resource},
+};
+use parking_lot::Mutex;
+
+use crate::{
+    azalea_player_list::{OnlinePlayers, PlayerListPlugin},
+    bevy_discord::{self, send::Status},
+};
+
+pub struct DiscordStatusPlugin {
+    /// The name of the server that's shown in Discord.
+    pub server_name: String,
+    /// Where every server's status is put, so the presence can show all of
+    /// them.
+    pub statuses: ServerStatuses,
+    /// Whether this swarm sets the bot's activity, like `Playing main — 3
+    /// online`. There's only one bot, so only one swarm should do this.
+    pub presence: bool,
+    /// Channels whose topic lists who's online.
+    pub topic_channels: Vec<u64>,
+    /// How often the topics can be updated. Discord only lets us change each
+    /// channel's topic twice every 10 minutes.
+    pub topic_interval: Duration,
+}
+
+impl Plugin for DiscordStatusPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.world.contains_resource::<OnlinePlayers>() {
+            app.add_plugin(PlayerListPlugin);
+        }
+        app.insert_resource(DiscordStatus {
+            server_name: self.server_name.clone(),
+            statuses: self.statuses.clone(),
+            presence: self.presence,
+            topic_channels: self.topic_channels.clone(),
+            topic_interval: self.topic_interval,
+            started_at: Instant::now(),
+            last_presence: None,
+            last_topic: None,
+        })
+        .add_system(update_discord_status);
+    }
+}
+
+/// How often the presence can be updated. Discord allows a few updates a
+/// minute, but there's no point in changing it that often.
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(20);
+/// The bots take a bit to join when we start, so we don't say the server is
+/// offline until they've had a chance to.
+const STARTUP_GRACE: Duration = Duration::from_secs(30);
+/// The longest topic Discord allows, in characters.
+const MAX_TOPIC_LENGTH: usize = 1024;
+
+/// How many players are on each server, in the order they're in the config.
+/// `None` means the server is offline, or hasn't had the chance to join yet.
+#[derive(Clone, Default)]
+pub struct ServerStatuses(Arc<Mutex<Vec<(String, Option<usize>)>>>);
+
+impl ServerStatuses {
+    pub fn new(server_names: impl IntoIterator<Item = String>) -> Self {
+        Self(Arc::new(Mutex::new(
+            server_names.into_iter().map(|name| (name, None)).collect(),
+        )))
+    }
+
+    fn set(&self, server_name: &str, online: Option<usize>) {
+        let mut statuses = self.0.lock();
+        match statuses.iter_mut().find(|(name, _)| name == server_name) {
+            Some((_, status)) => *status = online,
+            None => statuses.push((server_name.to_string(), online)),
+        }
+    }
+
+    /// What the bot is playing and whether it's online or idle, which is idle
+    /// if every server is offline.
+    fn presence(&self) -> (String, Status) {
+        let statuses = self.0.lock();
+        let status = if statuses.iter().any(|(_, online)| online.is_some()) {
+            Status::Online
+        } else {
+            Status::Idle
+        };
+        let playing = match statuses.as_slice() {
+            [(server_name, Some(online))] => format!("{server_name} — {online} online"),
+            [(server_name, None)] => format!("{server_name} — offline"),
+            statuses => statuses
+                .iter()
+                .map(|(server_name, online)| match online {
+                    Some(online) => format!("{server_name}: {online} online"),
+                    None => format!("{server_name}: offline"),
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        (playing, status)
+    }
+}
+
+#[derive(Resource)]
+pub struct DiscordStatus {
+    pub server_name: String,
+    pub statuses: ServerStatuses,
+    pub presence: bool,
+    pub topic_channels: Vec<u64>,
+    pub topic_interval: Duration,
+    started_at: Instant,
+    /// What we last set and when, so we only send changes and don't send them
+    /// too often.
+    last_presence: Option<(String, Instant)>,
+    last_topic: Option<(String, Instant)>,
+}
+
+/// Whether it's been long enough since the last update, and something changed.
+fn should_update(last: &Option<(String, Instant)>, text: &str, interval: Duration) -> bool {
+    match last {
+        Some((last_text, sent_at)) => last_text != text && sent_at.elapsed() >= interval,
+        None => true,
+    }
+}
+
+fn update_discord_status(
+    mut status: ResMut<DiscordStatus>,
+    online_players: Res<OnlinePlayers>,
+    mut presence_events: EventWriter<bevy_discord::send::UpdatePresence>,
+    mut topic_events: EventWriter<bevy_discord::send::UpdateChannelTopic>,
+    query: Query<&GameProfileComponent, With<Local>>,
+) {
+    let status = &mut *status;
+    // None if none of the bots are on the server
+    let players = (!query.is_empty()).then(|| {
+        let mut names = online_players
+            .values()
+            .flat_map(|player_list| player_list.players.values())
+            .map(|player| player.name.clone())
+            .collect::<Vec<_>>();
+        names.sort_by_key(|name| name.to_lowercase());
+        names.dedup();
+        names
+    });
+    if players.is_none() && status.started_at.elapsed() < STARTUP_GRACE {
+        return;
+    }
+    let server_name = &status.server_name;
+    status
+        .statuses
+        .set(server_name, players.as_ref().map(Vec::len));
+
+    if status.presence {
+        let (playing, discord_status) = status.statuses.presence();
+        if should_update(&status.last_presence, &playing, PRESENCE_INTERVAL) {
+            presence_events.send(bevy_discord::send::UpdatePresence {
+                status: discord_status,
+                playing: playing.clone(),
+            });
+            status.last_presence = Some((playing, Instant::now()));
+        }
+    }
+
+    if !status.topic_channels.is_empty() {
+        let topic = match &players {
+            Some(players) if players.is_empty() => format!("{server_name}: nobody online"),
+            Some(players) => format!(
+                "{server_name}: {} online — {}",
+                players.len(),
+                players.join(", ")
+            ),
+            None => format!("{server_name} is offline"),
+        };
+        let topic = truncate(&topic, MAX_TOPIC_LENGTH);
+        if should_update(&status.last_topic, &topic, status.topic_interval) {
+            for &channel_id in &status.topic_channels {
+                topic_events.send(bevy_discord::send::UpdateChannelTopic {
+                    channel_id,
+                    topic: topic.clone(),
+                });
+            }
+            status.last_topic = Some((topic, Instant::now()));
+        }
+    }
+}
+
+/// Cut the text off at this many characters, ending it with `…` if it was too
+/// long.
+fn truncate(text: &str, max_length: usize) -> String {
+    if text.chars().count() <= max_length {
+        return text.to_string();
+    }
+    let mut truncated = text.chars().take(max_length - 1).collect::<String>();
+    truncated.push('…');
+    truncated
+}
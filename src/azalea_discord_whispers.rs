@@ -0,0 +1,275 @@
+//! Send whispers to the bots to Discord, and let people reply to them from
+//! Discord.
+
+use std::collections::HashMap;
+
+use azalea::{
+    ecs::{
+        app::{App, Plugin},
+        event::{EventReader, EventWriter},
+        system::Query,
+    },
+    entity::Local,
+    GameProfileComponent,
+};
+use bevy_ecs::{
+    entity::Entity,
+    query::With,
+    system::{Res, ResMut, Resource},
+};
+
+use crate::{
+    azalea_avoid_chat_kick::ChatPriority,
+    azalea_bridge::{BridgePlugin, ToMinecraftEvent, WhisperFromMinecraftEvent},
+    azalea_discord_bridge::{handle_bridge_info_events, DiscordContext},
+    azalea_discord_links::looks_like_link_code,
+    azalea_discord_markdown::escape_markdown,
+    azalea_player_list::OnlinePlayers,
+    azalea_playtime::is_playtime_command,
+    bevy_discord,
+};
+
+pub struct DiscordWhispersPlugin {
+    /// The Discord user that gets DMed whispers to each account, keyed by the
+    /// account's username.
+    pub owners: HashMap<String, u64>,
+    /// Whispers to accounts without an owner are sent to this channel. If
+    /// this isn't set, they're only logged.
+    pub admin_channel: Option<u64>,
+    /// Long or multi-line replies are split into at most this many whispers.
+    pub max_chunks: usize,
+}
+
+impl Plugin for DiscordWhispersPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(DiscordWhispers {
+            owners: self.owners.clone(),
+            admin_channel: self.admin_channel,
+            last_whispers: HashMap::new(),
+        })
+        // the player's name is passed as the username, so every chunk is
+        // whispered to them
+        .add_plugin(BridgePlugin::<WhisperContext>::new(
+            "discord",
+            "/msg {username} {content}".to_string(),
+            self.max_chunks,
+        ))
+        .add_system(whispers_to_discord)
+        .add_system(discord_to_whispers)
+        .add_system(handle_bridge_info_events::<WhisperContext>);
+    }
+}
+
+/// The Discord message that a reply to a whisper came from.
+#[derive(Clone)]
+pub struct WhisperContext(pub DiscordContext);
+impl From<WhisperContext> for DiscordContext {
+    fn from(context: WhisperContext) -> Self {
+        context.0
+    }
+}
+
+#[derive(Resource)]
+pub struct DiscordWhispers {
+    pub owners: HashMap<String, u64>,
+    pub admin_channel: Option<u64>,
+    /// The last whisper that was sent to each place, so replies know who to
+    /// whisper to. Keyed by the user id for DMs or the channel id for the admin
+    /// channel.
+    pub last_whispers: HashMap<u64, LastWhisper>,
+}
+
+#[derive(Clone)]
+pub struct LastWhisper {
+    /// The username of the bot that was whispered to.
+    pub account: String,
+    pub player: String,
+}
+
+/// Where a whisper gets sent in Discord.
+enum WhisperDestination {
+    DirectMessage { user_id: u64 },
+    AdminChannel { channel_id: u64 },
+}
+
+impl DiscordWhispers {
+    fn destination(&self, account: &str) -> Option<WhisperDestination> {
+        if let Some(&user_id) = self.owners.get(account) {
+            return Some(WhisperDestination::DirectMessage { user_id });
+        }
+        self.admin_channel
+            .map(|channel_id| WhisperDestination::AdminChannel { channel_id })
+    }
+}
+
+fn whispers_to_discord(
+    mut whispers: ResMut<DiscordWhispers>,
+    mut events: EventReader<WhisperFromMinecraftEvent>,
+    mut create_message_events: EventWriter<bevy_discord::send::CreateMessage>,
+    mut direct_message_events: EventWriter<bevy_discord::send::CreateDirectMessage>,
+    query: Query<&GameProfileComponent, With<Local>>,
+) {
+    for event in events.iter() {
+        let Ok(game_profile) = query.get(event.entity) else {
+            continue;
+        };
+        if looks_like_link_code(&event.content) || is_playtime_command(&event.content) {
+            // these are handled by DiscordLinksPlugin and PlaytimePlugin
+            continue;
+        }
+        let account = &game_profile.name;
+        println!("{} whispered to {account}: {}", event.sender, event.content);
+
+        let Some(destination) = whispers.destination(account) else {
+            continue;
+        };
+        let content = format!(
+            "**{}** whispered to **{}**: {}",
+            escape_markdown(&event.sender),
+            escape_markdown(account),
+            escape_markdown(&event.content)
+        );
+        let key = match destination {
+            WhisperDestination::DirectMessage { user_id } => {
+                direct_message_events
+                    .send(bevy_discord::send::CreateDirectMessage { user_id, content });
+                user_id
+            }
+            WhisperDestination::AdminChannel { channel_id } => {
+                create_message_events.send(bevy_discord::send::CreateMessage {
+                    channel_id,
+                    content,
+                });
+                channel_id
+            }
+        };
+        whispers.last_whispers.insert(
+            key,
+            LastWhisper {
+                account: account.clone(),
+                player: event.sender.clone(),
+            },
+        );
+    }
+}
+
+/// Send DMs from an account's owner and messages in the admin channel to
+/// Minecraft as whispers. Messages can start with `player: ` to pick who to
+/// whisper to, and otherwise they go to whoever whispered last.
+///
+/// Messages that we don't know who to whisper to are ignored, since they might
+/// be for a player on a different server.
+fn discord_to_whispers(
+    whispers: Res<DiscordWhispers>,
+    mut events: EventReader<bevy_discord::recv::MessageCreate>,
+    mut to_minecraft_events: EventWriter<ToMinecraftEvent<WhisperContext>>,
+    online_players: Res<OnlinePlayers>,
+    query: Query<(Entity, &GameProfileComponent), With<Local>>,
+) {
+    for event in events.iter() {
+        if event.author.bot {
+            continue;
+        }
+        let channel_id = event.channel_id.get();
+
+        // the accounts this message could be sent from, and where we remember
+        // the last whisper
+        let (key, mut accounts) = if event.guild_id.is_none() {
+            let user_id = event.author.id.get();
+            let owned = whispers
+                .owners
+                .iter()
+                .filter(|(_, &owner)| owner == user_id)
+                .map(|(account, _)| account.clone())
+                .collect::<Vec<_>>();
+            if owned.is_empty() {
+                continue;
+            }
+            (user_id, owned)
+        } else if whispers.admin_channel == Some(channel_id) {
+            let online = query
+                .iter()
+                .map(|(_, game_profile)| game_profile.name.clone())
+                .filter(|account| !whispers.owners.contains_key(account))
+                .collect::<Vec<_>>();
+            (channel_id, online)
+        } else {
+            continue;
+        };
+        accounts.sort();
+
+        let last_whisper = whispers.last_whispers.get(&key);
+        let target = match split_player_prefix(&event.content) {
+            Some((player, content)) => {
+                // keep using the same bot if we're replying to the same player,
+                // and otherwise use one that can see them
+                let account = last_whisper
+                    .filter(|last| last.player.eq_ignore_ascii_case(player))
+                    .map(|last| last.account.clone())
+                    .or_else(|| {
+                        accounts
+                            .iter()
+                            .find(|account| can_see(account, player, &online_players, &query))
+                            .cloned()
+                    });
+                account.map(|account| (account, player.to_string(), content.to_string()))
+            }
+            None => last_whisper.map(|last| {
+                (
+                    last.account.clone(),
+                    last.player.clone(),
+                    event.content.clone(),
+                )
+            }),
+        };
+        let Some((account, player, content)) = target else {
+            // every server gets this message, so it might be for a player on
+            // a different one
+            continue;
+        };
+
+        to_minecraft_events.send(ToMinecraftEvent {
+            account,
+            username: player,
+            content,
+            // someone's waiting for an answer
+            priority: ChatPriority::High,
+            context: WhisperContext(DiscordContext {
+                channel_id,
+                message_id: event.id.get(),
+            }),
+        });
+    }
+}
+
+/// Whether the player is in the account's tab list.
+fn can_see(
+    account: &str,
+    player: &str,
+    online_players: &OnlinePlayers,
+    query: &Query<(Entity, &GameProfileComponent), With<Local>>,
+) -> bool {
+    let Some((entity, _)) = query
+        .iter()
+        .find(|(_, game_profile)| game_profile.name == account)
+    else {
+        return false;
+    };
+    online_players.get(&entity).map_or(false, |player_list| {
+        player_list
+            .players
+            .values()
+            .any(|online| online.name.eq_ignore_ascii_case(player))
+    })
+}
+
+/// Split a message like `player: hello` into the player's name and the rest.
+fn split_player_prefix(content: &str) -> Option<(&str, &str)> {
+    let (player, rest) = content.split_once(": ")?;
+    let valid = !player.is_empty()
+        && player.len() <= 16
+        && player
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+    valid.then_some((player, rest))
+}
@@ -0,0 +1,261 @@
+use std::collections::{HashMap, VecDeque};
+
+use azalea::{
+    ecs::{
+        app::{App, Plugin},
+        event::{EventReader, EventWriter},
+        system::Query,
+        AppTickExt,
+    },
+    entity::Local,
+    prelude::*,
+    GameProfileComponent,
+};
+use bevy_ecs::{
+    query::With,
+    system::{Res, ResMut},
+};
+
+use crate::{
+    azalea_avoid_chat_kick::ChatPriority,
+    azalea_bridge::{
+        fill_template, BridgeInfoEvent, BridgeInfoKind, BridgePlugin, FromMinecraftEvent,
+        FromMinecraftPlayerEvent, PlayerActivity, ToMinecraftEvent,
+    },
+    azalea_message_kind::MessageKind,
+    bevy_matrix,
+};
+
+pub struct MatrixBridgePlugin {
+    /// The Matrix rooms that each Minecraft account is bridged to, keyed by
+    /// the account's username.
+    pub rooms: HashMap<String, Vec<String>>,
+    /// How Minecraft messages are shown in Matrix. `{content}` is replaced.
+    pub format: String,
+    /// How Matrix messages are sent to Minecraft. `{username}` and
+    /// `{content}` are replaced.
+    pub to_minecraft_format: String,
+    /// Long or multi-line messages are split into at most this many
+    /// Minecraft chat messages.
+    pub max_chunks: usize,
+    /// How much sending a message adds to the ratelimit.
+    pub message_cost: usize,
+    /// We can't send messages while the ratelimit is at least this.
+    pub max_ratelimit: usize,
+}
+
+impl Plugin for MatrixBridgePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(MatrixBridge {
+            rooms: self.rooms.clone(),
+            matrix_queues: HashMap::new(),
+            format: self.format.clone(),
+            message_cost: self.message_cost,
+            max_ratelimit: self.max_ratelimit,
+        })
+        .add_plugin(BridgePlugin::<MatrixContext>::new(
+            "matrix",
+            self.to_minecraft_format.clone(),
+            self.max_chunks,
+        ))
+        .add_system(minecraft_to_matrix_queue)
+        .add_system(player_activity_to_matrix_queue)
+        .add_system(matrix_to_minecraft)
+        .add_system(handle_bridge_info_events)
+        .add_tick_system(flush_to_matrix_queue);
+    }
+}
+
+#[derive(Clone)]
+pub struct MatrixContext {
+    pub room_id: String,
+    pub event_id: String,
+}
+
+#[derive(Resource)]
+pub struct MatrixBridge {
+    /// The Matrix rooms that each Minecraft account is bridged to, keyed by
+    /// the account's username.
+    pub rooms: HashMap<String, Vec<String>>,
+    /// The messages waiting to be sent to each room, keyed by room id.
+    pub matrix_queues: HashMap<String, MatrixQueue>,
+    pub format: String,
+    pub message_cost: usize,
+    pub max_ratelimit: usize,
+}
+
+impl MatrixBridge {
+    /// The usernames of the Minecraft accounts that are bridged to this
+    /// Matrix room.
+    pub fn accounts_for_room<'a>(&'a self, room_id: &'a str) -> impl Iterator<Item = &'a String> {
+        self.rooms
+            .iter()
+            .filter(move |(_, room_ids)| room_ids.iter().any(|r| r == room_id))
+            .map(|(account, _)| account)
+    }
+}
+
+#[derive(Default)]
+pub struct MatrixQueue {
+    pub messages: VecDeque<String>,
+    /// The number of ticks we have to wait until the ratelimit is fully reset. Sending a message adds `message_cost`, if it's >= `max_ratelimit` we can't send messages.
+    pub ratelimit: usize,
+}
+
+fn minecraft_to_matrix_queue(
+    mut matrix_bridge: ResMut<MatrixBridge>,
+    mut events: EventReader<FromMinecraftEvent>,
+    query: Query<&GameProfileComponent, With<Local>>,
+) {
+    let matrix_bridge = &mut *matrix_bridge;
+    for event in events.iter() {
+        let Ok(game_profile) = query.get(event.entity) else {
+            continue;
+        };
+        let Some(room_ids) = matrix_bridge.rooms.get(&game_profile.name) else {
+            continue;
+        };
+        // joins and leaves are sent by player_activity_to_matrix_queue, so the
+        // vanilla messages for them would be duplicates
+        if matches!(
+            event.classified.kind,
+            MessageKind::Join | MessageKind::Leave
+        ) {
+            continue;
+        }
+
+        // we send plain text messages so there's nothing to escape
+        let content = fill_template(&matrix_bridge.format, &[("content", &event.content)]);
+        for room_id in room_ids {
+            matrix_bridge
+                .matrix_queues
+                .entry(room_id.clone())
+                .or_default()
+                .messages
+                .push_back(content.clone());
+        }
+    }
+}
+
+fn player_activity_to_matrix_queue(
+    mut matrix_bridge: ResMut<MatrixBridge>,
+    mut events: EventReader<FromMinecraftPlayerEvent>,
+    query: Query<&GameProfileComponent, With<Local>>,
+) {
+    let matrix_bridge = &mut *matrix_bridge;
+    for event in events.iter() {
+        let Ok(game_profile) = query.get(event.entity) else {
+            continue;
+        };
+        let Some(room_ids) = matrix_bridge.rooms.get(&game_profile.name) else {
+            continue;
+        };
+
+        let content = match event.kind {
+            PlayerActivity::Joined => format!("→ {} joined the game", event.player.name),
+            PlayerActivity::Left => format!("← {} left the game", event.player.name),
+        };
+
+        for room_id in room_ids {
+            matrix_bridge
+                .matrix_queues
+                .entry(room_id.clone())
+                .or_default()
+                .messages
+                .push_back(content.clone());
+        }
+    }
+}
+
+fn flush_to_matrix_queue(
+    mut matrix_bridge: ResMut<MatrixBridge>,
+    mut send_message_events: EventWriter<bevy_matrix::send::SendMessage>,
+) {
+    let matrix_bridge = &mut *matrix_bridge;
+    let message_cost = matrix_bridge.message_cost;
+    let max_ratelimit = matrix_bridge.max_ratelimit;
+    for (room_id, queue) in matrix_bridge.matrix_queues.iter_mut() {
+        if queue.ratelimit > 0 {
+            queue.ratelimit -= 1;
+        }
+        if queue.ratelimit >= max_ratelimit {
+            // ratelimited!
+            continue;
+        }
+        let mut sending_messages: Vec<String> = Vec::new();
+        while let Some(content) = queue.messages.front() {
+            if !sending_messages.is_empty()
+                && sending_messages.join("\n").len() + 1 + content.len() > 1000
+            {
+                // leave it in the queue for next time
+                break;
+            }
+            let content = queue.messages.pop_front().unwrap();
+            sending_messages.push(content);
+        }
+        if !sending_messages.is_empty() {
+            queue.ratelimit += message_cost;
+            let content = sending_messages.join("\n");
+            send_message_events.send(bevy_matrix::send::SendMessage {
+                room_id: room_id.clone(),
+                content,
+            });
+        }
+    }
+}
+
+fn matrix_to_minecraft(
+    matrix_bridge: Res<MatrixBridge>,
+    mut events: EventReader<bevy_matrix::recv::RoomMessage>,
+    mut to_minecraft_events: EventWriter<ToMinecraftEvent<MatrixContext>>,
+) {
+    for event in events.iter() {
+        for account in matrix_bridge.accounts_for_room(&event.room_id) {
+            to_minecraft_events.send(ToMinecraftEvent {
+                account: account.clone(),
+                content: event.body.clone(),
+                username: event.sender_name.clone(),
+                priority: ChatPriority::Normal,
+                context: MatrixContext {
+                    room_id: event.room_id.clone(),
+                    event_id: event.event_id.clone(),
+                },
+            });
+        }
+    }
+}
+
+fn handle_bridge_info_events(
+    mut events: EventReader<BridgeInfoEvent<MatrixContext>>,
+    mut react_events: EventWriter<bevy_matrix::send::SendReaction>,
+    mut send_message_events: EventWriter<bevy_matrix::send::SendMessage>,
+) {
+    for event in events.iter() {
+        let key = match event.kind {
+            // reactions can't be taken back as easily in matrix, so we only
+            // react once we know what happened
+            BridgeInfoKind::Queued => continue,
+            BridgeInfoKind::Delivered => "👍",
+            BridgeInfoKind::TimedOut => "⌛",
+            BridgeInfoKind::Sanitized => "🧹",
+            BridgeInfoKind::NotInServer => "👎",
+            BridgeInfoKind::Dropped => "💨",
+            BridgeInfoKind::IllegalMessage => "🚫",
+            BridgeInfoKind::TooLong { chunks, max_chunks } => {
+                send_message_events.send(bevy_matrix::send::SendMessage {
+                    room_id: event.context.room_id.clone(),
+                    content: format!(
+                        "That message is too long for Minecraft, it would take {chunks} messages \
+                         but the limit is {max_chunks}."
+                    ),
+                });
+                "📏"
+            }
+        };
+        react_events.send(bevy_matrix::send::SendReaction {
+            room_id: event.context.room_id.clone(),
+            event_id: event.context.event_id.clone(),
+            key: key.to_string(),
+        });
+    }
+}
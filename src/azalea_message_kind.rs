@@ -0,0 +1,274 @@
+//! Tell apart the different kinds of messages we get from Minecraft, like
+//! chat, deaths and advancements.
+
+use azalea::chat::{translatable_component::StringOrComponent, ChatPacket, Component};
+use azalea_protocol::packets::game::clientbound_player_chat_packet::ChatType;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MessageKind {
+    /// Something a player said, including `/me` and whispers.
+    Chat,
+    /// An announcement from `/say`, which can come from a player or from the
+    /// server's console.
+    Broadcast,
+    /// Anything that isn't one of the other kinds, like command output and
+    /// messages from plugins.
+    #[default]
+    System,
+    Death,
+    Advancement,
+    /// A player joined the game.
+    Join,
+    /// A player left the game.
+    Leave,
+}
+
+/// What a message from Minecraft is and who it's about, from [`classify`].
+#[derive(Clone, Debug, Default)]
+pub struct ClassifiedMessage {
+    pub kind: MessageKind,
+    /// The player that said something, joined, left or made an advancement, or
+    /// whoever made an announcement.
+    pub sender: Option<String>,
+    /// The player that died.
+    pub victim: Option<String>,
+    /// Whatever killed the victim, which can be a mob or another player.
+    pub killer: Option<String>,
+    /// The title of the advancement, without the brackets.
+    pub advancement: Option<String>,
+}
+
+/// Figure out what kind of message this is. Player chat is easy since it has
+/// its own packet, and vanilla's system messages are translatable so we can go
+/// by their key. Plugins often send everything as plain text though, so
+/// messages that look like `<player> text` are treated as chat too.
+pub fn classify(packet: &ChatPacket) -> ClassifiedMessage {
+    if let ChatPacket::Player(player_chat) = packet {
+        // the other chat types are something a player said, they only change
+        // how it's decorated
+        let kind = match player_chat.chat_type.chat_type {
+            ChatType::SayCommand => MessageKind::Broadcast,
+            _ => MessageKind::Chat,
+        };
+        return ClassifiedMessage {
+            kind,
+            sender: Some(player_chat.chat_type.name.to_string()),
+            ..Default::default()
+        };
+    }
+    classify_component(&packet.message())
+}
+
+/// Figure out what kind of message a system message is.
+fn classify_component(message: &Component) -> ClassifiedMessage {
+    let Component::Translatable(translatable) = message else {
+        return match plain_chat_sender(&message.to_string()) {
+            Some(sender) => ClassifiedMessage {
+                kind: MessageKind::Chat,
+                sender: Some(sender.to_string()),
+                ..Default::default()
+            },
+            None => ClassifiedMessage::default(),
+        };
+    };
+    let key = translatable.key.as_str();
+    let arg = |i: usize| translatable.args.get(i).map(arg_to_string);
+
+    match key {
+        "chat.type.text" | "chat.type.emote" => ClassifiedMessage {
+            kind: MessageKind::Chat,
+            sender: arg(0),
+            ..Default::default()
+        },
+        "chat.type.announcement" => ClassifiedMessage {
+            kind: MessageKind::Broadcast,
+            sender: arg(0),
+            ..Default::default()
+        },
+        "multiplayer.player.joined" | "multiplayer.player.joined.renamed" => ClassifiedMessage {
+            kind: MessageKind::Join,
+            sender: arg(0),
+            ..Default::default()
+        },
+        "multiplayer.player.left" => ClassifiedMessage {
+            kind: MessageKind::Leave,
+            sender: arg(0),
+            ..Default::default()
+        },
+        _ if key.starts_with("chat.type.advancement.") => ClassifiedMessage {
+            kind: MessageKind::Advancement,
+            sender: arg(0),
+            advancement: translatable.args.get(1).map(advancement_title),
+            ..Default::default()
+        },
+        _ if key.starts_with("death.") => ClassifiedMessage {
+            kind: MessageKind::Death,
+            victim: arg(0),
+            // the second argument is whatever killed them, except for beds in
+            // the nether where it's a link to a bug report
+            killer: if key == "death.attack.badRespawnPoint.message" {
+                None
+            } else {
+                arg(1)
+            },
+            ..Default::default()
+        },
+        _ => ClassifiedMessage::default(),
+    }
+}
+
+/// The player in a plain text message that looks like `<player> text`.
+fn plain_chat_sender(message: &str) -> Option<&str> {
+    let (sender, _) = message.strip_prefix('<')?.split_once("> ")?;
+    // names can't be empty or have spaces in them
+    (!sender.is_empty() && !sender.contains(' ')).then_some(sender)
+}
+
+fn arg_to_string(arg: &StringOrComponent) -> String {
+    match arg {
+        StringOrComponent::String(text) => text.clone(),
+        StringOrComponent::Component(component) => component.to_string(),
+    }
+}
+
+/// Advancements are shown like `[Stone Age]`, so this takes the title out of
+/// the brackets.
+fn advancement_title(arg: &StringOrComponent) -> String {
+    if let StringOrComponent::Component(Component::Translatable(brackets)) = arg {
+        if brackets.key == "chat.square_brackets" {
+            if let Some(title) = brackets.args.first() {
+                return arg_to_string(title);
+            }
+        }
+    }
+    let title = arg_to_string(arg);
+    title
+        .strip_prefix('[')
+        .and_then(|title| title.strip_suffix(']'))
+        .map(str::to_owned)
+        .unwrap_or(title)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify_json(json: &str) -> ClassifiedMessage {
+        classify_component(&serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn chat() {
+        let classified = classify_json(r#"{"translate":"chat.type.text","with":["Notch","hi"]}"#);
+        assert_eq!(classified.kind, MessageKind::Chat);
+        assert_eq!(classified.sender.as_deref(), Some("Notch"));
+    }
+
+    #[test]
+    fn emote() {
+        let classified =
+            classify_json(r#"{"translate":"chat.type.emote","with":["Notch","waves"]}"#);
+        assert_eq!(classified.kind, MessageKind::Chat);
+        assert_eq!(classified.sender.as_deref(), Some("Notch"));
+    }
+
+    #[test]
+    fn announcement() {
+        let classified = classify_json(
+            r#"{"translate":"chat.type.announcement","with":["Server","restarting"]}"#,
+        );
+        assert_eq!(classified.kind, MessageKind::Broadcast);
+        assert_eq!(classified.sender.as_deref(), Some("Server"));
+    }
+
+    #[test]
+    fn join() {
+        for key in [
+            "multiplayer.player.joined",
+            "multiplayer.player.joined.renamed",
+        ] {
+            let classified = classify_json(&format!(
+                r#"{{"translate":"{key}","with":["Notch","OldName"]}}"#
+            ));
+            assert_eq!(classified.kind, MessageKind::Join, "{key}");
+            assert_eq!(classified.sender.as_deref(), Some("Notch"), "{key}");
+        }
+    }
+
+    #[test]
+    fn leave() {
+        let classified =
+            classify_json(r#"{"translate":"multiplayer.player.left","with":["Notch"]}"#);
+        assert_eq!(classified.kind, MessageKind::Leave);
+        assert_eq!(classified.sender.as_deref(), Some("Notch"));
+    }
+
+    #[test]
+    fn advancement() {
+        let classified = classify_json(
+            r#"{"translate":"chat.type.advancement.task","with":["Notch",{"translate":"chat.square_brackets","with":["Stone Age"]}]}"#,
+        );
+        assert_eq!(classified.kind, MessageKind::Advancement);
+        assert_eq!(classified.sender.as_deref(), Some("Notch"));
+        assert_eq!(classified.advancement.as_deref(), Some("Stone Age"));
+    }
+
+    #[test]
+    fn advancement_in_plain_brackets() {
+        let classified = classify_json(
+            r#"{"translate":"chat.type.advancement.goal","with":["Notch","[Cover Me in Debris]"]}"#,
+        );
+        assert_eq!(classified.kind, MessageKind::Advancement);
+        assert_eq!(
+            classified.advancement.as_deref(),
+            Some("Cover Me in Debris")
+        );
+    }
+
+    #[test]
+    fn death_by_player() {
+        let classified =
+            classify_json(r#"{"translate":"death.attack.player","with":["Notch","jeb_"]}"#);
+        assert_eq!(classified.kind, MessageKind::Death);
+        assert_eq!(classified.victim.as_deref(), Some("Notch"));
+        assert_eq!(classified.killer.as_deref(), Some("jeb_"));
+    }
+
+    #[test]
+    fn death_by_bed() {
+        let classified = classify_json(
+            r#"{"translate":"death.attack.badRespawnPoint.message","with":["Notch","[Intentional Game Design]"]}"#,
+        );
+        assert_eq!(classified.kind, MessageKind::Death);
+        assert_eq!(classified.victim.as_deref(), Some("Notch"));
+        assert_eq!(classified.killer, None);
+    }
+
+    #[test]
+    fn other_translations_are_system() {
+        let classified = classify_json(r#"{"translate":"commands.time.set","with":["1000"]}"#);
+        assert_eq!(classified.kind, MessageKind::System);
+        assert_eq!(classified.sender, None);
+    }
+
+    #[test]
+    fn plain_chat() {
+        let classified = classify_json(r#"{"text":"<Notch> hello there"}"#);
+        assert_eq!(classified.kind, MessageKind::Chat);
+        assert_eq!(classified.sender.as_deref(), Some("Notch"));
+    }
+
+    #[test]
+    fn plain_text_that_isnt_chat() {
+        for text in [
+            "Server restarting soon",
+            "<> hi",
+            "<not a name> hi",
+            "<Notch>hi",
+        ] {
+            let classified = classify_json(&format!(r#"{{"text":"{text}"}}"#));
+            assert_eq!(classified.kind, MessageKind::System, "{text}");
+            assert_eq!(classified.sender, None, "{text}");
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! An Azalea plugin that keeps track of who's online, based on the tab list.
+
+use std::{
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    time::{Duration, Instant},
+};
+
+use azalea::{
+    ecs::{
+        app::{App, Plugin},
+        event::{EventReader, EventWriter},
+        system::Query,
+    },
+    entity::Local,
+    packet_handling::{AddPlayerEvent, RemovePlayerEvent, UpdatePlayerEvent},
+    PlayerInfo,
+};
+use azalea_core::GameType;
+use bevy_ecs::{
+    entity::Entity,
+    query::With,
+    schedule::IntoSystemDescriptor,
+    system::{ResMut, Resource},
+};
+use uuid::Uuid;
+
+/// When we join a server we get sent everyone that's already online, so we
+/// don't send join events for players added this soon after the first one.
+const INITIAL_PLAYER_LIST_DURATION: Duration = Duration::from_secs(2);
+
+pub struct PlayerListPlugin;
+
+impl Plugin for PlayerListPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<PlayerJoined>()
+            .add_event::<PlayerLeft>()
+            .init_resource::<OnlinePlayers>()
+            .add_system(update_online_players)
+            .add_system(remove_disconnected_bots.after(update_online_players));
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct OnlinePlayer {
+    pub name: String,
+    pub uuid: Uuid,
+    /// The player's ping in milliseconds.
+    pub latency: i32,
+    pub gamemode: GameType,
+}
+
+impl From<&PlayerInfo> for OnlinePlayer {
+    fn from(info: &PlayerInfo) -> Self {
+        Self {
+            name: info.profile.name.clone(),
+            uuid: info.uuid,
+            latency: info.latency,
+            gamemode: info.gamemode,
+        }
+    }
+}
+
+/// The players in a bot's tab list.
+pub struct PlayerList {
+    pub players: HashMap<Uuid, OnlinePlayer>,
+    /// When the first player was added to the list, used to tell apart the
+    /// players that were already online from ones that joined.
+    pub created_at: Instant,
+}
+
+impl PlayerList {
+    /// The online players sorted by name.
+    pub fn sorted(&self) -> Vec<&OnlinePlayer> {
+        let mut players = self.players.values().collect::<Vec<_>>();
+        players.sort_by_key(|player| player.name.to_lowercase());
+        players
+    }
+}
+
+/// The players that each bot can see in its tab list.
+#[derive(Resource, Default)]
+pub struct OnlinePlayers(HashMap<Entity, PlayerList>);
+impl Deref for OnlinePlayers {
+    type Target = HashMap<Entity, PlayerList>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl DerefMut for OnlinePlayers {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// A player joined the server that the bot is on.
+pub struct PlayerJoined {
+    /// The bot that saw the player join.
+    pub entity: Entity,
+    pub player: OnlinePlayer,
+}
+
+/// A player left the server that the bot is on.
+pub struct PlayerLeft {
+    /// The bot that saw the player leave.
+    pub entity: Entity,
+    pub player: OnlinePlayer,
+}
+
+fn update_online_players(
+    mut online_players: ResMut<OnlinePlayers>,
+    mut add_player_events: EventReader<AddPlayerEvent>,
+    mut update_player_events: EventReader<UpdatePlayerEvent>,
+    mut remove_player_events: EventReader<RemovePlayerEvent>,
+    mut player_joined_events: EventWriter<PlayerJoined>,
+    mut player_left_events: EventWriter<PlayerLeft>,
+) {
+    for event in add_player_events.iter() {
+        let player_list = online_players
+            .entry(event.entity)
+            .or_insert_with(|| PlayerList {
+                players: HashMap::new(),
+                created_at: Instant::now(),
+            });
+        let player = OnlinePlayer::from(&event.info);
+        let already_online = player_list
+            .players
+            .insert(player.uuid, player.clone())
+            .is_some();
+        if !already_online && player_list.created_at.elapsed() > INITIAL_PLAYER_LIST_DURATION {
+            player_joined_events.send(PlayerJoined {
+                entity: event.entity,
+                player,
+            });
+        }
+    }
+    for event in update_player_events.iter() {
+        let Some(player_list) = online_players.get_mut(&event.entity) else {
+            continue;
+        };
+        if let Some(player) = player_list.players.get_mut(&event.info.uuid) {
+            *player = OnlinePlayer::from(&event.info);
+        }
+    }
+    for event in remove_player_events.iter() {
+        let Some(player_list) = online_players.get_mut(&event.entity) else {
+            continue;
+        };
+        if let Some(player) = player_list.players.remove(&event.info.uuid) {
+            player_left_events.send(PlayerLeft {
+                entity: event.entity,
+                player,
+            });
+        }
+    }
+}
+
+/// Forget the player lists of bots that aren't on the server anymore.
+fn remove_disconnected_bots(
+    mut online_players: ResMut<OnlinePlayers>,
+    query: Query<Entity, With<Local>>,
+) {
+    online_players.retain(|&entity, _| query.contains(entity));
+}
@@ -0,0 +1,434 @@
+//! Keep track of when players are online, so people can ask when someone was
+//! last on with `!seen` and how long they've played with `!playtime`.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use azalea::{
+    ecs::{
+        app::{App, Plugin},
+        event::{EventReader, EventWriter},
+        system::Query,
+    },
+    entity::Local,
+    GameProfileComponent,
+};
+use bevy_ecs::{
+    entity::Entity,
+    query::With,
+    system::{Res, ResMut, Resource},
+};
+use log::{error, info};
+use rusqlite::{params, Connection, OptionalExtension};
+use uuid::Uuid;
+
+use crate::{
+    azalea_archive::{format_utc, now_millis, parse_duration},
+    azalea_avoid_chat_kick::{ChatPriority, SendChatEvent},
+    azalea_bridge::{is_whisper, WhisperFromMinecraftEvent},
+    azalea_discord_bridge::DiscordBridge,
+    azalea_discord_markdown::escape_markdown,
+    azalea_player_list::{OnlinePlayer, OnlinePlayers, PlayerListPlugin},
+    bevy_discord,
+    bevy_storage::Storage,
+};
+
+/// Records when players join and leave, and answers `!seen` and `!playtime` in
+/// Minecraft and in bridged Discord channels. This needs
+/// [`StoragePlugin`](crate::bevy_storage::StoragePlugin) and should be added
+/// after [`DiscordBridgePlugin`](crate::azalea_discord_bridge::DiscordBridgePlugin).
+pub struct PlaytimePlugin {
+    /// The address of the server, since every server shares the same database.
+    pub server: String,
+}
+
+impl Plugin for PlaytimePlugin {
+    fn build(&self, app: &mut App) {
+        if !app.world.contains_resource::<OnlinePlayers>() {
+            app.add_plugin(PlayerListPlugin);
+        }
+        app.insert_resource(PlayerSessions {
+            server: self.server.clone(),
+            open: None,
+            last_heartbeat: Instant::now(),
+        })
+        .add_system(track_sessions)
+        .add_system(playtime_commands_from_minecraft)
+        .add_system(playtime_commands_from_discord);
+    }
+}
+
+/// How often the sessions of online players are marked as still going. If the
+/// bot stops without ending them, they end when they were last marked.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Resource)]
+pub struct PlayerSessions {
+    pub server: String,
+    /// The session row of every player that's online. This is None until the
+    /// sessions left over from the last time the bot ran have been ended.
+    open: Option<HashMap<Uuid, i64>>,
+    last_heartbeat: Instant,
+}
+
+/// The most recent time a player was online.
+pub struct LastSession {
+    /// The player's name during the session.
+    pub name: String,
+    /// Unix time in milliseconds.
+    pub started_at: u64,
+    /// None if the player is still online.
+    pub ended_at: Option<u64>,
+}
+
+impl PlayerSessions {
+    /// The most recent session of whoever last played with this name, and
+    /// their UUID. Names can change, so this finds the UUID first.
+    fn latest_session(
+        &self,
+        connection: &Connection,
+        name: &str,
+    ) -> rusqlite::Result<Option<(String, LastSession)>> {
+        connection
+            .query_row(
+                "SELECT uuid, name, started_at, ended_at FROM player_sessions
+                WHERE server = ?1 AND uuid = (
+                    SELECT uuid FROM player_sessions
+                    WHERE server = ?1 AND name = ?2
+                    ORDER BY started_at DESC
+                    LIMIT 1
+                )
+                ORDER BY started_at DESC
+                LIMIT 1",
+                params![self.server, name],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        LastSession {
+                            name: row.get(1)?,
+                            started_at: row.get(2)?,
+                            ended_at: row.get(3)?,
+                        },
+                    ))
+                },
+            )
+            .optional()
+    }
+
+    pub fn last_session(
+        &self,
+        storage: &Storage,
+        name: &str,
+    ) -> rusqlite::Result<Option<LastSession>> {
+        let session = storage.with(|connection| self.latest_session(connection, name))?;
+        Ok(session.map(|(_, session)| session))
+    }
+
+    /// How long the player has been online in total since `since` (unix time
+    /// in milliseconds), and their latest name.
+    pub fn playtime(
+        &self,
+        storage: &Storage,
+        name: &str,
+        since: Option<u64>,
+    ) -> rusqlite::Result<Option<(String, Duration)>> {
+        let now = now_millis();
+        let since = since.unwrap_or(0);
+        storage.with(|connection| {
+            let Some((uuid, latest)) = self.latest_session(connection, name)? else {
+                return Ok(None);
+            };
+            let mut statement = connection.prepare(
+                "SELECT started_at, COALESCE(ended_at, ?3) FROM player_sessions
+                WHERE server = ?1 AND uuid = ?2 AND COALESCE(ended_at, ?3) > ?4",
+            )?;
+            let sessions = statement.query_map(params![self.server, uuid, now, since], |row| {
+                Ok((row.get::<_, u64>(0)?, row.get::<_, u64>(1)?))
+            })?;
+            let mut total = 0;
+            for session in sessions {
+                // only count the part of the session that's in the period
+                let (started_at, ended_at) = session?;
+                total += ended_at.saturating_sub(started_at.max(since));
+            }
+            Ok(Some((latest.name, Duration::from_millis(total))))
+        })
+    }
+}
+
+/// Start and end sessions as players show up in and disappear from the bots'
+/// tab lists. When every bot disconnects the tab lists are gone, so everyone's
+/// session ends then.
+fn track_sessions(
+    mut sessions: ResMut<PlayerSessions>,
+    storage: Res<Storage>,
+    online_players: Res<OnlinePlayers>,
+) {
+    let sessions = &mut *sessions;
+    let now = now_millis();
+    if sessions.open.is_none() {
+        // the bot stopped without ending these, so they end when we last saw
+        // the player
+        let result = storage.with(|connection| {
+            connection.execute(
+                "UPDATE player_sessions SET ended_at = last_seen_at
+                WHERE server = ?1 AND ended_at IS NULL",
+                [&sessions.server],
+            )
+        });
+        match result {
+            Ok(0) => {}
+            Ok(ended) => info!("ended {ended} sessions left over from last time"),
+            Err(err) => {
+                error!("couldn't end old sessions: {err}");
+                return;
+            }
+        }
+        sessions.open = Some(HashMap::new());
+    }
+    let Some(open) = &mut sessions.open else {
+        return;
+    };
+
+    // everyone that any of the bots can see
+    let mut online = HashMap::<Uuid, &OnlinePlayer>::new();
+    for player_list in online_players.values() {
+        for player in player_list.players.values() {
+            online.insert(player.uuid, player);
+        }
+    }
+    let joined = online
+        .values()
+        .filter(|player| !open.contains_key(&player.uuid))
+        .collect::<Vec<_>>();
+    let left = open
+        .iter()
+        .filter(|(uuid, _)| !online.contains_key(uuid))
+        .map(|(&uuid, &id)| (uuid, id))
+        .collect::<Vec<_>>();
+    let heartbeat = sessions.last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL;
+    if joined.is_empty() && left.is_empty() && !heartbeat {
+        return;
+    }
+
+    let server = &sessions.server;
+    let result = storage.transaction(|transaction| {
+        let mut started = Vec::new();
+        for player in &joined {
+            transaction.execute(
+                "INSERT INTO player_sessions (server, uuid, name, started_at, last_seen_at)
+                VALUES (?1, ?2, ?3, ?4, ?4)",
+                params![server, player.uuid.to_string(), player.name, now],
+            )?;
+            started.push((player.uuid, transaction.last_insert_rowid()));
+        }
+        for (_, id) in &left {
+            transaction.execute(
+                "UPDATE player_sessions SET ended_at = ?1, last_seen_at = ?1 WHERE id = ?2",
+                params![now, id],
+            )?;
+        }
+        if heartbeat {
+            transaction.execute(
+                "UPDATE player_sessions SET last_seen_at = ?1
+                WHERE server = ?2 AND ended_at IS NULL",
+                params![now, server],
+            )?;
+        }
+        Ok(started)
+    });
+    // only change what we think is open if it was saved, so it's tried again
+    // otherwise
+    match result {
+        Ok(started) => {
+            open.extend(started);
+            for (uuid, _) in left {
+                open.remove(&uuid);
+            }
+            if heartbeat {
+                sessions.last_heartbeat = Instant::now();
+            }
+        }
+        Err(err) => error!("couldn't save player sessions: {err}"),
+    }
+}
+
+/// Whether a message is `!seen` or `!playtime`, so it shouldn't be treated like
+/// a normal whisper.
+pub fn is_playtime_command(message: &str) -> bool {
+    matches!(
+        command_name(message).as_deref(),
+        Some("!seen" | "!playtime")
+    )
+}
+
+fn command_name(message: &str) -> Option<String> {
+    message
+        .split_whitespace()
+        .next()
+        .map(|name| name.to_ascii_lowercase())
+}
+
+/// Answer `!seen <player>` or `!playtime <player> [period]`. This returns None
+/// if the message isn't one of them.
+fn run_command(sessions: &PlayerSessions, storage: &Storage, message: &str) -> Option<String> {
+    let mut args = message.split_whitespace().skip(1);
+    let reply = match command_name(message)?.as_str() {
+        "!seen" => match args.next() {
+            Some(player) => seen(sessions, storage, player),
+            None => "Usage: !seen <player>".to_string(),
+        },
+        "!playtime" => match (args.next(), args.next()) {
+            (Some(player), None) => playtime(sessions, storage, player, None),
+            (Some(player), Some(period)) => match parse_duration(period) {
+                Some(duration) => playtime(sessions, storage, player, Some((period, duration))),
+                None => "The period should look like 12h, 7d or 4w.".to_string(),
+            },
+            (None, _) => "Usage: !playtime <player> [period, like 7d]".to_string(),
+        },
+        _ => return None,
+    };
+    Some(reply)
+}
+
+fn seen(sessions: &PlayerSessions, storage: &Storage, player: &str) -> String {
+    let now = now_millis();
+    match sessions.last_session(storage, player) {
+        Ok(Some(session)) => match session.ended_at {
+            None => format!(
+                "{} is online now, and has been for {}.",
+                session.name,
+                format_duration(Duration::from_millis(
+                    now.saturating_sub(session.started_at)
+                ))
+            ),
+            Some(ended_at) => format!(
+                "{} was last seen {} ago, at {} UTC.",
+                session.name,
+                format_duration(Duration::from_millis(now.saturating_sub(ended_at))),
+                format_utc(ended_at)
+            ),
+        },
+        Ok(None) => format!("I haven't seen {player} yet."),
+        Err(err) => {
+            error!("couldn't look up when {player} was last seen: {err}");
+            "Couldn't look that up.".to_string()
+        }
+    }
+}
+
+fn playtime(
+    sessions: &PlayerSessions,
+    storage: &Storage,
+    player: &str,
+    period: Option<(&str, Duration)>,
+) -> String {
+    let since =
+        period.map(|(_, duration)| now_millis().saturating_sub(duration.as_millis() as u64));
+    match sessions.playtime(storage, player, since) {
+        Ok(Some((name, total))) => match period {
+            Some((period, _)) => format!(
+                "{name} has played for {} in the last {period}.",
+                format_duration(total)
+            ),
+            None => format!("{name} has played for {} in total.", format_duration(total)),
+        },
+        Ok(None) => format!("I haven't seen {player} yet."),
+        Err(err) => {
+            error!("couldn't look up the playtime of {player}: {err}");
+            "Couldn't look that up.".to_string()
+        }
+    }
+}
+
+/// Format a duration like `3d 4h`, `2h 5m` or `5m`.
+fn format_duration(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    if days > 0 {
+        format!("{days}d {hours}h")
+    } else if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else if minutes > 0 {
+        format!("{minutes}m")
+    } else {
+        "less than a minute".to_string()
+    }
+}
+
+fn playtime_commands_from_minecraft(
+    sessions: Res<PlayerSessions>,
+    storage: Res<Storage>,
+    mut chat_events: EventReader<azalea::chat::ChatReceivedEvent>,
+    mut whisper_events: EventReader<WhisperFromMinecraftEvent>,
+    mut send_chat_events: EventWriter<SendChatEvent>,
+    query: Query<(Entity, &GameProfileComponent), With<Local>>,
+) {
+    // every bot sees the same chat, so only one of them answers
+    let responder = query
+        .iter()
+        .min_by(|(_, a), (_, b)| a.name.cmp(&b.name))
+        .map(|(entity, _)| entity);
+
+    let mut replies = Vec::new();
+    for event in chat_events.iter() {
+        if Some(event.entity) != responder || is_whisper(&event.packet) {
+            continue;
+        }
+        let (Some(sender), content) = event.packet.split_sender_and_content() else {
+            continue;
+        };
+        // commands from Discord get relayed by the bots, but they're answered
+        // in Discord
+        if query
+            .iter()
+            .any(|(_, game_profile)| game_profile.name == sender)
+        {
+            continue;
+        }
+        if let Some(reply) = run_command(&sessions, &storage, &content) {
+            replies.push((event.entity, reply));
+        }
+    }
+    for event in whisper_events.iter() {
+        if let Some(reply) = run_command(&sessions, &storage, &event.content) {
+            replies.push((event.entity, format!("/msg {} {reply}", event.sender)));
+        }
+    }
+
+    for (entity, reply) in replies {
+        let Some(chat_event) = SendChatEvent::new(entity, &reply) else {
+            continue;
+        };
+        send_chat_events.send(chat_event.with_priority(ChatPriority::High));
+    }
+}
+
+fn playtime_commands_from_discord(
+    sessions: Res<PlayerSessions>,
+    storage: Res<Storage>,
+    discord_bridge: Res<DiscordBridge>,
+    mut events: EventReader<bevy_discord::recv::MessageCreate>,
+    mut create_message_events: EventWriter<bevy_discord::send::CreateMessage>,
+) {
+    for event in events.iter() {
+        if event.author.bot {
+            continue;
+        }
+        let channel_id = event.channel_id.get();
+        // every server gets the message, so only the one that answers commands
+        // in the channel does
+        if !discord_bridge.answers_commands_in(channel_id) {
+            continue;
+        }
+        let Some(reply) = run_command(&sessions, &storage, &event.content) else {
+            continue;
+        };
+        create_message_events.send(bevy_discord::send::CreateMessage {
+            channel_id,
+            content: escape_markdown(&reply),
+        });
+    }
+}
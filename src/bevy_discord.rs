@@ -1,6 +1,11 @@
 //! A Bevy plugin for controlling a Discord bot.
 
-use std::{num::NonZeroU64, sync::Arc};
+use std::{
+    collections::HashMap,
+    num::NonZeroU64,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use async_compat::Compat;
 use bevy_app::{App, Plugin};
@@ -13,134 +18,458 @@ use bevy_ecs::{
 use bevy_tasks::{IoTaskPool, Task};
 use futures_lite::future;
 use log::{error, warn};
-use tokio::sync::mpsc;
+use parking_lot::Mutex;
+use tokio::sync::broadcast::{self, error::TryRecvError};
 use twilight_cache_inmemory::{InMemoryCache, ResourceType};
 pub use twilight_gateway::Intents;
-use twilight_gateway::{error::ReceiveMessageError, Event, Shard, ShardId};
+use twilight_gateway::{Event, MessageSender, Shard, ShardId};
 use twilight_http::{
-    request::channel::reaction::RequestReactionType, response::marker::EmptyBody,
+    request::channel::reaction::RequestReactionType,
+    response::marker::{EmptyBody, ListBody},
     Client as HttpClient, Response,
 };
-use twilight_model::channel::{message::AllowedMentions, Message};
-use twilight_validate::message::MessageValidationError;
+use twilight_model::{
+    application::command::Command,
+    channel::{
+        message::{AllowedMentions, MessageFlags},
+        Message,
+    },
+    gateway::{
+        payload::outgoing::UpdatePresence as UpdatePresenceCommand,
+        presence::{ActivityType, MinimalActivity},
+    },
+    http::{
+        attachment::Attachment,
+        interaction::{InteractionResponse, InteractionResponseData, InteractionResponseType},
+    },
+    id::{marker::ApplicationMarker, Id},
+};
+use twilight_validate::{
+    message::MessageValidationError, request::webhook_username as validate_webhook_username,
+};
 
 pub mod recv {
     pub use twilight_gateway::Event;
-    pub use twilight_model::gateway::payload::incoming::MessageCreate;
+    pub use twilight_model::gateway::payload::incoming::{
+        InteractionCreate, MessageCreate, MessageDelete, MessageUpdate,
+    };
 }
 pub mod send {
+    pub use twilight_model::gateway::presence::Status;
+
     #[derive(Debug)]
     pub struct CreateMessage {
         pub channel_id: u64,
         pub content: String,
     }
+    /// Send a message in the DMs of a user.
+    #[derive(Debug)]
+    pub struct CreateDirectMessage {
+        pub user_id: u64,
+        pub content: String,
+    }
     #[derive(Debug)]
     pub struct CreateReaction {
         pub channel_id: u64,
         pub message_id: u64,
         pub emoji: char,
     }
+    /// Remove a reaction that the bot added.
+    #[derive(Debug)]
+    pub struct DeleteReaction {
+        pub channel_id: u64,
+        pub message_id: u64,
+        pub emoji: char,
+    }
+    #[derive(Clone, Debug)]
+    pub struct ExecuteWebhook {
+        pub webhook_id: u64,
+        pub token: String,
+        /// Override the webhook's name. If Discord doesn't allow the name, the
+        /// webhook's default name is used instead.
+        pub username: Option<String>,
+        /// Override the webhook's avatar.
+        pub avatar_url: Option<String>,
+        pub content: String,
+    }
+    /// Respond to an interaction. This has to be done within 3 seconds of
+    /// receiving it, so defer if it'll take longer than that and then use
+    /// [`UpdateInteractionResponse`].
+    #[derive(Debug)]
+    pub struct CreateInteractionResponse {
+        pub application_id: u64,
+        pub interaction_id: u64,
+        pub token: String,
+        pub kind: InteractionResponseKind,
+    }
+    #[derive(Debug)]
+    pub enum InteractionResponseKind {
+        Message(String),
+        /// A message that only the person who used the command can see.
+        EphemeralMessage(String),
+        /// Show that the bot is thinking, so we can respond later.
+        Defer,
+    }
+    /// Edit the response to an interaction, usually after deferring it. This
+    /// waits until Discord has the response, so it can be sent right after
+    /// [`CreateInteractionResponse`].
+    #[derive(Debug)]
+    pub struct UpdateInteractionResponse {
+        pub application_id: u64,
+        pub token: String,
+        pub content: String,
+        /// A file to attach, for things that are too long to fit in a message.
+        pub attachment: Option<FileAttachment>,
+    }
+    #[derive(Debug)]
+    pub struct FileAttachment {
+        pub filename: String,
+        pub data: Vec<u8>,
+    }
+    /// Set the bot's status and what it's playing. This is sent again when
+    /// the bot reconnects, so it only has to be sent when it changes. Discord
+    /// only allows a few of these a minute.
+    #[derive(Debug)]
+    pub struct UpdatePresence {
+        pub status: Status,
+        pub playing: String,
+    }
+    /// Change a channel's topic. Discord only allows this twice every 10
+    /// minutes for each channel.
+    #[derive(Debug)]
+    pub struct UpdateChannelTopic {
+        pub channel_id: u64,
+        pub topic: String,
+    }
+}
+
+/// The application commands that are registered when the bot starts. Other
+/// plugins can add to this when they're built.
+#[derive(Resource, Default)]
+pub struct ApplicationCommands(pub Vec<Command>);
+
+/// The connection to Discord. This is shared between every app that has a
+/// [`DiscordPlugin`], so the bot only has one gateway session no matter how
+/// many servers it's bridging.
+#[derive(Clone)]
+pub struct DiscordClient {
+    pub http: Arc<HttpClient>,
+    pub cache: Arc<InMemoryCache>,
+    events: broadcast::Sender<Event>,
+    gateway: Arc<Mutex<GatewayState>>,
+}
+
+struct GatewayState {
+    /// Sends gateway commands to the shard, since the shard itself is moved
+    /// into the task that receives events.
+    sender: MessageSender,
+    /// Whether the shard is identified, since commands sent before then would
+    /// get us disconnected.
+    ready: bool,
+    /// We only know this once we're ready.
+    application_id: Option<Id<ApplicationMarker>>,
+    /// The last presence we were asked to set, which is set again whenever we
+    /// identify.
+    presence: Option<UpdatePresenceCommand>,
+}
+
+/// How many events each app can fall behind by before it starts missing them.
+const EVENT_BUFFER: usize = 1024;
+/// How long to wait before reconnecting after the gateway closes for good. This
+/// doubles every time it fails again, up to [`MAX_RECONNECT_DELAY`].
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+
+impl DiscordClient {
+    /// Connect to Discord. This has to be called from inside the tokio runtime,
+    /// since that's where the gateway runs.
+    pub fn connect(token: String, intents: Intents, api_proxy: Option<String>) -> Self {
+        let shard = Shard::new(ShardId::ONE, token.clone(), intents);
+        let sender = shard.sender();
+        let mut http = HttpClient::builder().token(token.clone());
+        if let Some(api_proxy) = api_proxy {
+            http = http.proxy(api_proxy, true);
+        }
+        let http = Arc::new(http.build());
+        // channels, roles, members and emoji are cached so mentions of them can
+        // be shown by name
+        let cache = InMemoryCache::builder()
+            .resource_types(
+                ResourceType::MESSAGE
+                    | ResourceType::CHANNEL
+                    | ResourceType::ROLE
+                    | ResourceType::MEMBER
+                    | ResourceType::USER
+                    | ResourceType::EMOJI,
+            )
+            .build();
+
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+
+        let client = DiscordClient {
+            http,
+            cache: Arc::new(cache),
+            events,
+            gateway: Arc::new(Mutex::new(GatewayState {
+                sender,
+                ready: false,
+                application_id: None,
+                presence: None,
+            })),
+        };
+        // we do it like this because it has to run in the tokio runtime and
+        // async_compat doesn't work for next_event
+        tokio::spawn(client.clone().loop_get_next_events(shard, token, intents));
+        client
+    }
+
+    async fn loop_get_next_events(self, mut shard: Shard, token: String, intents: Intents) {
+        let mut reconnect_delay = RECONNECT_DELAY;
+        loop {
+            let event = match shard.next_event().await {
+                Ok(event) => event,
+                Err(source) => {
+                    if !source.is_fatal() {
+                        warn!("error receiving event {source}");
+                        continue;
+                    }
+                    // every app shares this shard, so we can't just give up
+                    // and wait for a swarm to restart us
+                    error!(
+                        "fatal error receiving event {source}, reconnecting in {}s",
+                        reconnect_delay.as_secs()
+                    );
+                    self.gateway.lock().ready = false;
+                    tokio::time::sleep(reconnect_delay).await;
+                    reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+                    shard = Shard::new(ShardId::ONE, token.clone(), intents);
+                    self.gateway.lock().sender = shard.sender();
+                    continue;
+                }
+            };
+            self.cache.update(&event);
+            {
+                let mut gateway = self.gateway.lock();
+                match &event {
+                    Event::Ready(ready) => {
+                        reconnect_delay = RECONNECT_DELAY;
+                        // identifying resets the presence
+                        gateway.ready = true;
+                        gateway.application_id = Some(ready.application.id);
+                        gateway.send_presence();
+                    }
+                    Event::Resumed => gateway.ready = true,
+                    Event::GatewayClose(_) => gateway.ready = false,
+                    _ => {}
+                }
+            }
+            // this only fails if no app is listening, like while every swarm
+            // is reconnecting, and then nobody needs the event anyway
+            let _ = self.events.send(event);
+        }
+    }
+
+    fn application_id(&self) -> Option<Id<ApplicationMarker>> {
+        self.gateway.lock().application_id
+    }
+
+    fn set_presence(&self, presence: UpdatePresenceCommand) {
+        let mut gateway = self.gateway.lock();
+        gateway.presence = Some(presence);
+        if gateway.ready {
+            gateway.send_presence();
+        }
+    }
+}
+
+impl GatewayState {
+    fn send_presence(&self) {
+        let Some(presence) = &self.presence else {
+            return;
+        };
+        if let Err(err) = self.sender.command(presence) {
+            warn!("couldn't update presence {err}");
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct DiscordPlugin {
-    pub token: String,
-    pub intents: Intents,
+    pub client: DiscordClient,
+    /// Whether this app registers the [`ApplicationCommands`]. Commands are
+    /// global, so only one of the apps sharing a client should do it.
+    pub register_commands: bool,
 }
 impl Plugin for DiscordPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<recv::MessageCreate>()
+            .add_event::<recv::MessageUpdate>()
+            .add_event::<recv::MessageDelete>()
+            .add_event::<recv::InteractionCreate>()
             .add_event::<send::CreateMessage>()
+            .add_event::<send::CreateDirectMessage>()
             .add_event::<send::CreateReaction>()
+            .add_event::<send::DeleteReaction>()
+            .add_event::<send::ExecuteWebhook>()
+            .add_event::<send::CreateInteractionResponse>()
+            .add_event::<send::UpdateInteractionResponse>()
+            .add_event::<send::UpdatePresence>()
+            .add_event::<send::UpdateChannelTopic>()
+            .init_resource::<ApplicationCommands>()
             .add_system(handle_from_discord_events)
             .add_system(handle_create_message)
             .add_system(handle_create_message_response)
+            .add_system(handle_create_direct_message)
+            .add_system(handle_direct_message_task)
             .add_system(handle_create_reaction)
-            .add_system(handle_empty_body_response);
+            .add_system(handle_delete_reaction)
+            .add_system(handle_execute_webhook)
+            .add_system(handle_create_interaction_response)
+            .init_resource::<InteractionResponses>()
+            .add_system(
+                handle_update_interaction_response.after(handle_create_interaction_response),
+            )
+            .add_system(handle_update_presence)
+            .add_system(handle_update_channel_topic)
+            .add_system(handle_channel_topic_task)
+            .add_system(handle_empty_body_response)
+            .add_system(handle_register_commands_response);
 
-        app.insert_resource(Discord::new(self.token.clone(), self.intents));
+        app.insert_resource(Discord::new(self.client.clone(), self.register_commands));
     }
 }
 
 impl Discord {
-    pub fn new(token: String, intents: Intents) -> Self {
-        let shard = Shard::new(ShardId::ONE, token.clone(), intents);
-        let http = Arc::new(HttpClient::new(token));
-        let cache = InMemoryCache::builder()
-            .resource_types(ResourceType::MESSAGE)
-            .build();
-
-        let (tx, rx) = mpsc::unbounded_channel();
-
+    pub fn new(client: DiscordClient, register_commands: bool) -> Self {
         Discord {
-            http,
-            cache,
-            rx,
-
-            shard: Some(shard),
-            task: None,
-            tx: Some(tx),
+            http: client.http.clone(),
+            cache: client.cache.clone(),
+            rx: client.events.subscribe(),
+            client,
+            register_commands,
+            registered_commands: false,
         }
     }
 }
 
 #[derive(Resource)]
-struct Discord {
+pub struct Discord {
     pub http: Arc<HttpClient>,
-    pub cache: InMemoryCache,
-    rx: mpsc::UnboundedReceiver<Result<Event, ReceiveMessageError>>,
+    pub cache: Arc<InMemoryCache>,
+    rx: broadcast::Receiver<Event>,
+    client: DiscordClient,
 
-    shard: Option<Shard>,
-    task: Option<Task<()>>,
-    tx: Option<mpsc::UnboundedSender<Result<Event, ReceiveMessageError>>>,
-}
-
-async fn loop_get_next_events(
-    mut shard: Shard,
-    tx: mpsc::UnboundedSender<Result<Event, ReceiveMessageError>>,
-) {
-    loop {
-        // we do it like this because it has to run in the tokio runtime and
-        // async_compat doesn't work for next_event
-        let event = shard.next_event().await;
-        if tx.send(event).is_err() {
-            println!("couldn't send event to discord (probably because the receiver was dropped)");
-            return;
-        }
-    }
+    register_commands: bool,
+    registered_commands: bool,
 }
 
 fn handle_from_discord_events(
+    mut commands: Commands,
     mut discord: ResMut<Discord>,
+    application_commands: Res<ApplicationCommands>,
     mut message_create_events: EventWriter<recv::MessageCreate>,
+    mut message_update_events: EventWriter<recv::MessageUpdate>,
+    mut message_delete_events: EventWriter<recv::MessageDelete>,
+    mut interaction_create_events: EventWriter<recv::InteractionCreate>,
 ) {
-    let pool = IoTaskPool::get();
-    if discord.task.is_none() {
-        discord.task = Some(pool.spawn(Compat::new(loop_get_next_events(
-            discord.shard.take().unwrap(),
-            discord.tx.take().unwrap(),
-        ))));
-    }
-    let mut discord_task = discord.task.as_mut().unwrap();
-    future::block_on(future::poll_once(&mut discord_task));
-    while let Ok(event) = discord.rx.try_recv() {
-        let event = match event {
+    loop {
+        let event = match discord.rx.try_recv() {
             Ok(event) => event,
-            Err(source) => {
-                if source.is_fatal() {
-                    error!("fatal error receiving event {source}");
-                    continue;
-                }
-                warn!("error receiving event {source}");
+            Err(TryRecvError::Lagged(skipped)) => {
+                warn!("fell behind and missed {skipped} Discord events");
                 continue;
             }
+            Err(TryRecvError::Empty | TryRecvError::Closed) => break,
         };
-        discord.cache.update(&event);
         match event {
             recv::Event::MessageCreate(m) => message_create_events.send(*m),
+            recv::Event::MessageUpdate(m) => message_update_events.send(*m),
+            recv::Event::MessageDelete(m) => message_delete_events.send(m),
+            recv::Event::InteractionCreate(i) => interaction_create_events.send(*i),
             _ => {}
         }
     }
+
+    // we only know the application id once we're ready, so this is when we
+    // register the commands
+    if discord.register_commands && !discord.registered_commands {
+        let Some(application_id) = discord.client.application_id() else {
+            return;
+        };
+        discord.registered_commands = true;
+        let http = discord.http.clone();
+        let application_commands = application_commands.0.clone();
+        let task = IoTaskPool::get().spawn(Compat::new(async move {
+            Ok(http
+                .interaction(application_id)
+                .set_global_commands(&application_commands)
+                .await)
+        }));
+        commands.spawn(DiscordResponseTask(task));
+    }
+}
+
+fn handle_update_presence(discord: Res<Discord>, mut events: EventReader<send::UpdatePresence>) {
+    // only the newest one matters
+    let Some(event) = events.iter().last() else {
+        return;
+    };
+    let activity = MinimalActivity {
+        kind: ActivityType::Playing,
+        name: event.playing.clone(),
+        url: None,
+    };
+    match UpdatePresenceCommand::new(vec![activity.into()], false, None, event.status) {
+        Ok(presence) => discord.client.set_presence(presence),
+        Err(err) => warn!("couldn't make presence {err}"),
+    }
+}
+
+/// Updating a channel's topic fails with a different validation error than
+/// messages, so the task handles the response itself.
+#[derive(Component)]
+pub struct ChannelTopicTask(Task<()>);
+
+fn handle_update_channel_topic(
+    mut commands: Commands,
+    discord: Res<Discord>,
+    mut events: EventReader<send::UpdateChannelTopic>,
+) {
+    let task_pool = IoTaskPool::get();
+
+    for event in events.iter() {
+        let channel_id = event.channel_id;
+        let topic = event.topic.clone();
+
+        let http = discord.http.clone();
+
+        let task = task_pool.spawn(Compat::new(async move {
+            let request = match http
+                .update_channel(NonZeroU64::try_from(channel_id).unwrap().into())
+                .topic(&topic)
+            {
+                Ok(request) => request,
+                Err(err) => {
+                    warn!("couldn't update the topic of {channel_id} {err}");
+                    return;
+                }
+            };
+            if let Err(err) = request.await {
+                warn!("couldn't update the topic of {channel_id} {err}");
+            }
+        }));
+        commands.spawn(ChannelTopicTask(task));
+    }
+}
+fn handle_channel_topic_task(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut ChannelTopicTask)>,
+) {
+    for (entity, mut task) in &mut query {
+        if future::block_on(future::poll_once(&mut task.0)).is_some() {
+            commands.entity(entity).remove::<ChannelTopicTask>();
+        }
+    }
 }
 
 #[derive(Component)]
@@ -174,12 +503,123 @@ fn handle_create_message(
         commands.spawn(DiscordResponseTask(task));
     }
 }
+/// Sending a DM takes two requests, so the task handles the responses itself.
+#[derive(Component)]
+pub struct DirectMessageTask(Task<()>);
+
+fn handle_create_direct_message(
+    mut commands: Commands,
+    discord: Res<Discord>,
+    mut events: EventReader<send::CreateDirectMessage>,
+) {
+    let task_pool = IoTaskPool::get();
+
+    for event in events.iter() {
+        let content = event.content.clone();
+        let user_id = event.user_id;
+
+        let http = discord.http.clone();
+
+        let task = task_pool.spawn(Compat::new(async move {
+            let channel = match http
+                .create_private_channel(NonZeroU64::try_from(user_id).unwrap().into())
+                .await
+            {
+                Ok(response) => response.model().await,
+                Err(err) => {
+                    warn!("couldn't open DMs with {user_id} {err}");
+                    return;
+                }
+            };
+            let channel = match channel {
+                Ok(channel) => channel,
+                Err(err) => {
+                    warn!("couldn't open DMs with {user_id} {err}");
+                    return;
+                }
+            };
+            let created_message = http
+                .create_message(channel.id)
+                .allowed_mentions(Some(&AllowedMentions::default()))
+                .content(&content);
+            match created_message {
+                Ok(created_message) => {
+                    if let Err(err) = created_message.await {
+                        warn!("couldn't send DM to {user_id} {err}");
+                    }
+                }
+                Err(err) => warn!("couldn't send DM to {user_id} {err}"),
+            }
+        }));
+        commands.spawn(DirectMessageTask(task));
+    }
+}
+fn handle_direct_message_task(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut DirectMessageTask)>,
+) {
+    for (entity, mut task) in &mut query {
+        if future::block_on(future::poll_once(&mut task.0)).is_some() {
+            commands.entity(entity).remove::<DirectMessageTask>();
+        }
+    }
+}
+
+fn handle_execute_webhook(
+    mut commands: Commands,
+    discord: Res<Discord>,
+    mut events: EventReader<send::ExecuteWebhook>,
+) {
+    let task_pool = IoTaskPool::get();
+
+    for event in events.iter() {
+        let mut event = event.clone();
+        event.username = event.username.filter(|username| {
+            let valid = validate_webhook_username(username).is_ok();
+            if !valid {
+                warn!("Discord doesn't allow {username:?} as a webhook username");
+            }
+            valid
+        });
+
+        let task = task_pool.spawn(Compat::new(execute_webhook(discord.http.clone(), event)));
+        commands.spawn(DiscordResponseTask(task));
+    }
+}
+
+/// Send a message with a webhook. The username has to be valid already.
+pub(crate) async fn execute_webhook(
+    http: Arc<HttpClient>,
+    event: send::ExecuteWebhook,
+) -> Result<Result<Response<EmptyBody>, twilight_http::Error>, MessageValidationError> {
+    let mut request = http
+        .execute_webhook(
+            NonZeroU64::try_from(event.webhook_id).unwrap().into(),
+            &event.token,
+        )
+        .allowed_mentions(Some(&AllowedMentions::default()));
+    if let Some(username) = &event.username {
+        request = request
+            .username(username)
+            .expect("we already checked that the username is valid");
+    }
+    if let Some(avatar_url) = &event.avatar_url {
+        request = request.avatar_url(avatar_url);
+    }
+    match request.content(&event.content) {
+        Ok(executed_webhook) => Ok(executed_webhook.await),
+        Err(e) => Err(e),
+    }
+}
+
 fn handle_create_message_response(
     mut commands: Commands,
     mut query: Query<(Entity, &mut DiscordResponseTask<Message>)>,
 ) {
     for (entity, mut response) in &mut query {
-        let Some(_result) = future::block_on(future::poll_once(&mut response.0)) else { continue };
+        let Some(_result) = future::block_on(future::poll_once(&mut response.0)) else {
+            continue;
+        };
         commands
             .entity(entity)
             .remove::<DiscordResponseTask<Message>>();
@@ -214,14 +654,179 @@ fn handle_create_reaction(
         commands.spawn(DiscordResponseTask(task));
     }
 }
+fn handle_delete_reaction(
+    mut commands: Commands,
+    discord: Res<Discord>,
+    mut events: EventReader<send::DeleteReaction>,
+) {
+    let task_pool = IoTaskPool::get();
+
+    for event in events.iter() {
+        let channel_id = event.channel_id;
+        let message_id = event.message_id;
+        let emoji = event.emoji;
+
+        let http = discord.http.clone();
+
+        let task = task_pool.spawn(Compat::new(async move {
+            Ok(http
+                .delete_current_user_reaction(
+                    NonZeroU64::try_from(channel_id).unwrap().into(),
+                    NonZeroU64::try_from(message_id).unwrap().into(),
+                    &RequestReactionType::Unicode {
+                        name: &emoji.to_string(),
+                    },
+                )
+                .await)
+        }));
+        commands.spawn(DiscordResponseTask(task));
+    }
+}
 fn handle_empty_body_response(
     mut commands: Commands,
     mut query: Query<(Entity, &mut DiscordResponseTask<EmptyBody>)>,
 ) {
     for (entity, mut response) in &mut query {
-        let Some(_result) = future::block_on(future::poll_once(&mut response.0)) else { continue };
+        let Some(_result) = future::block_on(future::poll_once(&mut response.0)) else {
+            continue;
+        };
         commands
             .entity(entity)
             .remove::<DiscordResponseTask<EmptyBody>>();
     }
 }
+
+fn handle_register_commands_response(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut DiscordResponseTask<ListBody<Command>>)>,
+) {
+    for (entity, mut response) in &mut query {
+        let Some(result) = future::block_on(future::poll_once(&mut response.0)) else {
+            continue;
+        };
+        if let Ok(Err(err)) = result {
+            error!("couldn't register application commands {err}");
+        }
+        commands
+            .entity(entity)
+            .remove::<DiscordResponseTask<ListBody<Command>>>();
+    }
+}
+
+/// How long an interaction's token can be used for.
+const INTERACTION_TOKEN_LIFETIME: Duration = Duration::from_secs(15 * 60);
+
+/// The responses we created for each interaction token, and when. Updates wait
+/// for the lock, which is held until Discord has the response, since an update
+/// that gets there first fails with "Unknown interaction".
+#[derive(Resource, Default)]
+struct InteractionResponses(HashMap<String, (Instant, Arc<tokio::sync::Mutex<()>>)>);
+
+fn handle_create_interaction_response(
+    mut commands: Commands,
+    discord: Res<Discord>,
+    mut responses: ResMut<InteractionResponses>,
+    mut events: EventReader<send::CreateInteractionResponse>,
+) {
+    let task_pool = IoTaskPool::get();
+
+    // the tokens expire, so there's nothing left to update after that
+    responses
+        .0
+        .retain(|_, (created_at, _)| created_at.elapsed() < INTERACTION_TOKEN_LIFETIME);
+
+    for event in events.iter() {
+        let application_id = event.application_id;
+        let interaction_id = event.interaction_id;
+        let token = event.token.clone();
+        let response = match &event.kind {
+            send::InteractionResponseKind::Message(content) => InteractionResponse {
+                kind: InteractionResponseType::ChannelMessageWithSource,
+                data: Some(InteractionResponseData {
+                    content: Some(content.clone()),
+                    allowed_mentions: Some(AllowedMentions::default()),
+                    ..Default::default()
+                }),
+            },
+            send::InteractionResponseKind::EphemeralMessage(content) => InteractionResponse {
+                kind: InteractionResponseType::ChannelMessageWithSource,
+                data: Some(InteractionResponseData {
+                    content: Some(content.clone()),
+                    allowed_mentions: Some(AllowedMentions::default()),
+                    flags: Some(MessageFlags::EPHEMERAL),
+                    ..Default::default()
+                }),
+            },
+            send::InteractionResponseKind::Defer => InteractionResponse {
+                kind: InteractionResponseType::DeferredChannelMessageWithSource,
+                data: None,
+            },
+        };
+
+        let http = discord.http.clone();
+        let lock = Arc::new(tokio::sync::Mutex::new(()));
+        let created = lock
+            .clone()
+            .try_lock_owned()
+            .expect("nothing else has the lock yet");
+        responses.0.insert(token.clone(), (Instant::now(), lock));
+
+        let task = task_pool.spawn(Compat::new(async move {
+            let _created = created;
+            Ok(http
+                .interaction(NonZeroU64::try_from(application_id).unwrap().into())
+                .create_response(
+                    NonZeroU64::try_from(interaction_id).unwrap().into(),
+                    &token,
+                    &response,
+                )
+                .await)
+        }));
+        commands.spawn(DiscordResponseTask(task));
+    }
+}
+
+fn handle_update_interaction_response(
+    mut commands: Commands,
+    discord: Res<Discord>,
+    responses: Res<InteractionResponses>,
+    mut events: EventReader<send::UpdateInteractionResponse>,
+) {
+    let task_pool = IoTaskPool::get();
+
+    for event in events.iter() {
+        let application_id = event.application_id;
+        let token = event.token.clone();
+        let content = event.content.clone();
+        let attachments = event
+            .attachment
+            .iter()
+            .map(|attachment| {
+                Attachment::from_bytes(attachment.filename.clone(), attachment.data.clone(), 0)
+            })
+            .collect::<Vec<_>>();
+
+        let http = discord.http.clone();
+        let created = responses.0.get(&token).map(|(_, lock)| lock.clone());
+
+        let task = task_pool.spawn(Compat::new(async move {
+            // this also keeps updates to the same response in order
+            let _created = match &created {
+                Some(created) => Some(created.lock().await),
+                None => None,
+            };
+            let mut request = http
+                .interaction(NonZeroU64::try_from(application_id).unwrap().into())
+                .update_response(&token)
+                .allowed_mentions(Some(&AllowedMentions::default()));
+            if !attachments.is_empty() {
+                request = request.attachments(&attachments)?;
+            }
+            match request.content(Some(&content)) {
+                Ok(updated_message) => Ok(updated_message.await),
+                Err(e) => Err(e),
+            }
+        }));
+        commands.spawn(DiscordResponseTask(task));
+    }
+}
@@ -1,94 +1,763 @@
 //! A Bevy plugin for controlling a Matrix bot.
 
-use std::{pin::Pin, sync::mpsc};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
+use anyhow::Context;
+use async_compat::Compat;
 use bevy_app::{App, Plugin};
-use bevy_ecs::system::{Res, ResMut, Resource};
+use bevy_ecs::{
+    component::Component,
+    entity::Entity,
+    event::{EventReader, EventWriter},
+    system::{Commands, Query, Res, ResMut, Resource},
+};
 use bevy_tasks::{IoTaskPool, Task};
-use futures_lite::{future, Stream, StreamExt};
-use matrix_sdk::{config::SyncSettings, Client, deserialized_responses::SyncResponse};
+use futures_lite::future;
+use log::{error, warn};
+use matrix_sdk::{
+    config::SyncSettings,
+    room::Room,
+    ruma::{
+        events::{
+            reaction::{ReactionEventContent, Relation},
+            room::message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
+        },
+        EventId, RoomId, UserId,
+    },
+    Client, Session,
+};
+use parking_lot::Mutex;
+use tokio::sync::broadcast::{self, error::TryRecvError};
 
+pub mod recv {
+    #[derive(Debug, Clone)]
+    pub struct RoomMessage {
+        pub room_id: String,
+        pub event_id: String,
+        /// The full Matrix ID of the sender, like `@user:matrix.org`.
+        pub sender: String,
+        /// The sender's display name in the room, or their localpart if they
+        /// don't have one.
+        pub sender_name: String,
+        pub body: String,
+    }
+}
+pub mod send {
+    #[derive(Debug)]
+    pub struct SendMessage {
+        pub room_id: String,
+        pub content: String,
+    }
+    #[derive(Debug)]
+    pub struct SendReaction {
+        pub room_id: String,
+        pub event_id: String,
+        pub key: String,
+    }
+}
+
+/// How the bot should log into Matrix.
+#[derive(Clone, Debug)]
+pub enum MatrixLogin {
+    Password {
+        username: String,
+        password: String,
+    },
+    /// A `m.login.token` login token.
+    Token(String),
+    /// Use an access token from an existing session instead of logging in
+    /// again.
+    AccessToken {
+        user_id: String,
+        device_id: String,
+        access_token: String,
+    },
+}
+
+/// How to connect to Matrix.
+#[derive(Clone)]
+pub struct MatrixSettings {
+    /// The URL of the homeserver, like `https://matrix.org`.
+    pub homeserver: String,
+    pub login: MatrixLogin,
+    /// The display name of the device that's created when we log in.
+    pub device_display_name: String,
+    /// Where to save the session after logging in. If there's a session saved
+    /// here it's restored instead of logging in again, so we don't make a new
+    /// device every time we restart. If the homeserver doesn't accept it
+    /// anymore, it's deleted and we log in with [`Self::login`].
+    pub session_path: Option<PathBuf>,
+}
+
+/// The connection to Matrix. This is shared between every app that has a
+/// [`MatrixPlugin`], so the bot only has one device and one sync loop no
+/// matter how many servers it's bridging.
+#[derive(Clone)]
+pub struct MatrixClient {
+    /// The logged in client. This is None until we finish logging in, and
+    /// while we're logging in again.
+    client: Arc<Mutex<Option<Client>>>,
+    events: broadcast::Sender<recv::RoomMessage>,
+}
+
+/// How many events each app can fall behind by before it starts missing them.
+const EVENT_BUFFER: usize = 1024;
+/// How long we wait before logging in again when the sync loop stops, so we
+/// don't hammer the homeserver if it's down.
+const RELOGIN_DELAY: Duration = Duration::from_secs(30);
+
+impl MatrixClient {
+    /// Log into Matrix and start syncing. This has to be called from inside
+    /// the tokio runtime, since that's where the sync loop runs.
+    pub fn connect(settings: MatrixSettings) -> Self {
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        let client = MatrixClient {
+            client: Arc::new(Mutex::new(None)),
+            events,
+        };
+        tokio::spawn(client.clone().loop_sync(settings));
+        client
+    }
+
+    async fn loop_sync(self, settings: MatrixSettings) {
+        loop {
+            let Some(client) = login(settings.clone(), self.events.clone()).await else {
+                // login already logged why, and there's no point in trying
+                // again
+                return;
+            };
+            *self.client.lock() = Some(client.clone());
+            if let Err(err) = client.sync(SyncSettings::default()).await {
+                error!("Matrix sync loop stopped. {err}");
+            }
+            *self.client.lock() = None;
+            warn!(
+                "Matrix sync loop stopped, logging in again in {} seconds",
+                RELOGIN_DELAY.as_secs()
+            );
+            tokio::time::sleep(RELOGIN_DELAY).await;
+        }
+    }
+
+    fn client(&self) -> Option<Client> {
+        self.client.lock().clone()
+    }
+}
+
+#[derive(Clone)]
 pub struct MatrixPlugin {
-    pub token: String,
+    pub client: MatrixClient,
 }
 impl Plugin for MatrixPlugin {
     fn build(&self, app: &mut App) {
-        let mut pool = IoTaskPool::get();
+        app.add_event::<recv::RoomMessage>()
+            .add_event::<send::SendMessage>()
+            .add_event::<send::SendReaction>()
+            .add_system(handle_from_matrix_events)
+            .add_system(handle_send_message)
+            .add_system(handle_send_reaction)
+            .add_system(handle_matrix_response);
 
-        let token = self.token.clone();
+        app.insert_resource(Matrix::new(self.client.clone()));
+    }
+}
+
+impl Matrix {
+    pub fn new(client: MatrixClient) -> Self {
+        Matrix {
+            rx: client.events.subscribe(),
+            client,
+        }
+    }
+}
 
-        let (tx, rx) = mpsc::unbounded_channel();
+#[derive(Resource)]
+pub struct Matrix {
+    pub client: MatrixClient,
+    rx: broadcast::Receiver<recv::RoomMessage>,
+}
 
-        let task = pool.spawn(async move {
-            let client = match Client::builder()
-                .homeserver_url("https://matdoes.dev")
-                .build()
-                .await
-            {
-                Ok(client) => client,
-                Err(err) => {
-                    eprintln!("Couldn't make Matrix client with homeserver. {err}");
-                    return None;
-                }
-            };
-            if let Err(err) = client
-                .login_token(&token)
-                .initial_device_display_name("potato bot")
+fn load_session(path: &Path) -> Option<Session> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(err) => {
+            if err.kind() != io::ErrorKind::NotFound {
+                warn!(
+                    "Couldn't read Matrix session from {}. {err}",
+                    path.display()
+                );
+            }
+            return None;
+        }
+    };
+    match serde_json::from_str(&data) {
+        Ok(session) => Some(session),
+        Err(err) => {
+            warn!("Couldn't parse Matrix session in {}. {err}", path.display());
+            None
+        }
+    }
+}
+
+fn save_session(path: &Path, session: &Session) {
+    let data = serde_json::to_string(session).expect("sessions can always be serialized");
+    if let Err(err) = write_private_file(path, data.as_bytes()) {
+        warn!("Couldn't save Matrix session to {}. {err}", path.display());
+    }
+}
+
+/// Write a file that only we can read, since sessions have an access token in
+/// them.
+fn write_private_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    // the mode is only used when the file is created, so fix it in case it
+    // was already there
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(data)
+}
+
+async fn log_in_with(client: &Client, config: &MatrixSettings) -> Result<(), matrix_sdk::Error> {
+    match &config.login {
+        MatrixLogin::Password { username, password } => {
+            client
+                .login_username(username, password)
+                .initial_device_display_name(&config.device_display_name)
+                .send()
+                .await?;
+        }
+        MatrixLogin::Token(token) => {
+            client
+                .login_token(token)
+                .initial_device_display_name(&config.device_display_name)
                 .send()
+                .await?;
+        }
+        MatrixLogin::AccessToken {
+            user_id,
+            device_id,
+            access_token,
+        } => {
+            let user_id = UserId::parse(user_id.as_str()).map_err(matrix_sdk::Error::from)?;
+            client
+                .restore_login(Session {
+                    access_token: access_token.clone(),
+                    refresh_token: None,
+                    user_id,
+                    device_id: device_id.as_str().into(),
+                })
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Delete a saved session that doesn't work anymore, so we don't keep trying
+/// it.
+fn remove_session(path: &Path) {
+    if let Err(err) = fs::remove_file(path) {
+        if err.kind() != io::ErrorKind::NotFound {
+            warn!(
+                "Couldn't delete Matrix session in {}. {err}",
+                path.display()
+            );
+        }
+    }
+}
+
+/// Make a client that's logged in, either with a saved session or how the
+/// config says to, and sync it once so we know the session actually works.
+async fn logged_in_client(
+    config: &MatrixSettings,
+    saved_session: Option<Session>,
+) -> anyhow::Result<Client> {
+    let client = Client::builder()
+        .homeserver_url(&config.homeserver)
+        .build()
+        .await
+        .with_context(|| {
+            format!(
+                "Couldn't make Matrix client with homeserver {}",
+                config.homeserver
+            )
+        })?;
+
+    match saved_session {
+        Some(session) => client
+            .restore_login(session)
+            .await
+            .context("Couldn't restore saved Matrix session")?,
+        None => {
+            log_in_with(&client, config)
                 .await
-            {
-                eprintln!("Couldn't log into Matrix client with given token. {err}");
-                return None;
-            };
+                .context("Couldn't log into Matrix client")?;
+            // saved before syncing, so if that fails we can still use this
+            // device next time
+            if let (Some(path), Some(session)) = (&config.session_path, client.session()) {
+                save_session(path, &session);
+            }
+        }
+    }
 
-            if let Err(err) = client.sync_once(SyncSettings::default()).await {
-                eprintln!("{err}");
+    // sync once before adding the event handler so we don't bridge old messages
+    client
+        .sync_once(SyncSettings::default())
+        .await
+        .context("Couldn't do initial Matrix sync")?;
+    Ok(client)
+}
+
+async fn login(
+    config: MatrixSettings,
+    events: broadcast::Sender<recv::RoomMessage>,
+) -> Option<Client> {
+    let saved_session = config.session_path.as_deref().and_then(load_session);
+    let restored = match saved_session {
+        Some(session) => match logged_in_client(&config, Some(session)).await {
+            Ok(client) => Some(client),
+            Err(err) => {
+                // probably the access token was revoked
+                warn!("Saved Matrix session didn't work, logging in again. {err:#}");
+                if let Some(path) = &config.session_path {
+                    remove_session(path);
+                }
+                None
+            }
+        },
+        None => None,
+    };
+    let client = match restored {
+        Some(client) => client,
+        None => match logged_in_client(&config, None).await {
+            Ok(client) => client,
+            Err(err) => {
+                error!("{err:#}");
                 return None;
-            };
+            }
+        },
+    };
+
+    client.add_event_handler(
+        move |event: OriginalSyncRoomMessageEvent, room: Room, client: Client| {
+            let events = events.clone();
+            async move {
+                let Room::Joined(room) = room else { return };
+                if client.user_id() == Some(event.sender.as_ref()) {
+                    // we sent this message
+                    return;
+                }
+                let MessageType::Text(text) = event.content.msgtype else {
+                    return;
+                };
 
-            let sync_stream = Box::pin(client.sync_stream(SyncSettings::default()).await);
+                let sender_name = match room.get_member(&event.sender).await {
+                    Ok(Some(member)) => member.name().to_string(),
+                    _ => event.sender.localpart().to_string(),
+                };
 
-            while let Some(Ok(item)) = sync_stream.next().await {
-                tx.send(item);
+                // this only fails if no app is listening, like while every
+                // swarm is reconnecting, and then nobody needs the event anyway
+                let _ = events.send(recv::RoomMessage {
+                    room_id: room.room_id().to_string(),
+                    event_id: event.event_id.to_string(),
+                    sender: event.sender.to_string(),
+                    sender_name,
+                    body: text.body,
+                });
             }
+        },
+    );
 
-            Some(MatrixClient {client, sync_stream})
-        });
-        app.insert_resource(Matrix {
-            login_task: Some(task),
-            client: None,
-        })
-        .add_system(check_login_task);
+    Some(client)
+}
+
+fn handle_from_matrix_events(
+    mut matrix: ResMut<Matrix>,
+    mut room_message_events: EventWriter<recv::RoomMessage>,
+) {
+    loop {
+        match matrix.rx.try_recv() {
+            Ok(event) => room_message_events.send(event),
+            Err(TryRecvError::Lagged(skipped)) => {
+                warn!("missed {skipped} Matrix events because we fell behind");
+            }
+            Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+        }
     }
 }
 
-pub struct MatrixClient {
-    pub client: Client,
-    pub sync_stream: Pin<Box<impl Stream<Item = Result<SyncResponse, matrix_sdk::Error>>>>
+#[derive(Component)]
+pub struct MatrixResponseTask(Task<Result<(), matrix_sdk::Error>>);
+
+fn handle_send_message(
+    mut commands: Commands,
+    matrix: Res<Matrix>,
+    mut events: EventReader<send::SendMessage>,
+) {
+    let task_pool = IoTaskPool::get();
+
+    for event in events.iter() {
+        let Some(client) = matrix.client.client() else {
+            warn!("tried to send a Matrix message before logging in");
+            continue;
+        };
+        let Ok(room_id) = RoomId::parse(&event.room_id) else {
+            warn!("invalid Matrix room id {}", event.room_id);
+            continue;
+        };
+        let Some(room) = client.get_joined_room(&room_id) else {
+            warn!("tried to send a Matrix message to a room we're not in ({room_id})");
+            continue;
+        };
+        let content = RoomMessageEventContent::text_plain(&event.content);
+
+        let task = task_pool.spawn(Compat::new(async move {
+            room.send(content, None).await.map(|_| ())
+        }));
+        commands.spawn(MatrixResponseTask(task));
+    }
 }
 
-#[derive(Resource)]
-pub struct Matrix {
-    pub login_task: Option<Task<Option<MatrixClient>>>,
-    pub client: Option<Client>,
+fn handle_send_reaction(
+    mut commands: Commands,
+    matrix: Res<Matrix>,
+    mut events: EventReader<send::SendReaction>,
+) {
+    let task_pool = IoTaskPool::get();
+
+    for event in events.iter() {
+        let Some(client) = matrix.client.client() else {
+            warn!("tried to send a Matrix reaction before logging in");
+            continue;
+        };
+        let (Ok(room_id), Ok(event_id)) = (
+            RoomId::parse(&event.room_id),
+            EventId::parse(&event.event_id),
+        ) else {
+            warn!(
+                "invalid Matrix room or event id {} {}",
+                event.room_id, event.event_id
+            );
+            continue;
+        };
+        let Some(room) = client.get_joined_room(&room_id) else {
+            warn!("tried to send a Matrix reaction to a room we're not in ({room_id})");
+            continue;
+        };
+        let content = ReactionEventContent::new(Relation::new(event_id, event.key.clone()));
+
+        let task = task_pool.spawn(Compat::new(async move {
+            room.send(content, None).await.map(|_| ())
+        }));
+        commands.spawn(MatrixResponseTask(task));
+    }
 }
 
-fn check_login_task(mut matrix: ResMut<Matrix>) {
-    if let Some(login_task) = &mut matrix.login_task {
-        if let Some(client) = future::block_on(future::poll_once(login_task)) {
-            matrix.login_task = None;
-            matrix.client = client;
+fn handle_matrix_response(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut MatrixResponseTask)>,
+) {
+    for (entity, mut response) in &mut query {
+        let Some(result) = future::block_on(future::poll_once(&mut response.0)) else {
+            continue;
+        };
+        if let Err(err) = result {
+            warn!("error sending to Matrix {err}");
         }
+        commands.entity(entity).remove::<MatrixResponseTask>();
     }
 }
-fn handle_from_matrix_events(mut matrix: ResMut<Matrix>) {
-    let Some(client) = matrix.client else { return };
 
-    // let sync_stream = client.sync_stream();
-}
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("potato-bot-{}-{name}", std::process::id()))
+    }
+
+    fn session() -> Session {
+        Session {
+            access_token: "secret".to_string(),
+            refresh_token: None,
+            user_id: UserId::parse("@potatobot:example.com").unwrap(),
+            device_id: "ABCDEFGHIJ".into(),
+        }
+    }
+
+    #[test]
+    fn saved_session_loads() {
+        let path = temp_path("saved_session_loads.json");
+        save_session(&path, &session());
+        let loaded = load_session(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.access_token, "secret");
+        assert_eq!(loaded.refresh_token, None);
+        assert_eq!(loaded.user_id.as_str(), "@potatobot:example.com");
+        assert_eq!(loaded.device_id.as_str(), "ABCDEFGHIJ");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saved_session_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_path("saved_session_is_private.json");
+        // even if the file was already there and readable by everyone
+        fs::write(&path, "{}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        save_session(&path, &session());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn missing_session_is_none() {
+        assert!(load_session(&temp_path("missing_session_is_none.json")).is_none());
+    }
+
+    #[test]
+    fn invalid_session_is_none() {
+        let path = temp_path("invalid_session_is_none.json");
+        fs::write(&path, "not json").unwrap();
+        let loaded = load_session(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(loaded.is_none());
+    }
+
+    /// Every request the stand-in homeserver got, as the head and body.
+    type Requests = Arc<Mutex<Vec<(String, String)>>>;
 
-// impl Matrix {
-//     pub fn new() {
+    /// The only access token that the stand-in homeserver accepts, which it
+    /// gives out when anyone logs in.
+    const FRESH_TOKEN: &str = "fresh";
 
-//     }
-// }
+    async fn read_request(stream: &mut TcpStream) -> (String, String) {
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let read = stream.read(&mut buf).await.unwrap();
+            assert!(
+                read > 0,
+                "the connection closed before the whole request was sent"
+            );
+            request.extend_from_slice(&buf[..read]);
+            let text = String::from_utf8_lossy(&request);
+            let Some(head_end) = text.find("\r\n\r\n") else {
+                continue;
+            };
+            let head = &text[..head_end];
+            let content_length = head
+                .lines()
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("content-length")
+                        .then(|| value.trim().parse::<usize>().unwrap())
+                })
+                .unwrap_or(0);
+            let body = &text[head_end + 4..];
+            if body.len() >= content_length {
+                return (head.to_string(), body.to_string());
+            }
+        }
+    }
+
+    /// What the stand-in homeserver answers with, as the status and JSON body.
+    fn respond_to(head: &str) -> (&'static str, String) {
+        let path = head.split(' ').nth(1).unwrap_or_default();
+        let authorized = head.lines().any(|line| {
+            line.split_once(':').map_or(false, |(name, value)| {
+                name.eq_ignore_ascii_case("authorization")
+                    && value.trim() == format!("Bearer {FRESH_TOKEN}")
+            })
+        });
+        if path.ends_with("/versions") {
+            return ("200 OK", r#"{"versions":["r0.6.1","v1.1"]}"#.to_string());
+        }
+        if path.ends_with("/login") {
+            return (
+                "200 OK",
+                format!(
+                    r#"{{"user_id":"@potatobot:example.com","access_token":"{FRESH_TOKEN}","device_id":"NEWDEVICE"}}"#
+                ),
+            );
+        }
+        if !authorized {
+            return (
+                "401 Unauthorized",
+                r#"{"errcode":"M_UNKNOWN_TOKEN","error":"Unknown access token"}"#.to_string(),
+            );
+        }
+        if path.contains("/sync") {
+            ("200 OK", r#"{"next_batch":"s1"}"#.to_string())
+        } else if path.ends_with("/keys/upload") {
+            (
+                "200 OK",
+                r#"{"one_time_key_counts":{"signed_curve25519":50}}"#.to_string(),
+            )
+        } else if path.ends_with("/keys/query") {
+            ("200 OK", r#"{"device_keys":{},"failures":{}}"#.to_string())
+        } else {
+            (
+                "404 Not Found",
+                r#"{"errcode":"M_UNRECOGNIZED","error":"Unrecognized request"}"#.to_string(),
+            )
+        }
+    }
+
+    /// Start a stand-in homeserver, and return its URL and the requests it
+    /// gets.
+    async fn homeserver() -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let received = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let received = received.clone();
+                tokio::spawn(async move {
+                    let (head, body) = read_request(&mut stream).await;
+                    let (status, response) = respond_to(&head);
+                    received.lock().push((head, body));
+                    let response = format!(
+                        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+                        response.len()
+                    );
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        (url, requests)
+    }
+
+    fn config(homeserver: String, login: MatrixLogin, session_path: &Path) -> MatrixSettings {
+        MatrixSettings {
+            homeserver,
+            login,
+            device_display_name: "potato-bot".to_string(),
+            session_path: Some(session_path.to_path_buf()),
+        }
+    }
+
+    fn password_login() -> MatrixLogin {
+        MatrixLogin::Password {
+            username: "potatobot".to_string(),
+            password: "hunter2".to_string(),
+        }
+    }
+
+    /// The bodies of the login requests the stand-in homeserver got.
+    fn logins(requests: &Requests) -> Vec<serde_json::Value> {
+        requests
+            .lock()
+            .iter()
+            .filter(|(head, _)| head.starts_with("POST ") && head.contains("/login "))
+            .map(|(_, body)| serde_json::from_str(body).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn logs_in_with_password() {
+        let (homeserver, requests) = homeserver().await;
+        let path = temp_path("password_login.json");
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        let client = login(config(homeserver, password_login(), &path), events).await;
+        let saved = load_session(&path);
+        fs::remove_file(&path).unwrap();
+
+        let session = client.expect("logging in should work").session().unwrap();
+        assert_eq!(session.access_token, FRESH_TOKEN);
+        assert_eq!(saved.unwrap().access_token, FRESH_TOKEN);
+        let logins = logins(&requests);
+        assert_eq!(logins.len(), 1);
+        assert_eq!(logins[0]["type"], "m.login.password");
+        assert_eq!(logins[0]["identifier"]["user"], "potatobot");
+        assert_eq!(logins[0]["password"], "hunter2");
+        assert_eq!(logins[0]["initial_device_display_name"], "potato-bot");
+    }
+
+    #[tokio::test]
+    async fn logs_in_with_token() {
+        let (homeserver, requests) = homeserver().await;
+        let path = temp_path("token_login.json");
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        let login_token = MatrixLogin::Token("login-token".to_string());
+        let client = login(config(homeserver, login_token, &path), events).await;
+        fs::remove_file(&path).unwrap();
+
+        assert!(client.is_some());
+        let logins = logins(&requests);
+        assert_eq!(logins.len(), 1);
+        assert_eq!(logins[0]["type"], "m.login.token");
+        assert_eq!(logins[0]["token"], "login-token");
+    }
+
+    #[tokio::test]
+    async fn saved_session_is_used_instead_of_logging_in() {
+        let (homeserver, requests) = homeserver().await;
+        let path = temp_path("saved_session_is_used.json");
+        save_session(
+            &path,
+            &Session {
+                access_token: FRESH_TOKEN.to_string(),
+                ..session()
+            },
+        );
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        let client = login(config(homeserver, password_login(), &path), events).await;
+        fs::remove_file(&path).unwrap();
+
+        let session = client.expect("restoring should work").session().unwrap();
+        assert_eq!(session.device_id.as_str(), "ABCDEFGHIJ");
+        assert!(logins(&requests).is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejected_session_is_replaced() {
+        let (homeserver, requests) = homeserver().await;
+        let path = temp_path("rejected_session_is_replaced.json");
+        // the stand-in doesn't accept this token, like if it was revoked
+        save_session(&path, &session());
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        let client = login(config(homeserver, password_login(), &path), events).await;
+        let saved = load_session(&path);
+        fs::remove_file(&path).unwrap();
+
+        let session = client
+            .expect("logging in again should work")
+            .session()
+            .unwrap();
+        assert_eq!(session.access_token, FRESH_TOKEN);
+        assert_eq!(session.device_id.as_str(), "NEWDEVICE");
+        assert_eq!(saved.unwrap().access_token, FRESH_TOKEN);
+        assert_eq!(logins(&requests).len(), 1);
+    }
+}
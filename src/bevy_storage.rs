@@ -0,0 +1,161 @@
+//! A Bevy plugin for keeping state in an SQLite database, so it's still there
+//! after restarting.
+
+use std::{path::Path, sync::Arc};
+
+use bevy_app::{App, Plugin};
+use bevy_ecs::system::Resource;
+use log::info;
+use parking_lot::Mutex;
+use rusqlite::{Connection, Transaction};
+
+/// The changes to the database schema, in order. Each one is run once, in a
+/// transaction, and the number that have been run is kept in the database's
+/// `user_version`.
+///
+/// Don't change migrations once they've been released, add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: links between Discord users and Minecraft players
+    "CREATE TABLE account_links (
+        uuid TEXT PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL,
+        discord_id INTEGER NOT NULL UNIQUE
+    );",
+    // 2: everything the bridge sees, for searching
+    "CREATE TABLE chat_archive (
+        id INTEGER PRIMARY KEY,
+        -- unix time in milliseconds
+        timestamp INTEGER NOT NULL,
+        server TEXT NOT NULL,
+        -- the username of the bot that saw or sent the message
+        account TEXT NOT NULL,
+        -- minecraft, discord or matrix
+        platform TEXT NOT NULL,
+        sender TEXT,
+        content TEXT NOT NULL,
+        -- the chat component from minecraft, as json
+        component TEXT,
+        -- whispers aren't shown in search results
+        private INTEGER NOT NULL
+    );
+    CREATE INDEX chat_archive_server_timestamp ON chat_archive (server, timestamp);",
+    // 3: when players were online, for !seen and !playtime
+    "CREATE TABLE player_sessions (
+        id INTEGER PRIMARY KEY,
+        server TEXT NOT NULL,
+        uuid TEXT NOT NULL,
+        -- the player's name when the session started
+        name TEXT NOT NULL COLLATE NOCASE,
+        -- unix time in milliseconds
+        started_at INTEGER NOT NULL,
+        -- null while the player is online
+        ended_at INTEGER,
+        -- updated while the player is online, so the session can still be
+        -- ended if the bot stops without ending it
+        last_seen_at INTEGER NOT NULL
+    );
+    CREATE INDEX player_sessions_server_uuid ON player_sessions (server, uuid, started_at);
+    CREATE INDEX player_sessions_server_name ON player_sessions (server, name);",
+    // 4: how much slower we chat on each server because it warned us for
+    // spamming
+    "CREATE TABLE learned_rate_limits (
+        server TEXT PRIMARY KEY NOT NULL,
+        factor REAL NOT NULL
+    );",
+    // 5: messages that haven't been sent yet, so they're still sent after
+    // reconnecting or restarting
+    "CREATE TABLE discord_queue (
+        id INTEGER PRIMARY KEY,
+        server TEXT NOT NULL,
+        channel_id INTEGER NOT NULL,
+        -- the player it's sent as, for channels with a webhook
+        author_name TEXT,
+        author_uuid TEXT,
+        -- the player that sent it, for every channel
+        sender TEXT,
+        content TEXT NOT NULL,
+        -- unix time in milliseconds
+        queued_at INTEGER NOT NULL
+    );
+    CREATE INDEX discord_queue_server ON discord_queue (server, id);
+    CREATE TABLE chat_queue (
+        id INTEGER PRIMARY KEY,
+        server TEXT NOT NULL,
+        -- the username of the bot that's sending it
+        account TEXT NOT NULL,
+        -- 0 is low, 1 is normal and 2 is high
+        priority INTEGER NOT NULL,
+        content TEXT NOT NULL,
+        -- unix time in milliseconds
+        queued_at INTEGER NOT NULL
+    );
+    CREATE INDEX chat_queue_server_account ON chat_queue (server, account, id);",
+];
+
+#[derive(Clone)]
+pub struct StoragePlugin {
+    /// The database, which is shared between every swarm.
+    pub storage: Storage,
+}
+impl Plugin for StoragePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(self.storage.clone());
+    }
+}
+
+/// A connection to the database. This can be cloned, and every clone uses the
+/// same connection.
+#[derive(Resource, Clone)]
+pub struct Storage(Arc<Mutex<Connection>>);
+
+impl Storage {
+    /// Open the database, creating it if it doesn't exist, and run any
+    /// migrations it hasn't had yet.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut connection = Connection::open(path)?;
+        migrate(&mut connection)?;
+        Ok(Self(Arc::new(Mutex::new(connection))))
+    }
+
+    /// Run something with the connection. The database is locked until it
+    /// returns, so don't do anything slow in it.
+    pub fn with<T>(
+        &self,
+        f: impl FnOnce(&Connection) -> rusqlite::Result<T>,
+    ) -> rusqlite::Result<T> {
+        f(&self.0.lock())
+    }
+
+    /// Like [`Storage::with`], but everything is done in one transaction, which
+    /// is committed if `f` returns Ok.
+    pub fn transaction<T>(
+        &self,
+        f: impl FnOnce(&Transaction) -> rusqlite::Result<T>,
+    ) -> rusqlite::Result<T> {
+        let mut connection = self.0.lock();
+        let transaction = connection.transaction()?;
+        let result = f(&transaction)?;
+        transaction.commit()?;
+        Ok(result)
+    }
+}
+
+fn migrate(connection: &mut Connection) -> anyhow::Result<()> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!(
+            "the database is at version {version}, but we only know about {} versions. was it \
+             made by a newer version of the bot?",
+            MIGRATIONS.len()
+        );
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", i + 1)?;
+        transaction.commit()?;
+        info!("migrated the database to version {}", i + 1);
+    }
+    Ok(())
+}
@@ -0,0 +1,938 @@
+//! The typed config file, and the env vars that can override it.
+
+use std::{
+    collections::{HashMap, HashSet},
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::{
+    azalea_avoid_chat_kick::{DropPolicy, RateLimit, RateLimitProfile, SanitizeMode},
+    azalea_dedup::DedupSettings,
+    azalea_discord_bridge::DiscordWebhook,
+    bevy_matrix::MatrixLogin,
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub discord: DiscordConfig,
+    pub matrix: Option<MatrixConfig>,
+    pub accounts: Vec<AccountConfig>,
+    pub servers: Vec<ServerConfig>,
+    #[serde(default)]
+    pub bridges: Vec<BridgeConfig>,
+    #[serde(default)]
+    pub rate_limits: RateLimitsConfig,
+    #[serde(default)]
+    pub formatting: FormattingConfig,
+    #[serde(default)]
+    pub chat_queue: ChatQueueConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscordConfig {
+    /// The bot token. This is usually set with the `DISCORD_TOKEN` env var
+    /// instead so it doesn't have to be in the file.
+    #[serde(default)]
+    pub token: String,
+    /// Send API requests to this address over plain HTTP instead of to
+    /// Discord, like `localhost:8080`. This is for testing.
+    pub api_proxy: Option<String>,
+    /// Webhook URLs for channels that should show Minecraft messages with the
+    /// player's name and avatar, keyed by channel id.
+    #[serde(default)]
+    pub webhooks: HashMap<String, String>,
+    /// The avatar of players when sending through a webhook. `{uuid}` and
+    /// `{name}` are replaced.
+    #[serde(default = "default_avatar_url")]
+    pub avatar_url: String,
+    /// Edits to messages that were sent to Minecraft less than this many
+    /// seconds ago are sent to Minecraft too. 0 turns this off.
+    #[serde(default = "default_edit_window_secs")]
+    pub edit_window_secs: u64,
+    /// Whether to say in Minecraft when a message that was sent there is
+    /// deleted, within the same window as edits.
+    #[serde(default)]
+    pub relay_deletions: bool,
+    /// Whispers to accounts without a `discord_owner` are sent to this
+    /// channel, and messages in it are whispered back.
+    pub whisper_channel: Option<u64>,
+    /// Whether the bot's activity shows the server's status, like `Playing
+    /// main — 3 online`.
+    #[serde(default = "default_true")]
+    pub presence: bool,
+    /// How often channel topics listing who's online are updated. Discord only
+    /// allows changing a topic twice every 10 minutes, so this is at least 300.
+    #[serde(default = "default_topic_interval_secs")]
+    pub topic_interval_secs: u64,
+}
+fn default_edit_window_secs() -> u64 {
+    5 * 60
+}
+fn default_topic_interval_secs() -> u64 {
+    10 * 60
+}
+/// Discord allows changing a channel's topic twice every 10 minutes.
+const MIN_TOPIC_INTERVAL_SECS: u64 = 5 * 60;
+fn default_avatar_url() -> String {
+    "https://mc-heads.net/avatar/{uuid}".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatrixConfig {
+    pub homeserver: String,
+    #[serde(default = "default_matrix_device_name")]
+    pub device_name: String,
+    /// Where the session is saved after logging in. This stays a file instead
+    /// of going in the database because it has the access token, so it's only
+    /// readable by us and can be deleted by itself to log in again.
+    #[serde(default = "default_matrix_session_path")]
+    pub session_path: PathBuf,
+
+    /// Log in with a username and password.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Log in with an `m.login.token` token.
+    pub token: Option<String>,
+    /// Reuse an existing session.
+    pub user_id: Option<String>,
+    pub device_id: Option<String>,
+    pub access_token: Option<String>,
+}
+fn default_matrix_device_name() -> String {
+    "potato bot".to_string()
+}
+fn default_matrix_session_path() -> PathBuf {
+    "matrix_session.json".into()
+}
+
+impl MatrixConfig {
+    /// Pick the login method based on which fields are set. This is checked
+    /// by [`Config::validate`], so it only returns None for invalid configs.
+    pub fn login(&self) -> Option<MatrixLogin> {
+        if let (Some(user_id), Some(device_id), Some(access_token)) =
+            (&self.user_id, &self.device_id, &self.access_token)
+        {
+            return Some(MatrixLogin::AccessToken {
+                user_id: user_id.clone(),
+                device_id: device_id.clone(),
+                access_token: access_token.clone(),
+            });
+        }
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            return Some(MatrixLogin::Password {
+                username: username.clone(),
+                password: password.clone(),
+            });
+        }
+        self.token.clone().map(MatrixLogin::Token)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    /// The name other parts of the config use to refer to this account. If
+    /// there's no email, this is also the username of the offline account.
+    pub name: String,
+    /// The email of the Microsoft account. If this isn't set, the account is
+    /// offline-mode.
+    pub email: Option<String>,
+    /// How fast this account can send chat messages. This overrides the
+    /// server's rate limit.
+    pub rate_limit: Option<RateLimitProfile>,
+    /// The id of the Discord user that gets DMed whispers to this account. Their
+    /// DMs to the bot are whispered back.
+    pub discord_owner: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub name: String,
+    pub address: String,
+    /// The names of the accounts that should join this server.
+    pub accounts: Vec<String>,
+    /// How fast accounts on this server can send chat messages. If this isn't
+    /// set, `rate_limits.minecraft` is used.
+    pub rate_limit: Option<RateLimitProfile>,
+    /// Discord channels whose topic lists who's online on this server.
+    #[serde(default)]
+    pub topic_channels: Vec<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BridgeConfig {
+    /// The name of the server this bridge is for.
+    pub server: String,
+    /// The name of the account that sends and receives messages for this
+    /// bridge. This can be left out if the server only has one account.
+    pub account: Option<String>,
+    #[serde(default)]
+    pub discord_channels: Vec<u64>,
+    #[serde(default)]
+    pub matrix_rooms: Vec<String>,
+    /// How repeated messages from Minecraft are collapsed. This applies to
+    /// every bridge of the account, so other bridges of it can only leave it
+    /// out or set it to the same thing.
+    pub dedup: Option<DedupSettings>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitsConfig {
+    #[serde(default)]
+    pub minecraft: RateLimitConfig,
+    #[serde(default)]
+    pub discord: RateLimitConfig,
+    #[serde(default)]
+    pub matrix: RateLimitConfig,
+    /// Slow down chat on servers that warn or kick us for spamming.
+    #[serde(default = "default_true")]
+    pub learn: bool,
+    /// Server messages and kick reasons that contain any of these, ignoring
+    /// case, mean we're sending messages too fast. Vanilla's kick for spamming
+    /// is always recognized, so this is for anti-spam plugins. Players can say
+    /// these too if a plugin formats their chat, so keep them specific.
+    #[serde(default)]
+    pub spam_warnings: Vec<String>,
+}
+impl Default for RateLimitsConfig {
+    fn default() -> Self {
+        Self {
+            minecraft: RateLimitConfig::default(),
+            discord: RateLimitConfig::default(),
+            matrix: RateLimitConfig::default(),
+            learn: true,
+            spam_warnings: Vec::new(),
+        }
+    }
+}
+fn default_true() -> bool {
+    true
+}
+
+/// A ratelimit where every message adds `message_cost`, it goes down by one
+/// every tick, and we can't send messages while it's at least `max`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub message_cost: usize,
+    pub max: usize,
+}
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            message_cost: 20,
+            max: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct FormattingConfig {
+    /// How messages from other platforms are sent to Minecraft. `{username}`
+    /// and `{content}` are replaced.
+    pub to_minecraft: String,
+    /// How Minecraft messages are sent to Discord. `{content}` is replaced.
+    pub to_discord: String,
+    /// How Minecraft messages are sent to Matrix. `{content}` is replaced.
+    pub to_matrix: String,
+    /// Long or multi-line messages are split into at most this many Minecraft
+    /// chat messages, and rejected if they need more.
+    pub max_minecraft_chunks: usize,
+    /// What to do with characters that can't be sent in Minecraft chat, like
+    /// tabs and `§`. `off` rejects the message, `replace` turns `§` into `&`
+    /// and `remove` removes it.
+    pub sanitize_to_minecraft: SanitizeMode,
+}
+/// How many messages each bot can have waiting to be sent in Minecraft.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ChatQueueConfig {
+    pub max_length: usize,
+    /// Which message is dropped when the queue is full: `oldest`, `newest` or
+    /// `coalesce`.
+    pub drop_policy: DropPolicy,
+}
+impl Default for ChatQueueConfig {
+    fn default() -> Self {
+        Self {
+            max_length: 20,
+            drop_policy: DropPolicy::Oldest,
+        }
+    }
+}
+
+impl Default for FormattingConfig {
+    fn default() -> Self {
+        Self {
+            to_minecraft: "/me <{username}> {content}".to_string(),
+            to_discord: "{content}".to_string(),
+            to_matrix: "{content}".to_string(),
+            max_minecraft_chunks: 4,
+            sanitize_to_minecraft: SanitizeMode::Off,
+        }
+    }
+}
+
+/// Where things that should still be there after restarting, like account
+/// links, are saved.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct StorageConfig {
+    /// The SQLite database. It's created if it doesn't exist.
+    pub path: PathBuf,
+}
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            path: "potato.db".into(),
+        }
+    }
+}
+
+/// Chat from every server is kept in the database so it can be searched with
+/// `/search`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ArchiveConfig {
+    pub enabled: bool,
+    /// Messages older than this many days are deleted. 0 keeps them forever.
+    pub retention_days: u64,
+}
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retention_days: 30,
+        }
+    }
+}
+impl ArchiveConfig {
+    pub fn retention(&self) -> Option<Duration> {
+        (self.retention_days > 0).then(|| Duration::from_secs(self.retention_days * 24 * 60 * 60))
+    }
+}
+
+/// A problem with a specific key in the config.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+/// Every problem we found while validating the config.
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid config:")?;
+        for error in &self.0 {
+            writeln!(f, "  {error}")?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for ConfigErrors {}
+
+impl Config {
+    /// Read the config from a file, apply overrides from the env, and
+    /// validate it.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read config file {}", path.display()))?;
+        let mut config: Config = toml::from_str(&data)
+            .with_context(|| format!("Couldn't parse config file {}", path.display()))?;
+        config.apply_env_overrides();
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Secrets are usually kept in the env (or a .env file) instead of the
+    /// config file, so they override whatever's in the file.
+    fn apply_env_overrides(&mut self) {
+        if let Ok(token) = env::var("DISCORD_TOKEN") {
+            self.discord.token = token;
+        }
+        if let Some(matrix) = &mut self.matrix {
+            if let Ok(homeserver) = env::var("MATRIX_HOMESERVER") {
+                matrix.homeserver = homeserver;
+            }
+            if let Ok(password) = env::var("MATRIX_PASSWORD") {
+                matrix.password = Some(password);
+            }
+            if let Ok(token) = env::var("MATRIX_TOKEN") {
+                matrix.token = Some(token);
+            }
+            if let Ok(access_token) = env::var("MATRIX_ACCESS_TOKEN") {
+                matrix.access_token = Some(access_token);
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut errors = Vec::new();
+        let mut error = |key: String, message: String| errors.push(ConfigError { key, message });
+
+        if self.discord.token.is_empty() {
+            error(
+                "discord.token".to_string(),
+                "missing, set it in the config or with DISCORD_TOKEN".to_string(),
+            );
+        }
+
+        for (channel_id, url) in &self.discord.webhooks {
+            if channel_id.parse::<u64>().map_or(true, |id| id == 0) {
+                error(
+                    format!("discord.webhooks.{channel_id}"),
+                    format!("{channel_id:?} isn't a valid Discord channel id"),
+                );
+            }
+            if parse_webhook_url(url).is_none() {
+                error(
+                    format!("discord.webhooks.{channel_id}"),
+                    "should look like https://discord.com/api/webhooks/<id>/<token>".to_string(),
+                );
+            }
+        }
+
+        if self.discord.whisper_channel == Some(0) {
+            error(
+                "discord.whisper_channel".to_string(),
+                "0 isn't a valid Discord channel id".to_string(),
+            );
+        }
+        if self.discord.topic_interval_secs < MIN_TOPIC_INTERVAL_SECS {
+            error(
+                "discord.topic_interval_secs".to_string(),
+                format!(
+                    "Discord only allows changing a topic twice every 10 minutes, so this should \
+                     be at least {MIN_TOPIC_INTERVAL_SECS}"
+                ),
+            );
+        }
+
+        if let Some(matrix) = &self.matrix {
+            if !matrix.homeserver.starts_with("http://")
+                && !matrix.homeserver.starts_with("https://")
+            {
+                error(
+                    "matrix.homeserver".to_string(),
+                    format!("{:?} should be an http(s) URL", matrix.homeserver),
+                );
+            }
+            if matrix.username.is_some() && matrix.password.is_none() {
+                error(
+                    "matrix.password".to_string(),
+                    "missing, set it in the config or with MATRIX_PASSWORD".to_string(),
+                );
+            } else if matrix.login().is_none() {
+                error(
+                    "matrix".to_string(),
+                    "needs username and password, token, or user_id, device_id and access_token"
+                        .to_string(),
+                );
+            }
+        }
+
+        let mut account_names = HashSet::new();
+        for (i, account) in self.accounts.iter().enumerate() {
+            if !account_names.insert(account.name.as_str()) {
+                error(
+                    format!("accounts[{i}].name"),
+                    format!("there's already an account called {:?}", account.name),
+                );
+            }
+            if account.discord_owner == Some(0) {
+                error(
+                    format!("accounts[{i}].discord_owner"),
+                    "0 isn't a valid Discord user id".to_string(),
+                );
+            }
+            if let Some(rate_limit) = &account.rate_limit {
+                validate_rate_limit_profile(
+                    format!("accounts[{i}].rate_limit"),
+                    rate_limit,
+                    &mut error,
+                );
+            }
+        }
+
+        // the account name -> server name, since an account can only be on one
+        // server at a time
+        let mut account_servers: HashMap<&str, &str> = HashMap::new();
+        // the channel id -> server name, since two servers would keep
+        // overwriting each other's topic
+        let mut topic_servers: HashMap<u64, &str> = HashMap::new();
+        let mut server_names = HashSet::new();
+        for (i, server) in self.servers.iter().enumerate() {
+            if !server_names.insert(server.name.as_str()) {
+                error(
+                    format!("servers[{i}].name"),
+                    format!("there's already a server called {:?}", server.name),
+                );
+            }
+            if server.topic_channels.contains(&0) {
+                error(
+                    format!("servers[{i}].topic_channels"),
+                    "0 isn't a valid Discord channel id".to_string(),
+                );
+            }
+            for (j, &channel_id) in server.topic_channels.iter().enumerate() {
+                if let Some(other_server) = topic_servers.insert(channel_id, server.name.as_str()) {
+                    error(
+                        format!("servers[{i}].topic_channels[{j}]"),
+                        format!("is already the topic channel of server {other_server:?}"),
+                    );
+                }
+            }
+            if let Some(rate_limit) = &server.rate_limit {
+                validate_rate_limit_profile(
+                    format!("servers[{i}].rate_limit"),
+                    rate_limit,
+                    &mut error,
+                );
+            }
+            if server.accounts.is_empty() {
+                error(
+                    format!("servers[{i}].accounts"),
+                    "needs at least one account".to_string(),
+                );
+            }
+            for (j, account) in server.accounts.iter().enumerate() {
+                if !account_names.contains(account.as_str()) {
+                    error(
+                        format!("servers[{i}].accounts[{j}]"),
+                        format!("there's no account called {account:?}"),
+                    );
+                } else if let Some(other_server) =
+                    account_servers.insert(account.as_str(), server.name.as_str())
+                {
+                    error(
+                        format!("servers[{i}].accounts[{j}]"),
+                        format!("{account:?} is already on server {other_server:?}"),
+                    );
+                }
+            }
+        }
+        if self.servers.is_empty() {
+            error(
+                "servers".to_string(),
+                "needs at least one server".to_string(),
+            );
+        }
+
+        // the (server name, account name) -> which bridge set its dedup, since
+        // dedup happens before messages get to any of the account's bridges
+        let mut dedup_bridges: HashMap<(&str, &str), (usize, &DedupSettings)> = HashMap::new();
+        for (i, bridge) in self.bridges.iter().enumerate() {
+            let Some(server) = self.servers.iter().find(|s| s.name == bridge.server) else {
+                error(
+                    format!("bridges[{i}].server"),
+                    format!("there's no server called {:?}", bridge.server),
+                );
+                continue;
+            };
+            match &bridge.account {
+                Some(account) if !server.accounts.contains(account) => error(
+                    format!("bridges[{i}].account"),
+                    format!("{account:?} isn't one of the accounts on {:?}", server.name),
+                ),
+                None if server.accounts.len() > 1 => error(
+                    format!("bridges[{i}].account"),
+                    format!(
+                        "missing, and it can't be left out since {:?} has more than one account",
+                        server.name
+                    ),
+                ),
+                _ => {}
+            }
+            for (j, &channel_id) in bridge.discord_channels.iter().enumerate() {
+                if channel_id == 0 {
+                    error(
+                        format!("bridges[{i}].discord_channels[{j}]"),
+                        "0 isn't a valid Discord channel id".to_string(),
+                    );
+                }
+                // whispers are private, so they shouldn't end up in a public
+                // channel
+                if Some(channel_id) == self.discord.whisper_channel {
+                    error(
+                        format!("bridges[{i}].discord_channels[{j}]"),
+                        "can't also be discord.whisper_channel".to_string(),
+                    );
+                }
+            }
+            let account = bridge.account.as_ref().or(server.accounts.first());
+            if let (Some(account), Some(dedup)) = (account, &bridge.dedup) {
+                match dedup_bridges.insert((server.name.as_str(), account.as_str()), (i, dedup)) {
+                    Some((other_bridge, other_dedup)) if other_dedup != dedup => error(
+                        format!("bridges[{i}].dedup"),
+                        format!(
+                            "{account:?} on {:?} already has different dedup settings in \
+                             bridges[{other_bridge}].dedup, and there can only be one per account",
+                            server.name
+                        ),
+                    ),
+                    _ => {}
+                }
+            }
+            match bridge.dedup {
+                Some(DedupSettings::CollapseRepeats { max_recent: 0, .. })
+                | Some(DedupSettings::PerSender { max_recent: 0, .. }) => error(
+                    format!("bridges[{i}].dedup.max_recent"),
+                    "should be more than 0".to_string(),
+                ),
+                Some(DedupSettings::TimeWindow { window_secs: 0 }) => error(
+                    format!("bridges[{i}].dedup.window_secs"),
+                    "should be more than 0".to_string(),
+                ),
+                _ => {}
+            }
+            if !bridge.matrix_rooms.is_empty() && self.matrix.is_none() {
+                error(
+                    format!("bridges[{i}].matrix_rooms"),
+                    "there's no [matrix] section".to_string(),
+                );
+            }
+            for (j, room_id) in bridge.matrix_rooms.iter().enumerate() {
+                if !room_id.starts_with('!') || !room_id.contains(':') {
+                    error(
+                        format!("bridges[{i}].matrix_rooms[{j}]"),
+                        format!("{room_id:?} should look like !room:example.com"),
+                    );
+                }
+            }
+        }
+
+        for (key, rate_limit) in [
+            ("rate_limits.minecraft", &self.rate_limits.minecraft),
+            ("rate_limits.discord", &self.rate_limits.discord),
+            ("rate_limits.matrix", &self.rate_limits.matrix),
+        ] {
+            if rate_limit.message_cost == 0 || rate_limit.message_cost > rate_limit.max {
+                error(
+                    format!("{key}.message_cost"),
+                    format!("should be more than 0 and at most max ({})", rate_limit.max),
+                );
+            }
+        }
+
+        for (i, phrase) in self.rate_limits.spam_warnings.iter().enumerate() {
+            if phrase.trim().is_empty() {
+                error(
+                    format!("rate_limits.spam_warnings[{i}]"),
+                    "can't be empty, since every message would match it".to_string(),
+                );
+            }
+        }
+
+        if self.chat_queue.max_length == 0 {
+            error(
+                "chat_queue.max_length".to_string(),
+                "should be at least 1".to_string(),
+            );
+        }
+
+        if self.formatting.max_minecraft_chunks == 0 {
+            error(
+                "formatting.max_minecraft_chunks".to_string(),
+                "should be at least 1".to_string(),
+            );
+        }
+
+        for (key, template) in [
+            ("formatting.to_minecraft", &self.formatting.to_minecraft),
+            ("formatting.to_discord", &self.formatting.to_discord),
+            ("formatting.to_matrix", &self.formatting.to_matrix),
+        ] {
+            if !template.contains("{content}") {
+                error(key.to_string(), "should contain {content}".to_string());
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigErrors(errors))
+        }
+    }
+
+    /// The webhooks for each Discord channel, keyed by channel id.
+    pub fn discord_webhooks(&self) -> HashMap<u64, DiscordWebhook> {
+        self.discord
+            .webhooks
+            .iter()
+            .filter_map(|(channel_id, url)| {
+                Some((channel_id.parse().ok()?, parse_webhook_url(url)?))
+            })
+            .collect()
+    }
+
+    /// The Discord channels that each account on this server is bridged to,
+    /// keyed by the account's name in the config.
+    pub fn discord_channels(&self, server: &ServerConfig) -> HashMap<String, Vec<u64>> {
+        self.bridged(server, |bridge| bridge.discord_channels.clone())
+    }
+
+    /// The Discord channels where this server answers commands. Every server
+    /// sees every command, but an interaction can only be responded to once,
+    /// so a channel that's bridged to more than one server is answered by the
+    /// first of them in the config.
+    pub fn discord_command_channels(&self, server: &ServerConfig) -> HashSet<u64> {
+        let mut owners = HashMap::new();
+        for other_server in &self.servers {
+            for channel_id in self.discord_channels(other_server).into_values().flatten() {
+                owners
+                    .entry(channel_id)
+                    .or_insert(other_server.name.as_str());
+            }
+        }
+        owners
+            .into_iter()
+            .filter(|(_, owner)| *owner == server.name)
+            .map(|(channel_id, _)| channel_id)
+            .collect()
+    }
+
+    /// Every Discord channel that's bridged to any server.
+    pub fn all_discord_channels(&self) -> HashSet<u64> {
+        self.bridges
+            .iter()
+            .flat_map(|bridge| bridge.discord_channels.iter().copied())
+            .collect()
+    }
+
+    /// The Matrix rooms that each account on this server is bridged to, keyed
+    /// by the account's name in the config.
+    pub fn matrix_rooms(&self, server: &ServerConfig) -> HashMap<String, Vec<String>> {
+        self.bridged(server, |bridge| bridge.matrix_rooms.clone())
+    }
+
+    /// The chat rate limit for accounts on this server that don't have their
+    /// own.
+    pub fn default_rate_limit(&self, server: &ServerConfig) -> RateLimit {
+        match &server.rate_limit {
+            Some(profile) => profile.rate_limit(),
+            None => RateLimit::from_spam_counter(
+                self.rate_limits.minecraft.message_cost,
+                self.rate_limits.minecraft.max,
+            ),
+        }
+    }
+
+    /// The chat rate limits for accounts that have their own, keyed by the
+    /// account's name in the config.
+    pub fn account_rate_limits(&self) -> HashMap<String, RateLimit> {
+        self.accounts
+            .iter()
+            .filter_map(|account| {
+                Some((
+                    account.name.clone(),
+                    account.rate_limit.as_ref()?.rate_limit(),
+                ))
+            })
+            .collect()
+    }
+
+    /// The Discord user that gets whispers to each account, keyed by the
+    /// account's name in the config.
+    pub fn discord_owners(&self) -> HashMap<String, u64> {
+        self.accounts
+            .iter()
+            .filter_map(|account| Some((account.name.clone(), account.discord_owner?)))
+            .collect()
+    }
+
+    /// The dedup settings for each account on this server that has them, keyed
+    /// by the account's name in the config.
+    pub fn dedup_policies(&self, server: &ServerConfig) -> HashMap<String, DedupSettings> {
+        self.bridged(server, |bridge| bridge.dedup.clone().into_iter().collect())
+            .into_iter()
+            .filter_map(|(account, mut settings)| Some((account, settings.pop()?)))
+            .collect()
+    }
+
+    fn bridged<T>(
+        &self,
+        server: &ServerConfig,
+        get: impl Fn(&BridgeConfig) -> Vec<T>,
+    ) -> HashMap<String, Vec<T>> {
+        let mut bridged: HashMap<String, Vec<T>> = HashMap::new();
+        for bridge in self.bridges.iter().filter(|b| b.server == server.name) {
+            let account = bridge
+                .account
+                .clone()
+                .unwrap_or_else(|| server.accounts[0].clone());
+            bridged.entry(account).or_default().extend(get(bridge));
+        }
+        bridged
+    }
+}
+
+fn validate_rate_limit_profile(
+    key: String,
+    profile: &RateLimitProfile,
+    error: &mut impl FnMut(String, String),
+) {
+    let RateLimitProfile::TokenBucket {
+        burst,
+        refill_ticks,
+        ..
+    } = profile
+    else {
+        return;
+    };
+    if *burst == 0 {
+        error(format!("{key}.burst"), "should be at least 1".to_string());
+    }
+    if *refill_ticks == 0 {
+        error(
+            format!("{key}.refill_ticks"),
+            "should be at least 1".to_string(),
+        );
+    }
+}
+
+/// Get the id and token from a URL like
+/// `https://discord.com/api/webhooks/<id>/<token>`.
+fn parse_webhook_url(url: &str) -> Option<DiscordWebhook> {
+    let (_, id_and_token) = url.split_once("/webhooks/")?;
+    let (id, token) = id_and_token.split_once('/')?;
+    let id = id.parse().ok().filter(|&id| id != 0)?;
+    let token = token.trim_end_matches('/');
+    if token.is_empty() || token.contains('/') {
+        return None;
+    }
+    Some(DiscordWebhook {
+        id,
+        token: token.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(bridges: &str) -> Config {
+        toml::from_str(&format!(
+            "[discord]\ntoken = \"token\"\n\
+             [[accounts]]\nname = \"potatobot\"\n\
+             [[servers]]\nname = \"main\"\naddress = \"localhost\"\naccounts = \
+             [\"potatobot\"]\n{bridges}"
+        ))
+        .unwrap()
+    }
+
+    fn error_keys(config: &Config) -> Vec<String> {
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(ConfigErrors(errors)) => errors.into_iter().map(|error| error.key).collect(),
+        }
+    }
+
+    #[test]
+    fn conflicting_dedup_is_rejected() {
+        let config = config(
+            "[[bridges]]\nserver = \"main\"\ndiscord_channels = [1]\n\
+             dedup = { policy = \"off\" }\n\
+             [[bridges]]\nserver = \"main\"\ndiscord_channels = [2]\n\
+             dedup = { policy = \"time_window\", window_secs = 10 }\n",
+        );
+        assert_eq!(error_keys(&config), ["bridges[1].dedup"]);
+    }
+
+    #[test]
+    fn repeated_dedup_is_allowed() {
+        let config = config(
+            "[[bridges]]\nserver = \"main\"\ndiscord_channels = [1]\n\
+             dedup = { policy = \"off\" }\n\
+             [[bridges]]\nserver = \"main\"\ndiscord_channels = [2]\n\
+             dedup = { policy = \"off\" }\n\
+             [[bridges]]\nserver = \"main\"\ndiscord_channels = [3]\n",
+        );
+        assert_eq!(error_keys(&config), Vec::<String>::new());
+    }
+
+    #[test]
+    fn unknown_dedup_fields_are_rejected() {
+        let result = toml::from_str::<BridgeConfig>(
+            "server = \"main\"\n\
+             dedup = { policy = \"time_window\", window_secs = 10, max_recent = 5 }\n",
+        );
+        assert!(result.is_err());
+    }
+
+    fn matrix_config(fields: &str) -> MatrixConfig {
+        toml::from_str(&format!("homeserver = \"https://example.com\"\n{fields}")).unwrap()
+    }
+
+    #[test]
+    fn matrix_password_login() {
+        let config = matrix_config("username = \"potatobot\"\npassword = \"hunter2\"");
+        assert!(matches!(
+            config.login(),
+            Some(MatrixLogin::Password { username, password })
+                if username == "potatobot" && password == "hunter2"
+        ));
+    }
+
+    #[test]
+    fn matrix_token_login() {
+        let config = matrix_config("token = \"abc\"");
+        assert!(matches!(config.login(), Some(MatrixLogin::Token(token)) if token == "abc"));
+    }
+
+    #[test]
+    fn matrix_access_token_login_is_preferred() {
+        let config = matrix_config(
+            "username = \"potatobot\"\npassword = \"hunter2\"\ntoken = \"abc\"\n\
+             user_id = \"@potatobot:example.com\"\ndevice_id = \"ABCDEFGHIJ\"\n\
+             access_token = \"secret\"",
+        );
+        assert!(matches!(
+            config.login(),
+            Some(MatrixLogin::AccessToken { user_id, device_id, access_token })
+                if user_id == "@potatobot:example.com"
+                    && device_id == "ABCDEFGHIJ"
+                    && access_token == "secret"
+        ));
+    }
+
+    #[test]
+    fn matrix_incomplete_logins_fall_back() {
+        // a password without a username isn't enough, so the token is used
+        let config = matrix_config("password = \"hunter2\"\ntoken = \"abc\"");
+        assert!(matches!(config.login(), Some(MatrixLogin::Token(_))));
+        // and an access token needs the user and device too
+        let config = matrix_config(
+            "username = \"potatobot\"\npassword = \"hunter2\"\naccess_token = \"secret\"",
+        );
+        assert!(matches!(config.login(), Some(MatrixLogin::Password { .. })));
+    }
+
+    #[test]
+    fn matrix_without_credentials_has_no_login() {
+        assert!(matrix_config("").login().is_none());
+        assert!(matrix_config("username = \"potatobot\"").login().is_none());
+    }
+}
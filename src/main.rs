@@ -2,23 +2,52 @@
 
 use azalea::Account;
 
+mod azalea_archive;
 mod azalea_avoid_chat_kick;
 mod azalea_bridge;
+mod azalea_dedup;
 mod azalea_discord_bridge;
+mod azalea_discord_commands;
+mod azalea_discord_links;
+mod azalea_discord_markdown;
+mod azalea_discord_status;
+mod azalea_discord_whispers;
+mod azalea_matrix_bridge;
+mod azalea_message_kind;
+mod azalea_player_list;
+mod azalea_playtime;
 mod bevy_discord;
-// mod bevy_matrix;
+mod bevy_matrix;
+mod bevy_storage;
+mod config;
 
+use anyhow::Context;
 use azalea::prelude::*;
 use azalea::swarm::prelude::*;
 use azalea_protocol::packets::game::serverbound_client_command_packet::ServerboundClientCommandPacket;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::path::Path;
 use std::time::Duration;
 use tokio::time::sleep;
 use twilight_gateway::Intents;
 
-use crate::azalea_avoid_chat_kick::AvoidKickPlugin;
-use crate::azalea_discord_bridge::DiscordBridgePlugin;
-use crate::bevy_discord::DiscordPlugin;
+use crate::azalea_archive::ArchivePlugin;
+use crate::azalea_avoid_chat_kick::{
+    AvoidKickPlugin, LearnedRateLimits, RateLimit, SavedChatQueues,
+};
+use crate::azalea_dedup::{DedupPlugin, DedupSettings};
+use crate::azalea_discord_bridge::{DiscordBridgePlugin, DiscordWebhook, SavedDiscordQueues};
+use crate::azalea_discord_commands::DiscordCommandsPlugin;
+use crate::azalea_discord_links::{AccountLinks, DiscordLinksPlugin};
+use crate::azalea_discord_status::{DiscordStatusPlugin, ServerStatuses};
+use crate::azalea_discord_whispers::DiscordWhispersPlugin;
+use crate::azalea_matrix_bridge::MatrixBridgePlugin;
+use crate::azalea_playtime::PlaytimePlugin;
+use crate::bevy_discord::{DiscordClient, DiscordPlugin};
+use crate::bevy_matrix::{MatrixClient, MatrixPlugin, MatrixSettings};
+use crate::bevy_storage::{Storage, StoragePlugin};
+use crate::config::{ArchiveConfig, ChatQueueConfig, Config, FormattingConfig, RateLimitsConfig};
 
 #[derive(Component, Default, Clone)]
 struct State;
@@ -27,7 +56,8 @@ struct SwarmState;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    dotenv::dotenv().expect("Failed to load .env file");
+    // secrets can be kept in a .env file, but it's fine if there isn't one
+    dotenv::dotenv().ok();
     env_logger::init();
 
     {
@@ -54,35 +84,271 @@ async fn main() -> anyhow::Result<()> {
         });
     }
 
-    let account = if let Ok(email) = env::var("EMAIL") {
-        Account::microsoft(&email).await?
-    } else {
-        eprintln!("No EMAIL in env, defaulting to offline-mode.");
-        Account::offline("potatobot")
-    };
+    let config_path = env::var("POTATO_CONFIG").unwrap_or_else(|_| "config.toml".to_string());
+    let config = Config::load(Path::new(&config_path))?;
 
-    let token = env::var("DISCORD_TOKEN").expect("Expected DISCORD_TOKEN in env");
+    // log into every account first so we know their usernames
+    let mut accounts = HashMap::new();
+    for account in &config.accounts {
+        let logged_in = match &account.email {
+            Some(email) => Account::microsoft(email)
+                .await
+                .with_context(|| format!("Couldn't log into account {:?}", account.name))?,
+            None => Account::offline(&account.name),
+        };
+        accounts.insert(account.name.clone(), logged_in);
+    }
+
+    // the database and the links are shared between every server
+    let storage = Storage::open(&config.storage.path).with_context(|| {
+        format!(
+            "Couldn't open the database at {}",
+            config.storage.path.display()
+        )
+    })?;
+    let account_links = AccountLinks::new(storage.clone());
+
+    // so is the connection to Discord, since it's all one bot
+    let discord = DiscordClient::connect(
+        config.discord.token.clone(),
+        Intents::GUILDS
+            | Intents::GUILD_MESSAGES
+            | Intents::DIRECT_MESSAGES
+            | Intents::MESSAGE_CONTENT,
+        config.discord.api_proxy.clone(),
+    );
+    // and Matrix, but we only log into it if a server is actually bridged to it
+    let matrix = config
+        .matrix
+        .as_ref()
+        .filter(|_| {
+            config
+                .servers
+                .iter()
+                .any(|server| !config.matrix_rooms(server).is_empty())
+        })
+        .map(|matrix| {
+            MatrixClient::connect(MatrixSettings {
+                homeserver: matrix.homeserver.clone(),
+                login: matrix
+                    .login()
+                    .expect("the matrix login method was already validated"),
+                device_display_name: matrix.device_name.clone(),
+                session_path: Some(matrix.session_path.clone()),
+            })
+        });
+    let server_statuses = ServerStatuses::new(config.servers.iter().map(|s| s.name.clone()));
+
+    // every server gets its own swarm, and they all run on this thread
+    let local = tokio::task::LocalSet::new();
+    for (i, server) in config.servers.iter().enumerate() {
+        let matrix_rooms = by_username(config.matrix_rooms(server), &accounts);
+        local.spawn_local(run_server(SwarmSetup {
+            name: server.name.clone(),
+            address: server.address.clone(),
+            accounts: server
+                .accounts
+                .iter()
+                .map(|name| accounts[name].clone())
+                .collect(),
+            // the first server registers the commands and sets the presence
+            primary: i == 0,
+            discord: discord.clone(),
+            discord_webhooks: config.discord_webhooks(),
+            avatar_url_template: config.discord.avatar_url.clone(),
+            discord_edit_window: Duration::from_secs(config.discord.edit_window_secs),
+            relay_deletions: config.discord.relay_deletions,
+            discord_channels: by_username(config.discord_channels(server), &accounts),
+            discord_command_channels: config.discord_command_channels(server),
+            all_discord_channels: config.all_discord_channels(),
+            // only this server's accounts, so other servers don't answer DMs
+            // that aren't for them
+            discord_owners: config
+                .discord_owners()
+                .into_iter()
+                .filter(|(name, _)| server.accounts.contains(name))
+                .map(|(name, owner)| (accounts[&name].username.clone(), owner))
+                .collect(),
+            whisper_channel: config.discord.whisper_channel,
+            discord_presence: config.discord.presence,
+            server_statuses: server_statuses.clone(),
+            topic_channels: server.topic_channels.clone(),
+            topic_interval: Duration::from_secs(config.discord.topic_interval_secs),
+            storage: storage.clone(),
+            account_links: account_links.clone(),
+            default_rate_limit: config.default_rate_limit(server),
+            rate_limits_by_account: config
+                .account_rate_limits()
+                .into_iter()
+                .map(|(name, rate_limit)| (accounts[&name].username.clone(), rate_limit))
+                .collect(),
+            dedup_policies: accounts
+                .iter()
+                .filter_map(|(name, account)| {
+                    let settings = config.dedup_policies(server).remove(name)?;
+                    Some((account.username.clone(), settings))
+                })
+                .collect(),
+            // only add the matrix plugins if this server is bridged to it
+            matrix: matrix
+                .clone()
+                .filter(|_| !matrix_rooms.is_empty())
+                .map(|client| (client, matrix_rooms)),
+            rate_limits: config.rate_limits.clone(),
+            formatting: config.formatting.clone(),
+            chat_queue: config.chat_queue,
+            archive: config.archive,
+        }));
+    }
+    local.await;
+
+    Ok(())
+}
 
-    let channel_id: u64 = env::var("DISCORD_CHANNEL_ID").unwrap().parse().unwrap();
+/// The bridges in the config are keyed by the account's name in the config,
+/// but the plugins want them to be keyed by the account's username.
+fn by_username<T>(
+    bridged: HashMap<String, Vec<T>>,
+    accounts: &HashMap<String, Account>,
+) -> HashMap<String, Vec<T>> {
+    bridged
+        .into_iter()
+        .map(|(name, bridged)| (accounts[&name].username.clone(), bridged))
+        .collect()
+}
 
+/// Everything we need to start a swarm for one server.
+struct SwarmSetup {
+    name: String,
+    address: String,
+    accounts: Vec<Account>,
+    primary: bool,
+    discord: DiscordClient,
+    discord_webhooks: HashMap<u64, DiscordWebhook>,
+    avatar_url_template: String,
+    discord_edit_window: Duration,
+    relay_deletions: bool,
+    discord_channels: HashMap<String, Vec<u64>>,
+    discord_command_channels: HashSet<u64>,
+    all_discord_channels: HashSet<u64>,
+    discord_owners: HashMap<String, u64>,
+    whisper_channel: Option<u64>,
+    discord_presence: bool,
+    server_statuses: ServerStatuses,
+    topic_channels: Vec<u64>,
+    topic_interval: Duration,
+    storage: Storage,
+    account_links: AccountLinks,
+    default_rate_limit: RateLimit,
+    rate_limits_by_account: HashMap<String, RateLimit>,
+    dedup_policies: HashMap<String, DedupSettings>,
+    matrix: Option<(MatrixClient, HashMap<String, Vec<String>>)>,
+    rate_limits: RateLimitsConfig,
+    formatting: FormattingConfig,
+    chat_queue: ChatQueueConfig,
+    archive: ArchiveConfig,
+}
+
+/// Join a server and bridge it, reconnecting forever if the swarm stops.
+async fn run_server(setup: SwarmSetup) {
+    let rate_limits = &setup.rate_limits;
+    let formatting = &setup.formatting;
     loop {
-        let error = SwarmBuilder::new()
-            .add_plugin(AvoidKickPlugin)
+        let mut swarm_builder = SwarmBuilder::new()
+            .add_plugin(StoragePlugin {
+                storage: setup.storage.clone(),
+            })
+            .add_plugin(AvoidKickPlugin {
+                default_rate_limit: setup.default_rate_limit,
+                rate_limits: setup.rate_limits_by_account.clone(),
+                sanitize: formatting.sanitize_to_minecraft,
+                max_queue_length: setup.chat_queue.max_length,
+                drop_policy: setup.chat_queue.drop_policy,
+                learned_rate_limits: rate_limits.learn.then(|| LearnedRateLimits {
+                    storage: setup.storage.clone(),
+                    server: setup.address.clone(),
+                    spam_warnings: rate_limits.spam_warnings.clone(),
+                }),
+                saved_queues: Some(SavedChatQueues {
+                    storage: setup.storage.clone(),
+                    server: setup.address.clone(),
+                }),
+            })
+            .add_plugin(DedupPlugin {
+                policies: setup.dedup_policies.clone(),
+            })
             .add_plugin(DiscordPlugin {
-                token: token.clone(),
-                intents: Intents::GUILD_MESSAGES | Intents::MESSAGE_CONTENT,
+                client: setup.discord.clone(),
+                register_commands: setup.primary,
+            })
+            .add_plugin(DiscordBridgePlugin {
+                channels: setup.discord_channels.clone(),
+                command_channels: setup.discord_command_channels.clone(),
+                all_channels: setup.all_discord_channels.clone(),
+                answers_unbridged: setup.primary,
+                format: formatting.to_discord.clone(),
+                to_minecraft_format: formatting.to_minecraft.clone(),
+                max_chunks: formatting.max_minecraft_chunks,
+                message_cost: rate_limits.discord.message_cost,
+                max_ratelimit: rate_limits.discord.max,
+                webhooks: setup.discord_webhooks.clone(),
+                avatar_url_template: Some(setup.avatar_url_template.clone()),
+                edit_window: setup.discord_edit_window,
+                relay_deletions: setup.relay_deletions,
+                saved_queues: Some(SavedDiscordQueues {
+                    storage: setup.storage.clone(),
+                    server: setup.address.clone(),
+                }),
+            })
+            .add_plugin(DiscordCommandsPlugin {
+                to_minecraft_format: formatting.to_minecraft.clone(),
+                max_chunks: formatting.max_minecraft_chunks,
             })
-            .add_plugin(DiscordBridgePlugin { channel_id })
+            .add_plugin(DiscordWhispersPlugin {
+                owners: setup.discord_owners.clone(),
+                admin_channel: setup.whisper_channel,
+                max_chunks: formatting.max_minecraft_chunks,
+            })
+            .add_plugin(DiscordLinksPlugin {
+                links: setup.account_links.clone(),
+            })
+            .add_plugin(PlaytimePlugin {
+                server: setup.address.clone(),
+            })
+            .add_plugin(DiscordStatusPlugin {
+                server_name: setup.name.clone(),
+                statuses: setup.server_statuses.clone(),
+                presence: setup.discord_presence && setup.primary,
+                topic_channels: setup.topic_channels.clone(),
+                topic_interval: setup.topic_interval,
+            });
+        if setup.archive.enabled {
+            swarm_builder = swarm_builder.add_plugin(ArchivePlugin {
+                server: setup.address.clone(),
+                retention: setup.archive.retention(),
+            });
+        }
+        if let Some((matrix, rooms)) = &setup.matrix {
+            swarm_builder = swarm_builder
+                .add_plugin(MatrixPlugin {
+                    client: matrix.clone(),
+                })
+                .add_plugin(MatrixBridgePlugin {
+                    rooms: rooms.clone(),
+                    format: formatting.to_matrix.clone(),
+                    to_minecraft_format: formatting.to_minecraft.clone(),
+                    max_chunks: formatting.max_minecraft_chunks,
+                    message_cost: rate_limits.matrix.message_cost,
+                    max_ratelimit: rate_limits.matrix.max,
+                });
+        }
+        let error = swarm_builder
             .set_handler(handle)
             .set_swarm_handler(swarm_handle)
-            .add_account(account.clone())
-            .start(
-                env::var("SERVER_IP")
-                    .expect("Expected SERVER_IP in env")
-                    .as_str(),
-            )
+            .add_accounts(setup.accounts.clone())
+            .start(setup.address.as_str())
             .await;
-        eprintln!("{error:?}");
+        eprintln!("{}: {error:?}", setup.address);
         sleep(Duration::from_secs(4)).await;
     }
 }